@@ -0,0 +1,195 @@
+use std::{fmt, time::Duration};
+
+use reqwest::{header, Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use twilight_http::Client;
+
+use crate::Error;
+
+pub const DEFAULT_API_BASE: &str = "https://discord.com";
+pub const DEFAULT_API_VERSION: u8 = 9;
+/// Times a rate limited request is sent again before giving up
+const RATE_LIMIT_RETRIES: usize = 5;
+/// Wait when a rate limited response doesn't say how long for
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// How requests reach Discord, shared by the twilight client and [`Api`]
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub authorization: String,
+    /// Sent instead of the default user agent
+    pub user_agent: Option<String>,
+    /// Scheme and host requests are sent to, e.g. a compliance gateway
+    pub api_base: String,
+    /// Only applies to [`Api`], twilight always uses the version it was
+    /// built for
+    pub api_version: u8,
+}
+
+/// Check an `--api-base` value is a bare `http(s)://host[:port]`
+pub fn parse_api_base(value: &str) -> Result<String, String> {
+    let base = value.trim_end_matches('/');
+    match base.split_once("://") {
+        Some(("http" | "https", host)) if !host.is_empty() && !host.contains('/') => {
+            Ok(base.to_owned())
+        }
+        _ => Err(format!(
+            "Expected a URL like https://discord.com with no path, got \"{}\"",
+            value
+        )),
+    }
+}
+
+impl HttpConfig {
+    fn headers(&self) -> header::HeaderMap {
+        let mut headers = header::HeaderMap::new();
+        if let Some(value) = self
+            .user_agent
+            .as_deref()
+            .and_then(|u| header::HeaderValue::from_str(u).ok())
+        {
+            headers.insert(header::USER_AGENT, value);
+        }
+        headers
+    }
+
+    pub fn client(&self) -> Client {
+        let mut builder = Client::builder()
+            .token(self.authorization.clone())
+            .default_headers(self.headers());
+        if self.api_base != DEFAULT_API_BASE {
+            let (scheme, host) = self
+                .api_base
+                .split_once("://")
+                .expect("api_base is checked by parse_api_base");
+            builder = builder.proxy(host.to_owned(), scheme == "http");
+        }
+        builder.build()
+    }
+
+    pub fn api(&self) -> Api {
+        Api {
+            http: reqwest::Client::builder()
+                .default_headers(self.headers())
+                .build()
+                .expect("Unable to create HTTP client"),
+            authorization: self.authorization.clone(),
+            base: format!("{}/api/v{}", self.api_base, self.api_version),
+        }
+    }
+}
+
+/// Minimal client for the endpoints twilight doesn't cover, sharing the same
+/// authorization as the main client.
+#[derive(Clone)]
+pub struct Api {
+    http: reqwest::Client,
+    authorization: String,
+    base: String,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub body: String,
+    /// How long Discord asked to wait, when rate limited
+    pub retry_after: Option<Duration>,
+}
+
+/// Body of a 429 response
+#[derive(Deserialize)]
+struct RateLimited {
+    /// Seconds, with a fractional part
+    retry_after: f64,
+}
+
+/// How long a rate limited response asks to wait, from its body or else its
+/// `Retry-After` header (whole seconds)
+fn retry_after(headers: &header::HeaderMap, body: &[u8]) -> Option<Duration> {
+    let seconds = simd_json::from_slice::<RateLimited>(&mut body.to_vec())
+        .map(|r| r.retry_after)
+        .ok()
+        .or_else(|| {
+            headers
+                .get(header::RETRY_AFTER)?
+                .to_str()
+                .ok()?
+                .parse()
+                .ok()
+        })?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Discord API returned {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl Api {
+    /// Send a request, waiting out and retrying rate limits (unlike twilight
+    /// these requests aren't held back before they're sent)
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut attempt = 0;
+        loop {
+            let mut req = self
+                .http
+                .request(method.clone(), format!("{}{}", self.base, path))
+                .header(header::AUTHORIZATION, &self.authorization);
+            if let Some(body) = &body {
+                req = req
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+            }
+
+            let response = req.send().await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let bytes = response.bytes().await?.to_vec();
+            if status.is_success() {
+                return Ok(bytes);
+            }
+
+            let limited = status == StatusCode::TOO_MANY_REQUESTS;
+            let retry_after = if limited {
+                retry_after(&headers, &bytes)
+            } else {
+                None
+            };
+            if limited && attempt < RATE_LIMIT_RETRIES {
+                attempt += 1;
+                tokio::time::sleep(retry_after.unwrap_or(DEFAULT_RETRY_AFTER)).await;
+                continue;
+            }
+            return Err(Box::new(ApiError {
+                status: status.as_u16(),
+                body: String::from_utf8_lossy(&bytes).into_owned(),
+                retry_after,
+            }));
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let mut bytes = self.request(Method::GET, path, None).await?;
+        Ok(simd_json::from_slice(&mut bytes)?)
+    }
+
+    /// Send a JSON body, discarding whatever is returned
+    pub async fn send<B: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &B,
+    ) -> Result<(), Error> {
+        self.request(method, path, Some(simd_json::to_vec(body)?))
+            .await
+            .map(|_| ())
+    }
+}
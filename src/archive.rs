@@ -0,0 +1,590 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use twilight_model::id::{ChannelId, MessageId};
+
+use crate::{snapshot::SNAPSHOT_DIR, Error};
+
+const MESSAGES_SUFFIX: &str = ".messages.json";
+
+/// Order of the messages in a finished messages file
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    /// Oldest first
+    Asc,
+    /// Newest first, the order Discord returns them in
+    #[default]
+    Desc,
+}
+
+/// Just enough of a message to sort it
+#[derive(Deserialize)]
+struct SortableMessage {
+    id: MessageId,
+}
+
+/// Length of a messages file without its closing bracket, a separator left
+/// by a run stopped before its next message, or any whitespace, so that more
+/// messages can be appended to it. Only the end of the file is read.
+pub fn open_length(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut end = file.metadata()?.len();
+    let mut block = [0u8; 4096];
+    // What may still be dropped: the closing bracket and then a separator
+    let mut droppable: &[u8] = b"],";
+    while end > 0 {
+        let start = end.saturating_sub(block.len() as u64);
+        let block = &mut block[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        for (i, &byte) in block.iter().enumerate().rev() {
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+            match droppable.iter().position(|&b| b == byte) {
+                Some(at) => droppable = &droppable[at + 1..],
+                None => return Ok(start + i as u64 + 1),
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Reads a messages file a message at a time, so only one message is held in
+/// memory however big the file is. Files from interrupted runs are missing
+/// their closing bracket, which is tolerated, but a message cut off part way
+/// is an error.
+pub struct MessageReader<T> {
+    path: PathBuf,
+    reader: BufReader<File>,
+    /// The message being read
+    buffer: Vec<u8>,
+    /// Offset of the next byte to read
+    offset: u64,
+    /// Where the last message read starts
+    start: u64,
+    /// End of the last complete message, or of the opening bracket
+    complete: u64,
+    opened: bool,
+    finished: bool,
+    cut_off: bool,
+    _message: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> MessageReader<T> {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Ok(MessageReader {
+            path: path.to_path_buf(),
+            reader: BufReader::new(File::open(path)?),
+            buffer: Vec::new(),
+            offset: 0,
+            start: 0,
+            complete: 0,
+            opened: false,
+            finished: false,
+            cut_off: false,
+            _message: PhantomData,
+        })
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.reader.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.reader.consume(1);
+            self.offset += 1;
+        }
+        Ok(byte)
+    }
+
+    /// Skip whitespace, and between messages commas
+    fn next_token(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            match self.next_byte()? {
+                Some(b) if b.is_ascii_whitespace() || (self.opened && b == b',') => continue,
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// The JSON of the next message, or `None` at the end of the file
+    fn next_raw(&mut self) -> Result<Option<&mut [u8]>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        if !self.opened {
+            if self.next_token()? != Some(b'[') {
+                self.finished = true;
+                return Err(format!("{} isn't a messages file", self.path.display()).into());
+            }
+            self.opened = true;
+            self.complete = self.offset;
+        }
+
+        let first = match self.next_token()? {
+            // Either closed or still open
+            Some(b']') | None => {
+                self.finished = true;
+                return Ok(None);
+            }
+            Some(first @ (b'{' | b'[')) => first,
+            Some(other) => {
+                self.finished = true;
+                return Err(format!(
+                    "Unexpected {:?} at byte {} of {}",
+                    other as char,
+                    self.offset - 1,
+                    self.path.display()
+                )
+                .into());
+            }
+        };
+        self.start = self.offset - 1;
+        self.buffer.clear();
+        self.buffer.push(first);
+
+        let mut depth = 1usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        while depth > 0 {
+            let Some(byte) = self.next_byte()? else {
+                self.finished = true;
+                self.cut_off = true;
+                return Err(format!(
+                    "{} is cut off part way through the message at byte {}",
+                    self.path.display(),
+                    self.start
+                )
+                .into());
+            };
+            self.buffer.push(byte);
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => in_string = true,
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' => depth -= 1,
+                _ => {}
+            }
+        }
+        self.complete = self.offset;
+        Ok(Some(self.buffer.as_mut_slice()))
+    }
+
+    /// Offset and length in the file of the last message read
+    pub fn span(&self) -> (u64, u32) {
+        (self.start, (self.complete - self.start) as u32)
+    }
+
+    /// Whether reading stopped at a message cut off part way by a crash or
+    /// an interrupted copy, rather than at the end of the file or a message
+    /// that isn't valid
+    pub fn cut_off(&self) -> bool {
+        self.cut_off
+    }
+
+    /// Length of the file up to the end of the last complete message read,
+    /// for dropping a message that was cut off
+    pub fn complete_length(&self) -> u64 {
+        self.complete
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for MessageReader<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_raw() {
+            Ok(Some(raw)) => Some(simd_json::from_slice(raw).map_err(Into::into)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Where each message is in a messages file, by ID, so messages can be read
+/// in any order a few at a time without holding the file in memory
+pub struct MessageIndex {
+    path: PathBuf,
+    /// ID, offset and length of each message
+    entries: Vec<(MessageId, u64, u32)>,
+}
+
+impl MessageIndex {
+    /// Index a messages file with its messages oldest first
+    pub fn build(path: &Path) -> Result<Self, Error> {
+        let mut reader = MessageReader::<SortableMessage>::open(path)?;
+        let mut entries = Vec::new();
+        while let Some(message) = reader.next() {
+            let (offset, length) = reader.span();
+            entries.push((message?.id, offset, length));
+        }
+        entries.sort_by_key(|e| e.0);
+        Ok(MessageIndex {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// How many messages are older than `id`, while oldest first
+    pub fn position(&self, id: u64) -> usize {
+        self.entries.partition_point(|e| e.0.get() < id)
+    }
+
+    /// Newest first instead, or back again
+    pub fn reverse(&mut self) {
+        self.entries.reverse();
+    }
+
+    /// Keep only the messages `keep` selects
+    pub fn retain(&mut self, keep: impl Fn(MessageId) -> bool) {
+        self.entries.retain(|e| keep(e.0));
+    }
+
+    /// The messages at `range` of the index, read from the file
+    pub fn read<T: DeserializeOwned>(&self, range: Range<usize>) -> Result<Vec<T>, Error> {
+        let mut file = File::open(&self.path)?;
+        let mut buffer = Vec::new();
+        let mut messages = Vec::with_capacity(range.len());
+        for &(_, offset, length) in &self.entries[range] {
+            buffer.resize(length as usize, 0);
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            messages.push(simd_json::from_slice(&mut buffer)?);
+        }
+        Ok(messages)
+    }
+
+    /// Copy the indexed messages as they are, in the index's order, into
+    /// `out` after any messages already written to it
+    fn copy(&self, out: &mut impl Write, mut first: bool) -> Result<(), Error> {
+        let mut file = File::open(&self.path)?;
+        let mut buffer = Vec::new();
+        for &(_, offset, length) in &self.entries {
+            buffer.resize(length as usize, 0);
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buffer)?;
+            if !first {
+                out.write_all(b",")?;
+            }
+            out.write_all(&buffer)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrite a finished messages file with its messages in `order`. Files are
+/// written in the order messages were fetched, which for resumed archives
+/// is a mix of both. Messages are copied one at a time, so beyond the index
+/// this takes no more memory for a huge file than a small one.
+pub fn sort_messages(path: &Path, order: Order) -> Result<(), Error> {
+    let mut index = MessageIndex::build(path)?;
+    if order == Order::Desc {
+        index.reverse();
+    }
+    write_atomic_with(path, |out| {
+        out.write_all(b"[")?;
+        index.copy(out, true)?;
+        out.write_all(b"]")?;
+        Ok(())
+    })
+}
+
+/// Rewrite the messages file at `path` as the messages of `previous` that
+/// `replaced` doesn't select together with the ones already in `path`, in
+/// `order`. Returns how many messages of `previous` were dropped.
+pub fn splice_messages(
+    path: &Path,
+    previous: &Path,
+    replaced: impl Fn(MessageId) -> bool,
+    order: Order,
+) -> Result<usize, Error> {
+    let mut kept = MessageIndex::build(previous)?;
+    let count = kept.len();
+    kept.retain(|id| !replaced(id));
+    let dropped = count - kept.len();
+
+    // Append what's kept to the new messages, then sort the lot. Until
+    // `previous` is removed an interrupted splice is rolled back from it.
+    let end = open_length(path)?;
+    let empty = MessageReader::<SortableMessage>::open(path)?
+        .next()
+        .transpose()?
+        .is_none();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(end)?;
+    file.seek(SeekFrom::End(0))?;
+    let mut out = BufWriter::new(file);
+    kept.copy(&mut out, empty)?;
+    out.write_all(b"]")?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    sort_messages(path, order)?;
+    Ok(dropped)
+}
+
+/// All messages files in the backup, with the channel each belongs to
+pub fn message_files() -> Result<Vec<(ChannelId, PathBuf)>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let path = entry?.path();
+        let channel = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(MESSAGES_SUFFIX))
+            .and_then(|id| id.parse().ok())
+            .and_then(ChannelId::new);
+        if let Some(channel) = channel {
+            files.push((channel, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Every finished file in the backup, with its path relative to the backup
+/// (using `/` separators), sorted by that path. Hidden files (the state file
+/// and saved credentials), signing keys, partial downloads and snapshots are
+/// left out.
+pub fn backup_files() -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut paths = Vec::new();
+    collect_files(Path::new("."), &mut paths)?;
+
+    let mut files = Vec::new();
+    for path in paths {
+        let relative = path.strip_prefix(".").unwrap_or(&path);
+        let name = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        // Signing keys are only ever written to the root, keep them out
+        // even if `keygen --output` put one there
+        if name.starts_with('.')
+            || name.ends_with(".part")
+            || (!name.contains('/') && name.ends_with(".key"))
+            || name.starts_with(&format!("{}/", SNAPSHOT_DIR))
+        {
+            continue;
+        }
+        files.push((name, path));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Replace `path` with `data` by writing it to a `.part` file next to it,
+/// syncing that and renaming it into place, so a crash leaves either the old
+/// file or the new one and never half of one
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, |out| out.write_all(data))
+}
+
+/// [`write_atomic`] with the contents written a piece at a time by `write`
+pub fn write_atomic_with<E: From<io::Error>>(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+
+    let mut out = BufWriter::new(File::create(&part)?);
+    write(&mut out)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&part, path)?;
+
+    // Make the rename itself durable
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Write a JSON file in the backup, replacing any previous version
+pub fn write_json<T: Serialize + ?Sized>(path: &str, value: &T) -> Result<(), Error> {
+    write_atomic(Path::new(path), &simd_json::to_vec(value)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Versioned {
+        id: MessageId,
+        #[serde(default)]
+        new: bool,
+    }
+
+    fn messages_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "guild-backup-{}-{}{}",
+            name,
+            std::process::id(),
+            MESSAGES_SUFFIX
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn ids(path: &Path) -> Vec<u64> {
+        MessageReader::<SortableMessage>::open(path)
+            .unwrap()
+            .map(|m| m.unwrap().id.get())
+            .collect()
+    }
+
+    #[test]
+    fn cut_off_message() {
+        let path = messages_file("cut-off", r#"[{"id":"1"},{"id":"2","content":"} ]"#);
+        let mut reader = MessageReader::<SortableMessage>::open(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().id.get(), 1);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+        assert!(reader.cut_off());
+        assert_eq!(reader.complete_length(), r#"[{"id":"1"}"#.len() as u64);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn trailing_separator_is_dropped() {
+        for (name, contents) in [
+            ("separator", r#"[{"id":"1"},"#),
+            ("separator-space", "[{\"id\":\"1\"} , \n"),
+            ("separator-closed", r#"[{"id":"1"},]"#),
+        ] {
+            let path = messages_file(name, contents);
+            let mut reader = MessageReader::<SortableMessage>::open(&path).unwrap();
+            assert_eq!(reader.next().unwrap().unwrap().id.get(), 1);
+            assert!(reader.next().is_none());
+            assert!(!reader.cut_off());
+            assert_eq!(open_length(&path).unwrap(), r#"[{"id":"1"}"#.len() as u64);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_message_isnt_cut_off() {
+        let path = messages_file("invalid", r#"[{"id":"1"},"2"]"#);
+        let mut reader = MessageReader::<SortableMessage>::open(&path).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(!reader.cut_off());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_array() {
+        for (name, contents) in [("empty", "[]"), ("empty-open", "[ \n")] {
+            let path = messages_file(name, contents);
+            let mut reader = MessageReader::<SortableMessage>::open(&path).unwrap();
+            assert!(reader.next().is_none());
+            assert!(!reader.cut_off());
+            assert_eq!(MessageIndex::build(&path).unwrap().len(), 0);
+            assert_eq!(open_length(&path).unwrap(), 1);
+
+            sort_messages(&path, Order::Asc).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "[]");
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn sort_desc_to_asc() {
+        let path = messages_file(
+            "sort",
+            r#"[{"id":"3","content":"c"}, {"id":"2","content":"b"},{"id":"1","content":"a"}]"#,
+        );
+        sort_messages(&path, Order::Asc).unwrap();
+        // Messages are copied as they are, only their order changes
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            r#"[{"id":"1","content":"a"},{"id":"2","content":"b"},{"id":"3","content":"c"}]"#
+        );
+        sort_messages(&path, Order::Desc).unwrap();
+        assert_eq!(ids(&path), vec![3, 2, 1]);
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Splice `fetched` over a file of messages 1 to 5, checking the ones
+    /// `replaced` selects come from `fetched`, and return the IDs
+    fn splice(name: &str, fetched: &str, replaced: fn(MessageId) -> bool) -> Vec<u64> {
+        let previous = messages_file(
+            &format!("splice-{}-previous", name),
+            r#"[{"id":"5"},{"id":"4"},{"id":"3"},{"id":"2"},{"id":"1"}]"#,
+        );
+        let path = messages_file(&format!("splice-{}", name), fetched);
+        let dropped = splice_messages(&path, &previous, replaced, Order::Desc).unwrap();
+        assert_eq!(dropped, 2);
+
+        let messages: Vec<Versioned> = MessageReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for message in messages.iter() {
+            assert_eq!(message.new, replaced(message.id));
+        }
+        fs::remove_file(path).unwrap();
+        fs::remove_file(previous).unwrap();
+        messages.iter().map(|m| m.id.get()).collect()
+    }
+
+    #[test]
+    fn splice_at_each_edge() {
+        // The oldest messages refetched, with 1 since deleted
+        assert_eq!(
+            splice("oldest", r#"[{"id":"2","new":true}]"#, |id| id.get() <= 2),
+            vec![5, 4, 3, 2]
+        );
+        // The newest, still open as a rescrape leaves them
+        assert_eq!(
+            splice(
+                "newest",
+                r#"[{"id":"5","new":true},{"id":"4","new":true}"#,
+                |id| id.get() >= 4
+            ),
+            vec![5, 4, 3, 2, 1]
+        );
+        // The newest, all since deleted
+        assert_eq!(splice("deleted", "[", |id| id.get() >= 4), vec![3, 2, 1]);
+    }
+}
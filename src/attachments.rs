@@ -0,0 +1,618 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use futures_util::{stream, StreamExt};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use twilight_model::{
+    channel::{message::Message, Attachment},
+    id::{AttachmentId, ChannelId, MessageId},
+};
+
+use crate::{
+    api::{Api, ApiError},
+    archive::{message_files, MessageReader},
+    ledger::{self, Failure},
+    progress::Progress,
+    thumbnails, Error,
+};
+
+pub const ATTACHMENT_DIR: &str = "attachments";
+/// One JSON entry per line, appended as attachments are processed
+pub const INDEX_FILE: &str = "attachments/index.json";
+/// Messages read at a time when fetching attachments for an existing backup
+const FETCH_BATCH: usize = 100;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachmentType {
+    Image,
+    Video,
+    Audio,
+    Other,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AttachmentOptions {
+    /// Don't download any attachments, only record them in the index
+    pub skip: bool,
+    pub max_size: Option<u64>,
+    /// Types to download, all of them when empty
+    pub types: Vec<AttachmentType>,
+    /// Generate thumbnails for downloaded images and videos
+    pub thumbnails: bool,
+    /// Number of attachments to download at once
+    pub concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Downloaded,
+    Skipped,
+    Failed,
+    /// Deleted from Discord, along with its message or from it
+    Gone,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub attachment_id: AttachmentId,
+    pub filename: String,
+    pub url: String,
+    pub size: u64,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub status: Status,
+    /// Where the file was saved, relative to the backup
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Why the attachment was skipped or failed
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Hash of the downloaded file
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+impl IndexEntry {
+    /// Whether a downloaded attachment is still on disk in full. Only the
+    /// size is checked, so every run doesn't read every attachment, `verify`
+    /// checks the hashes.
+    pub fn is_present(&self) -> bool {
+        let Some(path) = self.path.as_deref() else {
+            return false;
+        };
+        fs::metadata(path.replace('\\', "/"))
+            .is_ok_and(|metadata| self.size == 0 || metadata.len() == self.size)
+    }
+}
+
+/// Every entry recorded in the index so far, oldest first
+pub fn read_index() -> Result<Vec<IndexEntry>, Error> {
+    let file = match File::open(INDEX_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let mut line = line?.into_bytes();
+        if line.is_empty() {
+            continue;
+        }
+        entries.push(simd_json::from_slice(&mut line)?);
+    }
+    Ok(entries)
+}
+
+/// Parse a size such as `500`, `10KB`, `25MB` or `1.5GiB` into bytes
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size \"{}\"", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1 << 30,
+        _ => return Err(format!("Unknown size unit \"{}\"", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a rate like `10MB/s`, in bytes per second
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    parse_size(value.strip_suffix("/s").unwrap_or(value))
+}
+
+pub fn attachment_type(attachment: &Attachment) -> AttachmentType {
+    classify(attachment.content_type.as_deref(), &attachment.filename)
+}
+
+/// Guess the type of a file from its MIME type, falling back to its extension
+pub fn classify(content_type: Option<&str>, filename: &str) -> AttachmentType {
+    let content_type = content_type.unwrap_or("");
+    let from_content = match content_type.split('/').next() {
+        Some("image") => Some(AttachmentType::Image),
+        Some("video") => Some(AttachmentType::Video),
+        Some("audio") => Some(AttachmentType::Audio),
+        _ => None,
+    };
+    from_content.unwrap_or_else(|| {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp" => AttachmentType::Image,
+            "mp4" | "webm" | "mov" | "mkv" | "avi" => AttachmentType::Video,
+            "mp3" | "ogg" | "wav" | "flac" | "m4a" | "opus" => AttachmentType::Audio,
+            _ => AttachmentType::Other,
+        }
+    })
+}
+
+/// Path in the asset store for an attachment, relative to the backup
+pub fn attachment_path(channel: ChannelId, attachment: AttachmentId, filename: &str) -> PathBuf {
+    let filename: String = filename
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    Path::new(ATTACHMENT_DIR)
+        .join(channel.to_string())
+        .join(format!("{}-{}", attachment, filename))
+}
+
+/// Partial downloads are kept here until complete so they can be resumed
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Download `url` to `path`, resuming a previous partial download with a
+/// range request when possible. The size is checked against what Discord
+/// reported (unless it is 0, when a partial download is started again as
+/// there's no telling whether it finished) and the SHA-256 of the finished
+/// file is returned.
+/// A file already at `path` is kept unless it doesn't match `sha256`, then
+/// it's replaced once the new download is complete. Downloaded bytes count
+/// towards the bandwidth cap in `progress`.
+pub async fn download_file(
+    http: &reqwest::Client,
+    path: &Path,
+    url: &str,
+    size: u64,
+    sha256: Option<&str>,
+    progress: &Progress,
+) -> Result<String, Error> {
+    let part = part_path(path);
+    if path.exists() {
+        let hash = hash_file(path)?;
+        if sha256.is_none_or(|sha256| sha256 == hash) {
+            return Ok(hash);
+        }
+        // Whatever is left of an earlier download can't be trusted either
+        let _ = fs::remove_file(&part);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut offset = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    if size == 0 && offset > 0 {
+        fs::remove_file(&part)?;
+        offset = 0;
+    }
+
+    if offset == 0 || offset < size {
+        let mut req = http.get(url);
+        if offset > 0 {
+            req = req.header(header::RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = req.send().await?.error_for_status()?;
+        let mut file = if response.status() == StatusCode::PARTIAL_CONTENT {
+            OpenOptions::new().append(true).open(&part)?
+        } else {
+            File::create(&part)?
+        };
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+            progress.transfer(chunk.len() as u64).await;
+        }
+        file.sync_all()?;
+    }
+
+    let written = fs::metadata(&part)?.len();
+    if size > 0 && written != size {
+        fs::remove_file(&part)?;
+        return Err(format!("Expected {} bytes but downloaded {}", size, written).into());
+    }
+    fs::rename(&part, path)?;
+    hash_file(path)
+}
+
+/// Whether a download failed because the CDN refused it, as it does once a
+/// signed URL expires or the file is deleted
+pub fn is_expired(e: &Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|status| matches!(status, StatusCode::FORBIDDEN | StatusCode::NOT_FOUND))
+}
+
+/// Downloads message attachments into the asset store, recording every
+/// attachment seen (downloaded or not) in the index.
+pub struct Downloader {
+    http: reqwest::Client,
+    options: AttachmentOptions,
+    index: File,
+    /// Attachments already downloaded by this or a previous run
+    downloaded: HashSet<AttachmentId>,
+    /// Attachments found to be deleted, never worth trying again
+    gone: HashSet<AttachmentId>,
+    /// Attachments last recorded as skipped, not recorded again while they
+    /// still are
+    skipped: HashSet<AttachmentId>,
+}
+
+impl Downloader {
+    pub fn new(options: &AttachmentOptions) -> Result<Self, Error> {
+        fs::create_dir_all(ATTACHMENT_DIR)?;
+        // The latest entry for each attachment says what happened to it
+        let mut latest = HashMap::new();
+        for entry in read_index()? {
+            latest.insert(entry.attachment_id, entry);
+        }
+        let mut downloaded = HashSet::new();
+        let mut gone = HashSet::new();
+        let mut skipped = HashSet::new();
+        for (id, entry) in latest {
+            match entry.status {
+                // Missing or damaged files, from a backup copied part way,
+                // are planned again
+                Status::Downloaded if entry.is_present() => downloaded.insert(id),
+                Status::Gone => gone.insert(id),
+                Status::Skipped => skipped.insert(id),
+                _ => false,
+            };
+        }
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(INDEX_FILE)?;
+        Ok(Downloader {
+            http: reqwest::Client::new(),
+            options: options.clone(),
+            index,
+            downloaded,
+            gone,
+            skipped,
+        })
+    }
+
+    fn skip_reason(&self, attachment: &Attachment) -> Option<String> {
+        if self.options.skip {
+            return Some("attachments skipped".to_owned());
+        }
+        if let Some(max_size) = self.options.max_size {
+            if attachment.size > max_size {
+                return Some(format!("larger than {} bytes", max_size));
+            }
+        }
+        let kind = attachment_type(attachment);
+        if !self.options.types.is_empty() && !self.options.types.contains(&kind) {
+            return Some(format!("type {:?} not selected", kind));
+        }
+        None
+    }
+
+    fn record(&mut self, entry: &IndexEntry) -> Result<(), Error> {
+        let mut line = simd_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.index.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Record the attachments of some messages that won't be downloaded, and
+    /// return the ones that should be, to be saved in the state before
+    /// calling [`Downloader::download`].
+    pub fn plan(&mut self, messages: &[Message]) -> Result<Vec<IndexEntry>, Error> {
+        let mut pending = Vec::new();
+        for message in messages {
+            for attachment in &message.attachments {
+                if self.downloaded.contains(&attachment.id) || self.gone.contains(&attachment.id) {
+                    continue;
+                }
+
+                let mut entry = IndexEntry {
+                    channel_id: message.channel_id,
+                    message_id: message.id,
+                    attachment_id: attachment.id,
+                    filename: attachment.filename.clone(),
+                    url: attachment.url.clone(),
+                    size: attachment.size,
+                    content_type: attachment.content_type.clone(),
+                    status: Status::Downloaded,
+                    path: None,
+                    reason: None,
+                    sha256: None,
+                };
+
+                if let Some(reason) = self.skip_reason(attachment) {
+                    if self.skipped.insert(attachment.id) {
+                        entry.status = Status::Skipped;
+                        entry.reason = Some(reason);
+                        self.record(&entry)?;
+                    }
+                } else {
+                    let path =
+                        attachment_path(message.channel_id, attachment.id, &attachment.filename);
+                    // With `/` on every platform, so the index and state
+                    // stay portable
+                    entry.path = Some(path.to_string_lossy().replace('\\', "/"));
+                    pending.push(entry);
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Download planned attachments, several at a time
+    pub async fn download(
+        &mut self,
+        pending: Vec<IndexEntry>,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        self.download_urls(pending, false, progress).await
+    }
+
+    /// Download attachments, where `refreshed` says their URLs were just
+    /// fetched so a refused download means the file is gone rather than the
+    /// URL having expired
+    async fn download_urls(
+        &mut self,
+        pending: Vec<IndexEntry>,
+        refreshed: bool,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        // Those downloaded by an interrupted run after being planned are
+        // already in the index
+        let pending: Vec<IndexEntry> = pending
+            .into_iter()
+            .filter(|e| !self.downloaded.contains(&e.attachment_id))
+            .collect();
+        let http = &self.http;
+        let results: Vec<(IndexEntry, Result<String, Error>)> = stream::iter(pending)
+            .map(|entry| async move {
+                let path = PathBuf::from(entry.path.as_deref().unwrap_or_default());
+                let result = download_file(
+                    http,
+                    &path,
+                    &entry.url,
+                    entry.size,
+                    entry.sha256.as_deref(),
+                    progress,
+                )
+                .await;
+                (entry, result)
+            })
+            .buffer_unordered(self.options.concurrency.max(1))
+            .collect()
+            .await;
+
+        for (mut entry, result) in results {
+            match result {
+                Ok(hash) => {
+                    entry.sha256 = Some(hash);
+                    self.downloaded.insert(entry.attachment_id);
+                    progress.attachment_downloaded(entry.size);
+
+                    if self.options.thumbnails {
+                        let kind = classify(entry.content_type.as_deref(), &entry.filename);
+                        let path = Path::new(entry.path.as_deref().unwrap_or_default());
+                        if let Err(e) = thumbnails::generate(path, kind) {
+                            progress.error(format!(
+                                "Error generating thumbnail for {} {:?}",
+                                entry.attachment_id, e
+                            ));
+                        }
+                    }
+                }
+                Err(e) if refreshed && is_expired(&e) => {
+                    progress.info(format!(
+                        "Attachment {} was deleted from Discord",
+                        entry.attachment_id
+                    ));
+                    self.gone.insert(entry.attachment_id);
+                    entry.status = Status::Gone;
+                    entry.path = None;
+                    entry.reason = Some(e.to_string());
+                }
+                Err(e) => {
+                    progress.error(format!(
+                        "Error downloading attachment {} {:?}",
+                        entry.attachment_id, e
+                    ));
+                    ledger::record(&Failure::Attachment {
+                        entry: entry.clone(),
+                        error: e.to_string(),
+                        expired: is_expired(&e),
+                    })?;
+                    entry.status = Status::Failed;
+                    entry.path = None;
+                    entry.reason = Some(e.to_string());
+                }
+            }
+            self.record(&entry)?;
+        }
+        Ok(())
+    }
+
+    fn record_gone(&mut self, mut entry: IndexEntry, reason: &str) -> Result<(), Error> {
+        self.gone.insert(entry.attachment_id);
+        entry.status = Status::Gone;
+        entry.path = None;
+        entry.reason = Some(reason.to_owned());
+        self.record(&entry)
+    }
+
+    /// Re-request the messages of attachments whose signed URLs expired and
+    /// download them again from the fresh URLs. Attachments whose message was
+    /// deleted, that were removed from their message or that still can't be
+    /// downloaded are recorded as [`Status::Gone`].
+    pub async fn refresh(
+        &mut self,
+        api: &Api,
+        expired: Vec<IndexEntry>,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        let mut by_message: HashMap<(ChannelId, MessageId), Vec<IndexEntry>> = HashMap::new();
+        for entry in expired {
+            by_message
+                .entry((entry.channel_id, entry.message_id))
+                .or_default()
+                .push(entry);
+        }
+
+        let mut pending = Vec::new();
+        for ((channel, message_id), entries) in by_message {
+            let path = format!("/channels/{}/messages/{}", channel, message_id);
+            let message: Message = match api.get(&path).await {
+                Ok(message) => message,
+                Err(e) if e.downcast_ref::<ApiError>().map(|e| e.status) == Some(404) => {
+                    for entry in entries {
+                        self.record_gone(entry, "message deleted")?;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    // Left in the ledger to try again later
+                    progress.error(format!("Error refreshing {} {:?}", message_id, e));
+                    for entry in entries {
+                        ledger::record(&Failure::Attachment {
+                            entry,
+                            error: e.to_string(),
+                            expired: true,
+                        })?;
+                    }
+                    continue;
+                }
+            };
+            for mut entry in entries {
+                match message
+                    .attachments
+                    .iter()
+                    .find(|a| a.id == entry.attachment_id)
+                {
+                    Some(attachment) => {
+                        entry.url = attachment.url.clone();
+                        entry.size = attachment.size;
+                        pending.push(entry);
+                    }
+                    None => self.record_gone(entry, "removed from the message")?,
+                }
+            }
+        }
+        self.download_urls(pending, true, progress).await
+    }
+
+    pub async fn archive(
+        &mut self,
+        messages: &[Message],
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        let pending = self.plan(messages)?;
+        self.download(pending, progress).await
+    }
+}
+
+/// Download the attachments of an existing backup, for backups made without
+/// attachments (or with filters) while the CDN still has them.
+pub async fn fetch_attachments(
+    options: &AttachmentOptions,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut downloader = Downloader::new(options)?;
+    for (channel, path) in message_files()? {
+        progress.info(format!("Fetching attachments for {}...", channel));
+        // A chunk's worth at a time, as if they were being backed up
+        let mut messages = MessageReader::<Message>::open(&path)?;
+        loop {
+            let batch = messages
+                .by_ref()
+                .take(FETCH_BATCH)
+                .collect::<Result<Vec<_>, _>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            downloader.archive(&batch, progress).await?;
+        }
+    }
+    progress.info("Done!".to_owned());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_in_decimal_and_binary_units() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("10KB"), Ok(10_000));
+        assert_eq!(parse_size("25 mb"), Ok(25_000_000));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("2k"), Ok(2000));
+    }
+
+    #[test]
+    fn bad_sizes_are_rejected() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("-5").is_err());
+        assert!(parse_size("10TB").is_err());
+    }
+
+    #[test]
+    fn rates_are_sizes_per_second() {
+        assert_eq!(parse_rate("10MB/s"), Ok(10_000_000));
+        assert_eq!(parse_rate("512KiB"), Ok(512 << 10));
+    }
+
+    #[test]
+    fn types_from_mime_then_extension() {
+        assert_eq!(
+            classify(Some("video/mp4"), "clip.png"),
+            AttachmentType::Video
+        );
+        assert_eq!(classify(None, "photo.JPG"), AttachmentType::Image);
+        assert_eq!(
+            classify(Some("application/octet-stream"), "song.flac"),
+            AttachmentType::Audio
+        );
+        assert_eq!(classify(None, "notes.txt"), AttachmentType::Other);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::StreamExt;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use twilight_gateway::{Event, Intents, Shard};
+use twilight_http::Client;
+use twilight_model::{
+    application::interaction::{ApplicationCommand, Interaction},
+    guild::Permissions,
+    id::{ChannelId, GuildId},
+};
+
+use crate::{
+    api::{Api, HttpConfig},
+    catalog::{self, CatalogEntry, RunKind},
+    progress::Progress,
+    scrape::{self, BackupOptions},
+    snapshots::{self, Activity},
+    Error,
+};
+
+const COMMAND_NAME: &str = "backup";
+/// MANAGE_GUILD, so only server admins see the command by default
+const COMMAND_PERMISSIONS: &str = "32";
+const EPHEMERAL: u64 = 1 << 6;
+
+#[derive(Deserialize)]
+struct Application {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct CommandRegistration {
+    name: &'static str,
+    description: &'static str,
+    #[serde(rename = "type")]
+    kind: u8,
+    default_member_permissions: &'static str,
+    dm_permission: bool,
+    options: Vec<SubCommand>,
+}
+
+#[derive(Serialize)]
+struct SubCommand {
+    name: &'static str,
+    description: &'static str,
+    #[serde(rename = "type")]
+    kind: u8,
+}
+
+#[derive(Serialize)]
+struct InteractionResponse {
+    #[serde(rename = "type")]
+    kind: u8,
+    data: InteractionResponseData,
+}
+
+#[derive(Serialize)]
+struct InteractionResponseData {
+    content: String,
+    flags: u64,
+}
+
+fn commands() -> Vec<CommandRegistration> {
+    vec![CommandRegistration {
+        name: COMMAND_NAME,
+        description: "Back up this server",
+        kind: 1,
+        default_member_permissions: COMMAND_PERMISSIONS,
+        dm_permission: false,
+        options: vec![
+            SubCommand {
+                name: "start",
+                description: "Start a backup of this server",
+                kind: 1,
+            },
+            SubCommand {
+                name: "status",
+                description: "Show the progress of the current backup",
+                kind: 1,
+            },
+        ],
+    }]
+}
+
+fn is_admin(command: &ApplicationCommand) -> bool {
+    command
+        .member
+        .as_ref()
+        .and_then(|m| m.permissions)
+        .is_some_and(|p| {
+            p.contains(Permissions::ADMINISTRATOR) || p.contains(Permissions::MANAGE_GUILD)
+        })
+}
+
+async fn respond(api: &Api, command: &ApplicationCommand, content: String) -> Result<(), Error> {
+    api.send(
+        Method::POST,
+        &format!("/interactions/{}/{}/callback", command.id, command.token),
+        &InteractionResponse {
+            kind: 4,
+            data: InteractionResponseData {
+                content,
+                flags: EPHEMERAL,
+            },
+        },
+    )
+    .await
+}
+
+async fn post(client: &Client, channel: ChannelId, content: &str) {
+    let result = match client.create_message(channel).content(content) {
+        Ok(req) => req.exec().await.map(|_| ()).map_err(Error::from),
+        Err(e) => Err(Error::from(e)),
+    };
+    if let Err(e) = result {
+        eprintln!("Error posting to staff channel {:?}", e);
+    }
+}
+
+fn start(
+    client: &Arc<Client>,
+    api: &Api,
+    guild_id: GuildId,
+    staff_channel: ChannelId,
+    options: &BackupOptions,
+    running: &mut Option<Arc<Progress>>,
+    command: &ApplicationCommand,
+) -> String {
+    if let Some(progress) = running {
+        if !progress.state().finished {
+            return "A backup is already running, see `/backup status`".to_owned();
+        }
+    }
+
+    let progress = Arc::new(Progress::new(false));
+    *running = Some(progress.clone());
+
+    let started_by = command
+        .member
+        .as_ref()
+        .and_then(|m| m.user.as_ref())
+        .map(|u| format!("<@{}>", u.id))
+        .unwrap_or_else(|| "an admin".to_owned());
+    let client = client.clone();
+    let api = api.clone();
+    let options = options.clone();
+    tokio::spawn(async move {
+        post(
+            &client,
+            staff_channel,
+            &format!("Backup of this server started by {}", started_by),
+        )
+        .await;
+        let started_at = catalog::now();
+
+        let result = scrape::backup_guild(&client, &api, guild_id, &options, &progress).await;
+        progress.finish();
+
+        let entry = CatalogEntry::run(
+            RunKind::Full,
+            Some(guild_id),
+            started_at,
+            result.is_ok(),
+            &progress.state(),
+        );
+        if let Err(e) = catalog::record(entry) {
+            eprintln!("Error recording the backup in the catalog {:?}", e);
+        }
+
+        let content = match result {
+            Ok(()) => format!("Backup finished: {}", progress.state().summary()),
+            Err(e) => format!("Backup failed: {}", e),
+        };
+        post(&client, staff_channel, &content).await;
+    });
+
+    "Backup started, results will be posted in the staff channel".to_owned()
+}
+
+fn status(running: &Option<Arc<Progress>>) -> String {
+    match running {
+        Some(progress) => {
+            let state = progress.state();
+            let status = if state.finished {
+                "Last backup finished"
+            } else {
+                "Backup running"
+            };
+            format!("{}: {}", status, state.summary())
+        }
+        None => "No backup has been run since the bot started".to_owned(),
+    }
+}
+
+/// Register the `/backup` command in the guild and serve it until the
+/// gateway connection closes. Backups run in the background, one at a time.
+/// With a `snapshot_interval`, member counts and channel activity are also
+/// recorded periodically.
+/// Serve `/backup` in the guild, backing it up with `options` whenever it's
+/// started
+pub async fn run(
+    token: &str,
+    config: &HttpConfig,
+    guild_id: GuildId,
+    staff_channel: ChannelId,
+    snapshot_interval: Option<Duration>,
+    options: BackupOptions,
+) -> Result<(), Error> {
+    let client = Arc::new(config.client());
+    let api = config.api();
+
+    let application: Application = api.get("/oauth2/applications/@me").await?;
+    api.send(
+        Method::PUT,
+        &format!(
+            "/applications/{}/guilds/{}/commands",
+            application.id, guild_id
+        ),
+        &commands(),
+    )
+    .await?;
+    eprintln!("Registered /{} in guild {}", COMMAND_NAME, guild_id);
+
+    let activity = Activity::default();
+    let mut intents = Intents::GUILDS;
+    if let Some(interval) = snapshot_interval {
+        // Message events are only needed to count activity
+        intents |= Intents::GUILD_MESSAGES;
+        tokio::spawn(snapshots::record(
+            api.clone(),
+            guild_id,
+            interval,
+            activity.clone(),
+        ));
+    }
+
+    let (shard, mut events) = Shard::new(token.to_owned(), intents);
+    shard.start().await?;
+
+    let mut running: Option<Arc<Progress>> = None;
+    while let Some(event) = events.next().await {
+        let command = match event {
+            Event::InteractionCreate(interaction) => match interaction.0 {
+                Interaction::ApplicationCommand(command) => command,
+                _ => continue,
+            },
+            Event::MessageCreate(message) => {
+                if message.guild_id == Some(guild_id) {
+                    activity.message(message.channel_id);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if command.data.name != COMMAND_NAME || command.guild_id != Some(guild_id) {
+            continue;
+        }
+
+        let reply = if !is_admin(&command) {
+            "You need the Manage Server permission to run backups".to_owned()
+        } else {
+            match command.data.options.first().map(|o| o.name.as_str()) {
+                Some("start") => start(
+                    &client,
+                    &api,
+                    guild_id,
+                    staff_channel,
+                    &options,
+                    &mut running,
+                    &command,
+                ),
+                Some("status") => status(&running),
+                _ => "Unknown subcommand".to_owned(),
+            }
+        };
+
+        if let Err(e) = respond(&api, &command, reply).await {
+            eprintln!("Error responding to interaction {:?}", e);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use twilight_model::id::GuildId;
+
+use crate::{
+    archive::write_json,
+    model::{read_json, GuildFileData, GUILD_FILE},
+    progress::ProgressState,
+    Error,
+};
+
+/// Every run made in this directory, oldest first
+pub const CATALOG_FILE: &str = "catalog.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunKind {
+    /// A backup from scratch, or finishing an interrupted one
+    Full,
+    /// `--resume-forward`, adding to the previous backup
+    Incremental,
+    /// `retry-failed`
+    Retry,
+    /// `rescrape`, replacing part of a channel
+    Rescrape,
+    Snapshot,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatalogEntry {
+    /// Position in the catalog, starting at 1
+    pub id: usize,
+    pub kind: RunKind,
+    /// `None` for private channels
+    pub guild_id: Option<GuildId>,
+    /// RFC 3339, in UTC
+    pub started_at: String,
+    pub finished_at: String,
+    /// Whether the run got to the end, rather than stopping or failing
+    pub complete: bool,
+    #[serde(default)]
+    pub channels: usize,
+    #[serde(default)]
+    pub messages: u64,
+    #[serde(default)]
+    pub bytes: u64,
+    #[serde(default)]
+    pub attachments: u64,
+    /// Directory of the snapshot, for snapshot entries
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// The run an incremental run added to
+    #[serde(default)]
+    pub base: Option<usize>,
+}
+
+pub fn read() -> Result<Vec<CatalogEntry>, Error> {
+    if !Path::new(CATALOG_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    read_json(CATALOG_FILE)
+}
+
+/// The guild the backup in this directory belongs to, if any
+pub fn backup_guild() -> Option<GuildId> {
+    read_json::<GuildFileData>(GUILD_FILE)
+        .ok()
+        .and_then(|g| GuildId::new(g.guild.id))
+}
+
+/// Add a run to the catalog, filling in its ID and, for incremental runs,
+/// the latest complete run of the same guild it built on
+pub fn record(mut entry: CatalogEntry) -> Result<(), Error> {
+    let mut entries = read()?;
+    entry.id = entries.len() + 1;
+    if entry.kind == RunKind::Incremental {
+        entry.base = entries
+            .iter()
+            .rev()
+            .find(|e| e.guild_id == entry.guild_id && e.complete && e.kind != RunKind::Snapshot)
+            .map(|e| e.id);
+    }
+    entries.push(entry);
+    write_json(CATALOG_FILE, &entries)
+}
+
+impl CatalogEntry {
+    /// A backup run, with its totals taken from the progress
+    pub fn run(
+        kind: RunKind,
+        guild_id: Option<GuildId>,
+        started_at: String,
+        complete: bool,
+        progress: &ProgressState,
+    ) -> Self {
+        CatalogEntry {
+            id: 0,
+            kind,
+            guild_id,
+            started_at,
+            finished_at: now(),
+            complete,
+            channels: progress.channels_done,
+            messages: progress.messages,
+            bytes: progress.bytes,
+            attachments: progress.attachments,
+            snapshot: None,
+            base: None,
+        }
+    }
+}
+
+pub fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Print every run in the catalog, only those of `guild` if given
+pub fn history(guild: Option<GuildId>) -> Result<(), Error> {
+    let entries = read()?;
+    let mut shown = 0;
+    for entry in entries
+        .iter()
+        .filter(|e| guild.is_none() || e.guild_id == guild)
+    {
+        let target = entry
+            .guild_id
+            .map(|g| g.to_string())
+            .unwrap_or_else(|| "dms".to_owned());
+        let mut line = format!(
+            "#{:<4} {}  {:<11} {:<20} {}",
+            entry.id,
+            entry.started_at,
+            format!("{:?}", entry.kind).to_lowercase(),
+            target,
+            if entry.complete {
+                "complete"
+            } else {
+                "incomplete"
+            },
+        );
+        match entry.kind {
+            RunKind::Snapshot => {
+                if let Some(snapshot) = &entry.snapshot {
+                    line.push_str(&format!("  {}", snapshot));
+                }
+            }
+            _ => line.push_str(&format!(
+                "  {} channels, {} messages, {} attachments",
+                entry.channels, entry.messages, entry.attachments
+            )),
+        }
+        if let Some(base) = entry.base {
+            line.push_str(&format!("  (based on #{})", base));
+        }
+        println!("{}", line);
+        shown += 1;
+    }
+    if shown == 0 {
+        println!("No runs recorded in {}", CATALOG_FILE);
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{progress::Progress, Error};
+
+/// Unix socket in the backup directory a running backup listens on
+#[cfg(unix)]
+pub const CONTROL_SOCKET: &str = ".guild-backup.sock";
+/// Named pipe a running backup listens on, so only one backup at a time can
+/// be controlled
+#[cfg(windows)]
+pub const CONTROL_PIPE: &str = r"\\.\pipe\guild-backup";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    Pause,
+    Resume,
+    /// Skip the channel being fetched
+    Skip,
+    /// Stop cleanly, so the backup can be resumed later
+    Stop,
+    Status,
+}
+
+fn run_command(progress: &Progress, line: &str) -> String {
+    match ControlCommand::from_str(line, true) {
+        Ok(ControlCommand::Pause) => {
+            progress.control.set_paused(true);
+            progress.info("Paused by control socket".to_owned());
+            "Paused".to_owned()
+        }
+        Ok(ControlCommand::Resume) => {
+            progress.control.set_paused(false);
+            progress.info("Resumed by control socket".to_owned());
+            "Resumed".to_owned()
+        }
+        Ok(ControlCommand::Skip) => {
+            progress.control.request_skip();
+            "Skipping the current channel".to_owned()
+        }
+        Ok(ControlCommand::Stop) => {
+            progress.control.request_stop();
+            "Stopping, run again to resume".to_owned()
+        }
+        Ok(ControlCommand::Status) => {
+            let paused = if progress.control.is_paused() {
+                " (paused)"
+            } else {
+                ""
+            };
+            format!("{}{}", progress.state().summary(), paused)
+        }
+        Err(_) => format!("Unknown command \"{}\"", line),
+    }
+}
+
+/// Answer each line sent on a connection with the result of running it
+async fn handle<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, progress: Arc<Progress>) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = run_command(&progress, line);
+        if writer
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Removes the socket when the backup finishes
+pub struct ControlListener;
+
+#[cfg(unix)]
+impl Drop for ControlListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(CONTROL_SOCKET);
+    }
+}
+
+/// Listen for control commands (one per line: pause, resume, skip, stop or
+/// status) until the returned listener is dropped
+#[cfg(unix)]
+pub fn listen(progress: Arc<Progress>) -> Result<ControlListener, Error> {
+    use tokio::net::UnixListener;
+
+    if std::os::unix::net::UnixStream::connect(CONTROL_SOCKET).is_ok() {
+        return Err("Another backup is already running in this directory".into());
+    }
+    // Left behind by a backup that was killed
+    let _ = std::fs::remove_file(CONTROL_SOCKET);
+    let listener = UnixListener::bind(CONTROL_SOCKET)?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle(stream, progress.clone()));
+                }
+                Err(e) => {
+                    progress.error(format!("Control socket stopped {:?}", e));
+                    break;
+                }
+            }
+        }
+    });
+    Ok(ControlListener)
+}
+
+#[cfg(windows)]
+pub fn listen(progress: Arc<Progress>) -> Result<ControlListener, Error> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(CONTROL_PIPE)
+        .map_err(|_| "Another backup is already running")?;
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = server.connect().await {
+                progress.error(format!("Control pipe stopped {:?}", e));
+                break;
+            }
+            // Create the next instance before handling this one so clients
+            // never find the pipe missing
+            let connected = server;
+            server = match ServerOptions::new().create(CONTROL_PIPE) {
+                Ok(server) => server,
+                Err(e) => {
+                    progress.error(format!("Control pipe stopped {:?}", e));
+                    break;
+                }
+            };
+            tokio::spawn(handle(connected, progress.clone()));
+        }
+    });
+    Ok(ControlListener)
+}
+
+/// Send a command to the backup running in this directory and print its reply
+pub async fn send(command: ControlCommand) -> Result<(), Error> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(CONTROL_SOCKET)
+        .await
+        .map_err(|_| "No backup is running in this directory")?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new()
+        .open(CONTROL_PIPE)
+        .map_err(|_| "No backup is running")?;
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let name = command
+        .to_possible_value()
+        .expect("Control commands are never skipped");
+    writer
+        .write_all(format!("{}\n", name.get_name()).as_bytes())
+        .await?;
+    match BufReader::new(reader).lines().next_line().await? {
+        Some(reply) => println!("{}", reply),
+        None => return Err("The backup closed the connection without replying".into()),
+    }
+    Ok(())
+}
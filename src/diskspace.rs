@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use crate::{
+    progress::{format_bytes, Progress},
+    Error,
+};
+
+/// How often to check whether space has been freed while waiting
+const SPACE_POLL: Duration = Duration::from_secs(30);
+
+/// Free space on the disk the backup is written to
+pub fn available() -> Result<u64, Error> {
+    Ok(fs2::available_space(".")?)
+}
+
+/// Bytes still to be written, from the average of the channels done so far
+/// and how many are left in the queue. `None` until a channel has finished.
+fn estimate_remaining(progress: &Progress) -> Option<u64> {
+    let state = progress.state();
+    if state.channels_done == 0 {
+        return None;
+    }
+    let per_channel = (state.bytes + state.attachment_bytes) / state.channels_done as u64;
+    Some(per_channel * (state.queue.len() as u64 + 1))
+}
+
+/// Warn when the rest of the backup probably won't fit, checked at the
+/// start of each channel
+pub fn check_estimate(min_free: u64, progress: &Progress) -> Result<(), Error> {
+    let free = available()?;
+    if let Some(remaining) = estimate_remaining(progress) {
+        if remaining + min_free > free {
+            progress.error(format!(
+                "About {} left to back up but only {} free, the backup will pause below {}",
+                format_bytes(remaining),
+                format_bytes(free),
+                format_bytes(min_free)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Report the free space before a run starts, waiting for enough to start
+pub async fn preflight(min_free: u64, progress: &Progress) -> Result<(), Error> {
+    progress.info(format!("{} free on disk", format_bytes(available()?)));
+    wait_for_space(min_free, progress).await
+}
+
+/// Wait until at least `min_free` bytes are free, so the backup pauses
+/// instead of failing partway through a write. Returns early if asked to stop.
+pub async fn wait_for_space(min_free: u64, progress: &Progress) -> Result<(), Error> {
+    let mut free = available()?;
+    if free >= min_free {
+        return Ok(());
+    }
+    progress.error(format!(
+        "Only {} free (below {}), paused until space is freed",
+        format_bytes(free),
+        format_bytes(min_free)
+    ));
+    while free < min_free {
+        if progress.control.should_stop() {
+            return Ok(());
+        }
+        tokio::time::sleep(SPACE_POLL).await;
+        free = available()?;
+    }
+    progress.info(format!("{} free, continuing", format_bytes(free)));
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use twilight_model::id::GuildId;
+
+use crate::{
+    api::Api,
+    archive::{message_files, MessageReader},
+    model::{
+        read_json, ChannelData, MemberData, RoleData, CHANNELS_FILE, CHANNEL_TYPE_TEXT,
+        MEMBERS_FILE, ROLES_FILE,
+    },
+    scrape::ArchivedMessage,
+    Error,
+};
+
+#[derive(Deserialize)]
+struct GuildCounts {
+    #[serde(default)]
+    approximate_member_count: Option<u64>,
+}
+
+fn name(channel: &ChannelData) -> &str {
+    channel.name.as_deref().unwrap_or("unnamed")
+}
+
+/// Compare channels, and each channel's newest message, with the live guild
+fn check_channels(archived: &[ChannelData], live: &[ChannelData]) -> Result<usize, Error> {
+    let mut differences = 0;
+    let archived_by_id: HashMap<u64, &ChannelData> = archived.iter().map(|c| (c.id, c)).collect();
+    let live_ids: HashSet<u64> = live.iter().map(|c| c.id).collect();
+
+    let mut newest: HashMap<u64, u64> = HashMap::new();
+    for (channel, path) in message_files()? {
+        for message in MessageReader::<ArchivedMessage>::open(&path)? {
+            let id = message?.id.get();
+            let entry = newest.entry(channel.get()).or_insert(id);
+            *entry = (*entry).max(id);
+        }
+    }
+
+    for channel in live {
+        match archived_by_id.get(&channel.id) {
+            None => {
+                println!("New channel: #{} ({})", name(channel), channel.id);
+                differences += 1;
+            }
+            Some(old) if old.name != channel.name => {
+                println!(
+                    "Renamed channel: #{} is now #{} ({})",
+                    name(old),
+                    name(channel),
+                    channel.id
+                );
+                differences += 1;
+            }
+            Some(_) => {}
+        }
+
+        // Only text channels' messages are backed up
+        let live_newest = match channel.last_message_id {
+            Some(id) if channel.kind == CHANNEL_TYPE_TEXT => id,
+            _ => continue,
+        };
+        match newest.get(&channel.id) {
+            Some(archived) if *archived >= live_newest => {}
+            Some(archived) => {
+                println!(
+                    "New messages in #{} ({}): backup ends at {}, latest is {}",
+                    name(channel),
+                    channel.id,
+                    archived,
+                    live_newest
+                );
+                differences += 1;
+            }
+            None => {
+                println!(
+                    "Messages never backed up in #{} ({})",
+                    name(channel),
+                    channel.id
+                );
+                differences += 1;
+            }
+        }
+    }
+
+    for channel in archived.iter().filter(|c| !live_ids.contains(&c.id)) {
+        println!("Deleted channel: #{} ({})", name(channel), channel.id);
+        differences += 1;
+    }
+    Ok(differences)
+}
+
+fn check_roles(archived: &[RoleData], live: &[RoleData]) -> usize {
+    let mut differences = 0;
+    let archived_by_id: HashMap<u64, &RoleData> = archived.iter().map(|r| (r.id, r)).collect();
+    let live_ids: HashSet<u64> = live.iter().map(|r| r.id).collect();
+
+    for role in live {
+        match archived_by_id.get(&role.id) {
+            None => println!("New role: {} ({})", role.name, role.id),
+            Some(old) if old.name != role.name => {
+                println!(
+                    "Renamed role: {} is now {} ({})",
+                    old.name, role.name, role.id
+                )
+            }
+            Some(old) if old.permissions != role.permissions => {
+                println!("Role permissions changed: {} ({})", role.name, role.id)
+            }
+            Some(_) => continue,
+        }
+        differences += 1;
+    }
+    for role in archived.iter().filter(|r| !live_ids.contains(&r.id)) {
+        println!("Deleted role: {} ({})", role.name, role.id);
+        differences += 1;
+    }
+    differences
+}
+
+/// Report how the live guild differs from the backup: channels, the newest
+/// message in each, roles and the member count. Meant for deciding whether a
+/// new incremental run is needed, so nothing is changed.
+pub async fn check(api: &Api, guild_id: GuildId) -> Result<(), Error> {
+    let archived_channels: Vec<ChannelData> = read_json(CHANNELS_FILE)?;
+    let archived_roles: Vec<RoleData> = read_json(ROLES_FILE)?;
+    let live_channels: Vec<ChannelData> =
+        api.get(&format!("/guilds/{}/channels", guild_id)).await?;
+    let live_roles: Vec<RoleData> = api.get(&format!("/guilds/{}/roles", guild_id)).await?;
+    let counts: GuildCounts = api
+        .get(&format!("/guilds/{}?with_counts=true", guild_id))
+        .await?;
+
+    let mut differences = check_channels(&archived_channels, &live_channels)?;
+    differences += check_roles(&archived_roles, &live_roles);
+
+    match (
+        read_json::<Vec<MemberData>>(MEMBERS_FILE),
+        counts.approximate_member_count,
+    ) {
+        (Ok(members), Some(live)) if members.len() as u64 != live => {
+            println!(
+                "Members: {} in the backup, about {} now",
+                members.len(),
+                live
+            );
+            differences += 1;
+        }
+        (Err(_), _) => println!("Members weren't exported, can't compare member counts"),
+        _ => {}
+    }
+
+    if differences == 0 {
+        println!("The backup matches the live guild");
+    } else {
+        println!(
+            "{} differences, run with --resume-forward to update the backup",
+            differences
+        );
+    }
+    Ok(())
+}
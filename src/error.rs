@@ -0,0 +1,189 @@
+use std::{io, path::PathBuf, process::ExitCode, time::Duration};
+
+use thiserror::Error;
+use twilight_http::{
+    api_error::{ApiError as DiscordError, GeneralApiError, RatelimitedApiError},
+    error::ErrorType,
+};
+use twilight_model::id::GuildId;
+
+use crate::{api::ApiError, Error};
+
+/// Discord's codes for a channel or guild the account can't see, and for
+/// permissions it doesn't have
+const MISSING_ACCESS: u64 = 50001;
+const MISSING_PERMISSIONS: u64 = 50013;
+
+/// The ways a backup fails that need something done before running it
+/// again, each with what to do, and `Other` for everything else, passed on
+/// as it is. These can be picked out of an [`Error`] with `downcast_ref` or
+/// [`BackupError::classify`].
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error(
+        "Discord rejected the token, check it hasn't been reset and that --token-type \
+         matches it (bot, user or oauth)"
+    )]
+    Auth,
+
+    #[error(
+        "Discord refused access ({0}), check the account can see the guild and has View \
+         Channel and Read Message History, or back up only the channels it can with --channel"
+    )]
+    Permission(String),
+
+    #[error("Rate limited by Discord{}, wait a while then run again to resume", retry(.0))]
+    RateLimited(Option<Duration>),
+
+    #[error(
+        "Unable to use {} ({source}), check there's space and permission to write there",
+        file(.path)
+    )]
+    Io {
+        /// `None` for an error passed on without saying which file it was
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(
+        "{} is corrupt ({reason}), restore it from a copy or move it and the messages files \
+         away to start over",
+        .path.display()
+    )]
+    CorruptState { path: PathBuf, reason: String },
+
+    #[error(
+        "The state file is for {}, not {}, finish that backup first or run this one in \
+         another directory",
+        describe(.state),
+        describe(.requested)
+    )]
+    WrongGuild {
+        state: Option<GuildId>,
+        requested: Option<GuildId>,
+    },
+
+    #[error("{0}")]
+    Other(Error),
+}
+
+impl From<Error> for BackupError {
+    fn from(error: Error) -> Self {
+        BackupError::classify(error).unwrap_or_else(BackupError::Other)
+    }
+}
+
+fn retry(after: &Option<Duration>) -> String {
+    match after {
+        Some(after) => format!(" for {:.0}s", after.as_secs_f64().ceil()),
+        None => String::new(),
+    }
+}
+
+fn file(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => path.display().to_string(),
+        None => "a file in the backup".to_owned(),
+    }
+}
+
+fn describe(guild: &Option<GuildId>) -> String {
+    match guild {
+        Some(guild) => format!("guild {}", guild),
+        None => "private channels".to_owned(),
+    }
+}
+
+impl BackupError {
+    /// For `map_err`, the I/O error as one using `path`
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> BackupError {
+        move |source| BackupError::Io {
+            path: Some(path.into()),
+            source,
+        }
+    }
+
+    /// The error as one of these if it's a kind they cover, such as an HTTP
+    /// error for a rejected token, otherwise the error unchanged
+    pub fn classify(error: Error) -> Result<BackupError, Error> {
+        let error = match error.downcast::<BackupError>() {
+            Ok(error) => match *error {
+                BackupError::Other(error) => return Err(error),
+                error => return Ok(error),
+            },
+            Err(error) => error,
+        };
+        let error = match error.downcast::<io::Error>() {
+            Ok(source) => {
+                return Ok(BackupError::Io {
+                    path: None,
+                    source: *source,
+                })
+            }
+            Err(error) => error,
+        };
+        if let Some(error) = error.downcast_ref::<twilight_http::Error>() {
+            match error.kind() {
+                ErrorType::Unauthorized => return Ok(BackupError::Auth),
+                ErrorType::Response {
+                    error: DiscordError::General(GeneralApiError { code, message, .. }),
+                    ..
+                } if matches!(code.num(), MISSING_ACCESS | MISSING_PERMISSIONS) => {
+                    return Ok(BackupError::Permission(message.clone()))
+                }
+                ErrorType::Response {
+                    error: DiscordError::Ratelimited(RatelimitedApiError { retry_after, .. }),
+                    ..
+                } => {
+                    return Ok(BackupError::RateLimited(Some(Duration::from_secs_f64(
+                        *retry_after,
+                    ))))
+                }
+                _ => {}
+            }
+        }
+        if let Some(error) = error.downcast_ref::<ApiError>() {
+            match error.status {
+                401 => return Ok(BackupError::Auth),
+                403 => return Ok(BackupError::Permission(error.body.clone())),
+                429 => return Ok(BackupError::RateLimited(error.retry_after)),
+                _ => {}
+            }
+        }
+        Err(error)
+    }
+
+    /// Each kind exits with its own status, for scripts running backups. 1
+    /// is any other error and 2 a mistake in the arguments.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            BackupError::Auth => 3,
+            BackupError::Permission(_) => 4,
+            BackupError::RateLimited(_) => 5,
+            BackupError::Io { .. } => 6,
+            BackupError::CorruptState { .. } | BackupError::WrongGuild { .. } => 7,
+            BackupError::Other(_) => 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_exits_as_io() {
+        let error: Error = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        let error = BackupError::classify(error).unwrap();
+        assert!(matches!(error, BackupError::Io { path: None, .. }));
+        assert_eq!(error.exit_code(), ExitCode::from(6));
+    }
+
+    #[test]
+    fn other_error_is_passed_on() {
+        let error: Error = "something else".into();
+        let error = BackupError::classify(error).unwrap_err();
+        assert_eq!(error.to_string(), "something else");
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Deserialize;
+use twilight_http::Client;
+use twilight_model::id::{ChannelId, GuildId};
+
+use crate::{api::Api, Error};
+
+const SAMPLE_SIZE: u64 = 100;
+
+/// Expected size of a channel's history, before it is fetched
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Estimate {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct SearchResults {
+    total_results: u64,
+}
+
+/// Milliseconds since the Discord epoch when a snowflake was created
+fn timestamp(id: u64) -> u64 {
+    id >> 22
+}
+
+/// Exact count from search, only available to user accounts
+async fn search_count(api: &Api, guild: Option<GuildId>, channel: ChannelId) -> Result<u64, Error> {
+    let path = match guild {
+        Some(guild) => format!("/guilds/{}/messages/search?channel_id={}", guild, channel),
+        None => format!("/channels/{}/messages/search", channel),
+    };
+    let results: SearchResults = api.get(&path).await?;
+    Ok(results.total_results)
+}
+
+/// Fetch the newest messages and assume the channel has been that active
+/// since it was created. Channels with fewer messages than a sample are
+/// counted exactly.
+async fn sample(client: &Client, channel: ChannelId) -> Result<Estimate, Error> {
+    let chunk = client
+        .channel_messages(channel)
+        .limit(SAMPLE_SIZE)?
+        .exec()
+        .await?
+        .models()
+        .await?;
+    let count = chunk.len() as u64;
+    let mut bytes = 0;
+    for message in &chunk {
+        bytes += simd_json::to_vec(message)?.len() as u64;
+    }
+    if count < SAMPLE_SIZE {
+        return Ok(Estimate {
+            messages: count,
+            bytes,
+        });
+    }
+
+    let newest = chunk.iter().map(|m| m.id.get()).max().unwrap_or(0);
+    let oldest = chunk.iter().map(|m| m.id.get()).min().unwrap_or(0);
+    let span = (timestamp(newest) - timestamp(oldest)).max(1);
+    let age = timestamp(newest).saturating_sub(timestamp(channel.get()));
+    let messages = (count * age / span).max(count);
+    Ok(Estimate {
+        messages,
+        bytes: bytes / count * messages,
+    })
+}
+
+/// Estimate how much a channel holds, using search counts when `search` is
+/// set and falling back to extrapolating from a sample. `search` is cleared
+/// if it isn't available so it isn't tried for every channel.
+pub async fn estimate(
+    client: &Client,
+    api: &Api,
+    guild: Option<GuildId>,
+    channel: ChannelId,
+    search: &mut bool,
+) -> Result<Estimate, Error> {
+    let sampled = sample(client, channel).await?;
+    if *search {
+        match search_count(api, guild, channel).await {
+            Ok(total) => {
+                let per_message = sampled.bytes / sampled.messages.max(1);
+                return Ok(Estimate {
+                    messages: total,
+                    bytes: per_message * total,
+                });
+            }
+            Err(_) => *search = false,
+        }
+    }
+    Ok(sampled)
+}
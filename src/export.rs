@@ -0,0 +1,422 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    slice,
+};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use clap::{Args, ValueEnum};
+use twilight_model::id::{AttachmentId, ChannelId};
+
+use crate::{
+    api::Api,
+    archive::{message_files, MessageIndex, MessageReader},
+    attachments::attachment_path,
+    mentions::Resolver,
+    meta::meta_path,
+    model::{read_json, ChannelData, MessageData},
+    readstate, references, Error,
+};
+
+pub const EXPORT_DIR: &str = "exports";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+        }
+    }
+
+    /// The start of a transcript, before its messages
+    fn header(&self, title: &str) -> String {
+        match self {
+            ExportFormat::Csv => {
+                "id,timestamp,edited,author_id,author,content,attachments,reply_to\n".to_owned()
+            }
+            ExportFormat::Markdown => format!("# {}\n\n", title),
+            ExportFormat::Html => format!("{}<h1>{}</h1>\n", html_head(title), escape_html(title)),
+        }
+    }
+
+    /// The end of a transcript, with the last read line if no message came
+    /// after it
+    fn footer(&self, marker: bool) -> String {
+        match self {
+            ExportFormat::Html if marker => format!("{}{}", LAST_READ_MARKER, HTML_FOOT),
+            ExportFormat::Html => HTML_FOOT.to_owned(),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Timestamps are shown in this timezone, the backup itself stays in UTC
+    pub timezone: Tz,
+    /// `strftime` style format for timestamps
+    pub timestamp_format: String,
+}
+
+/// How timestamps are shown, for the commands that show messages
+#[derive(Args, Debug, Clone)]
+pub struct TimestampArgs {
+    /// Timezone to show timestamps in, e.g. `Europe/London`. The messages
+    /// files themselves are always UTC
+    #[arg(long, default_value = "UTC", value_parser = parse_timezone)]
+    pub timezone: Tz,
+
+    /// `strftime` format for timestamps
+    #[arg(long, default_value = "%Y-%m-%d %H:%M:%S %Z")]
+    pub timestamp_format: String,
+}
+
+impl TimestampArgs {
+    pub fn options(&self, format: ExportFormat) -> ExportOptions {
+        ExportOptions {
+            format,
+            timezone: self.timezone,
+            timestamp_format: self.timestamp_format.clone(),
+        }
+    }
+}
+
+pub fn parse_timezone(value: &str) -> Result<Tz, String> {
+    value.parse().map_err(|_| {
+        format!(
+            "Unknown timezone \"{}\", expected a name like Europe/London",
+            value
+        )
+    })
+}
+
+/// Length replies quote the message they reply to at
+const QUOTE_LENGTH: usize = 100;
+
+/// Messages exported at a time, with mentions in them looked up before
+/// they're written, so a channel is never in memory all at once
+const EXPORT_BATCH: usize = 1000;
+
+/// Goes before the first message the backing up user hadn't read
+const LAST_READ_MARKER: &str =
+    "<hr class=\"last-read\" id=\"you-were-here\">\n<p class=\"last-read\">You were here</p>\n";
+
+const HTML_FOOT: &str = "</body>\n</html>\n";
+
+/// Enough of a message to show what a reply was replying to
+pub struct Quote {
+    author: String,
+    content: String,
+}
+
+/// The message a message replies to, if it's a reply
+fn reply_id(message: &MessageData) -> Option<u64> {
+    message.message_reference.as_ref()?.message_id
+}
+
+/// A message's ID with what replies to it show
+pub fn quote(message: &MessageData) -> (u64, Quote) {
+    (
+        message.id,
+        Quote {
+            author: message.author.name.clone(),
+            content: message.content.clone(),
+        },
+    )
+}
+
+pub struct Exporter<'a> {
+    pub options: &'a ExportOptions,
+    pub resolver: &'a Resolver,
+    /// The messages replied to, from the backup and `references.json`, by ID
+    pub quotes: &'a HashMap<u64, Quote>,
+    pub channel: ChannelId,
+    /// The last message the backing up user had read, from `read_states.json`
+    pub last_read: Option<u64>,
+}
+
+impl Exporter<'_> {
+    fn reply_to(&self, message: &MessageData) -> Option<(u64, Option<&Quote>)> {
+        let id = reply_id(message)?;
+        Some((id, self.quotes.get(&id)))
+    }
+
+    fn quote(&self, quote: &Quote) -> String {
+        let content = self.resolver.resolve(&quote.content).replace('\n', " ");
+        match content.char_indices().nth(QUOTE_LENGTH) {
+            Some((end, _)) => format!("{}...", &content[..end]),
+            None => content,
+        }
+    }
+
+    fn time(&self, timestamp: &str) -> String {
+        match DateTime::parse_from_rfc3339(timestamp) {
+            Ok(time) => time
+                .with_timezone(&self.options.timezone)
+                .format(&self.options.timestamp_format)
+                .to_string(),
+            Err(_) => timestamp.to_owned(),
+        }
+    }
+
+    /// Downloaded attachments link to the asset store, others to Discord
+    fn attachment_links(&self, message: &MessageData) -> Vec<String> {
+        message
+            .attachments
+            .iter()
+            .map(|a| {
+                let path = AttachmentId::new(a.id)
+                    .map(|id| attachment_path(self.channel, id, &a.filename));
+                match path {
+                    Some(path) if path.exists() => {
+                        format!("../{}", path.to_string_lossy().replace('\\', "/"))
+                    }
+                    _ => a.url.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// `messages` in the export's format. `marker` is whether the last read
+    /// line is still to be placed, for transcripts written in batches.
+    fn messages(&self, messages: &[MessageData], marker: &mut bool) -> String {
+        match self.options.format {
+            ExportFormat::Csv => self.csv(messages),
+            ExportFormat::Markdown => self.markdown(messages),
+            ExportFormat::Html => self.html(messages, marker),
+        }
+    }
+
+    fn csv(&self, messages: &[MessageData]) -> String {
+        let mut out = String::new();
+        for message in messages {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{},{},{}",
+                message.id,
+                csv_field(&self.time(&message.timestamp)),
+                csv_field(
+                    &message
+                        .edited_timestamp
+                        .as_deref()
+                        .map(|t| self.time(t))
+                        .unwrap_or_default()
+                ),
+                message.author.id,
+                csv_field(&message.author.name),
+                csv_field(&self.resolver.resolve(&message.content)),
+                csv_field(&self.attachment_links(message).join(" ")),
+                self.reply_to(message)
+                    .map(|(id, _)| id.to_string())
+                    .unwrap_or_default()
+            );
+        }
+        out
+    }
+
+    fn markdown(&self, messages: &[MessageData]) -> String {
+        let mut out = String::new();
+        for message in messages {
+            let _ = write!(
+                out,
+                "**{}** — {}",
+                message.author.name,
+                self.time(&message.timestamp)
+            );
+            if message.edited_timestamp.is_some() {
+                out.push_str(" (edited)");
+            }
+            match self.reply_to(message) {
+                Some((_, Some(quote))) => {
+                    let _ = write!(
+                        out,
+                        "\n\n> Replying to **{}**: {}",
+                        quote.author,
+                        self.quote(quote)
+                    );
+                }
+                Some((id, None)) => {
+                    let _ = write!(
+                        out,
+                        "\n\n> Replying to a message that wasn't backed up ({})",
+                        id
+                    );
+                }
+                None => {}
+            }
+            let _ = write!(out, "\n\n{}\n\n", self.resolver.resolve(&message.content));
+            for link in self.attachment_links(message) {
+                let _ = writeln!(out, "- <{}>", link);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The messages as HTML, without the rest of the page
+    pub fn html_messages(&self, messages: &[MessageData]) -> String {
+        let mut marker = self.last_read.is_some();
+        let mut out = self.html(messages, &mut marker);
+        if marker {
+            out.push_str(LAST_READ_MARKER);
+        }
+        out
+    }
+
+    fn html(&self, messages: &[MessageData], marker: &mut bool) -> String {
+        let mut out = String::new();
+        for message in messages {
+            if *marker && Some(message.id) > self.last_read {
+                out.push_str(LAST_READ_MARKER);
+                *marker = false;
+            }
+            let reply = match self.reply_to(message) {
+                Some((id, Some(quote))) => format!(
+                    "<p class=\"reply\"><a href=\"#{}\">Replying to</a> <strong>{}</strong>: {}</p>\n",
+                    id,
+                    escape_html(&quote.author),
+                    escape_html(&self.quote(quote))
+                ),
+                Some((id, None)) => format!(
+                    "<p class=\"reply\">Replying to a message that wasn't backed up ({})</p>\n",
+                    id
+                ),
+                None => String::new(),
+            };
+            let _ = write!(
+                out,
+                "<div class=\"message\" id=\"{}\">\n{}<p><strong>{}</strong> <time>{}</time>{}</p>\n<p>{}</p>\n",
+                message.id,
+                reply,
+                escape_html(&message.author.name),
+                escape_html(&self.time(&message.timestamp)),
+                if message.edited_timestamp.is_some() {
+                    " (edited)"
+                } else {
+                    ""
+                },
+                escape_html(&self.resolver.resolve(&message.content)).replace('\n', "<br>")
+            );
+            for link in self.attachment_links(message) {
+                let _ = writeln!(out, "<p><a href=\"{0}\">{0}</a></p>", escape_html(&link));
+            }
+            out.push_str("</div>\n");
+        }
+        out
+    }
+}
+
+fn html_head(title: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n",
+        escape_html(title)
+    )
+}
+
+/// A complete HTML page around `body`
+pub fn html_page(title: &str, body: &str) -> String {
+    format!("{}{}{}", html_head(title), body, HTML_FOOT)
+}
+
+/// `#name` from the channel's meta file, or its ID if it has none
+pub fn channel_title(channel: ChannelId) -> String {
+    read_json::<ChannelData>(meta_path(channel))
+        .ok()
+        .and_then(|c| c.name)
+        .map(|name| format!("#{}", name))
+        .unwrap_or_else(|| channel.to_string())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write a transcript of every channel in the backup to `exports/`, oldest
+/// message first, with timestamps in the chosen timezone and mentions
+/// replaced by names. The backup is read a message or a batch at a time, so
+/// channels of any size export in the same memory.
+pub async fn export(api: &Api, options: &ExportOptions) -> Result<(), Error> {
+    fs::create_dir_all(EXPORT_DIR)?;
+    let files = message_files()?;
+    let mut resolver = Resolver::load();
+    let last_read = readstate::last_read();
+
+    // Only messages that are replied to are kept for quoting, so first find
+    // which those are
+    let mut replied = HashSet::new();
+    for (_, path) in files.iter() {
+        for message in MessageReader::<MessageData>::open(path)? {
+            let message = message?;
+            resolver.add_authors(slice::from_ref(&message));
+            replied.extend(reply_id(&message));
+        }
+    }
+    let mut quotes = HashMap::new();
+    for message in references::read::<MessageData>()? {
+        if replied.contains(&message.id) {
+            quotes.insert(message.id, quote(&message).1);
+        }
+    }
+    for (_, path) in files.iter() {
+        for message in MessageReader::<MessageData>::open(path)? {
+            let message = message?;
+            if replied.contains(&message.id) {
+                quotes.insert(message.id, quote(&message).1);
+            }
+        }
+    }
+
+    for (channel, path) in files {
+        let title = channel_title(channel);
+        let index = MessageIndex::build(&path)?;
+        let channel_last_read = last_read.get(&channel.get()).copied();
+        let mut marker = channel_last_read.is_some();
+
+        let file =
+            Path::new(EXPORT_DIR).join(format!("{}.{}", channel, options.format.extension()));
+        let mut out = BufWriter::new(File::create(&file)?);
+        out.write_all(options.format.header(&title).as_bytes())?;
+        for start in (0..index.len()).step_by(EXPORT_BATCH) {
+            let batch: Vec<MessageData> =
+                index.read(start..(start + EXPORT_BATCH).min(index.len()))?;
+            resolver.fetch_missing(api, &batch).await;
+            let exporter = Exporter {
+                options,
+                resolver: &resolver,
+                quotes: &quotes,
+                channel,
+                last_read: channel_last_read,
+            };
+            out.write_all(exporter.messages(&batch, &mut marker).as_bytes())?;
+        }
+        out.write_all(options.format.footer(marker).as_bytes())?;
+        out.flush()?;
+        println!("Exported {} to {}", title, file.display());
+    }
+    Ok(())
+}
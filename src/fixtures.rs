@@ -0,0 +1,242 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Recorded exchanges, one JSON object per line in the order they happened
+pub const FIXTURES_FILE: &str = "fixtures.json";
+
+/// Response headers not worth keeping, mostly ones that describe the
+/// transfer rather than the response
+const SKIPPED_HEADERS: &[&str] = &[
+    "connection",
+    "content-encoding",
+    "content-length",
+    "date",
+    "set-cookie",
+    "transfer-encoding",
+];
+
+/// One request to the API and what it returned. Request headers (so the
+/// token) aren't recorded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fixture {
+    pub method: String,
+    /// Path and query, e.g. `/api/v9/channels/1/messages?limit=100`
+    pub path: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// Milliseconds to wait before replaying the response, so a test can act
+    /// while the request is in flight. Never recorded.
+    #[serde(default, skip_serializing)]
+    pub delay_ms: u64,
+}
+
+impl Fixture {
+    fn key(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::builder().status(self.status);
+        for (name, value) in self.headers.iter() {
+            response = response.header(name, value);
+        }
+        response
+            .body(Body::from(self.body.clone()))
+            .unwrap_or_else(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, "Bad fixture"))
+    }
+}
+
+fn request_key(request: &Request<Body>) -> String {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    format!("{} {}", request.method(), path)
+}
+
+/// A Discord style error body, so clients report it like a real API error
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(format!(
+        "{{\"message\":{:?},\"code\":0}}",
+        message
+    )));
+    *response.status_mut() = status;
+    response
+}
+
+struct Recorder {
+    http: reqwest::Client,
+    upstream: String,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    async fn forward(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+        let (parts, body) = request.into_parts();
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str().to_owned())
+            .unwrap_or_else(|| "/".to_owned());
+
+        let mut headers = parts.headers;
+        headers.remove(hyper::header::HOST);
+        // Keep the recorded bodies readable
+        headers.remove(hyper::header::ACCEPT_ENCODING);
+        let response = self
+            .http
+            .request(parts.method.clone(), format!("{}{}", self.upstream, path))
+            .headers(headers)
+            .body(hyper::body::to_bytes(body).await?)
+            .send()
+            .await?;
+
+        let fixture = Fixture {
+            method: parts.method.to_string(),
+            path,
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            body: String::from_utf8_lossy(&response.bytes().await?).into_owned(),
+            delay_ms: 0,
+        };
+        {
+            let mut file = self.file.lock().unwrap();
+            file.write_all(&simd_json::to_vec(&fixture)?)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(fixture.response())
+    }
+}
+
+/// Start a local proxy to `upstream` that records every exchange in
+/// `dir/fixtures.json`, returning the address to send requests to. Runs
+/// until the program exits.
+pub fn record(dir: &Path, upstream: &str) -> Result<SocketAddr, Error> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(FIXTURES_FILE))?;
+    let recorder = Arc::new(Recorder {
+        http: reqwest::Client::new(),
+        upstream: upstream.to_owned(),
+        file: Mutex::new(file),
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service_fn(
+        move |_| {
+            let recorder = recorder.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let recorder = recorder.clone();
+                    async move {
+                        Ok::<_, Infallible>(match recorder.forward(request).await {
+                            Ok(response) => response,
+                            Err(e) => error_response(StatusCode::BAD_GATEWAY, &e.to_string()),
+                        })
+                    }
+                }))
+            }
+        },
+    ));
+    let addr = server.local_addr();
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            eprintln!("Fixture recorder stopped: {}", e);
+        }
+    });
+    eprintln!("Recording API responses to {}", dir.display());
+    Ok(addr)
+}
+
+/// Responses for each request, in the order they were recorded. The last one
+/// is repeated once the others have been used, so retries after a recorded
+/// error see the recorded recovery.
+struct Replay {
+    fixtures: Mutex<HashMap<String, VecDeque<Fixture>>>,
+}
+
+impl Replay {
+    /// The response to `request` and how long to wait before sending it
+    fn respond(&self, request: &Request<Body>) -> (Response<Body>, Duration) {
+        let key = request_key(request);
+        let mut fixtures = self.fixtures.lock().unwrap();
+        let fixture = match fixtures.get_mut(&key) {
+            Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+            Some(queue) => queue[0].clone(),
+            None => {
+                eprintln!("No fixture for {}", key);
+                let response =
+                    error_response(StatusCode::NOT_FOUND, &format!("No fixture for {}", key));
+                return (response, Duration::ZERO);
+            }
+        };
+        (fixture.response(), Duration::from_millis(fixture.delay_ms))
+    }
+}
+
+/// Serve the fixtures recorded in `dir` as a mock Discord API, so a backup
+/// can be run against canned responses with `--api-base`
+pub async fn replay(dir: &Path, port: u16) -> Result<(), Error> {
+    let mut fixtures: HashMap<String, VecDeque<Fixture>> = HashMap::new();
+    for line in BufReader::new(File::open(dir.join(FIXTURES_FILE))?).lines() {
+        let mut line = line?.into_bytes();
+        if line.is_empty() {
+            continue;
+        }
+        let fixture: Fixture = simd_json::from_slice(&mut line)?;
+        fixtures
+            .entry(fixture.key())
+            .or_default()
+            .push_back(fixture);
+    }
+    let count: usize = fixtures.values().map(|q| q.len()).sum();
+    let replay = Arc::new(Replay {
+        fixtures: Mutex::new(fixtures),
+    });
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let replay = replay.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let (response, delay) = replay.respond(&request);
+                async move {
+                    tokio::time::sleep(delay).await;
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    }));
+    eprintln!(
+        "Serving {} fixtures on http://{}, run with --api-base http://{}",
+        count, addr, addr
+    );
+    server.await?;
+    Ok(())
+}
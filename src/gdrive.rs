@@ -0,0 +1,596 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use reqwest::{header, Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    oauth,
+    remote::{RemoteObject, RemoteStore},
+    Error,
+};
+
+const DEVICE_CODE_URL: &str = "https://oauth2.googleapis.com/device/code";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const FILES_URL: &str = "https://www.googleapis.com/drive/v3/files";
+const UPLOAD_URL: &str = "https://www.googleapis.com/upload/drive/v3/files";
+/// Only files created by this app are visible to it
+const SCOPE: &str = "https://www.googleapis.com/auth/drive.file";
+const FOLDER_TYPE: &str = "application/vnd.google-apps.folder";
+/// Must be a multiple of 256 KiB
+const UPLOAD_CHUNK_SIZE: usize = 8 << 20;
+const UPLOAD_RETRIES: usize = 3;
+/// Refresh access tokens this long before they expire
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+/// OAuth client (of the "TVs and limited input devices" type) and where to
+/// keep the refresh token between runs
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+    pub token_file: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedToken {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+    #[serde(default)]
+    next_page_token: Option<String>,
+    files: Vec<DriveFile>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DriveFile {
+    id: String,
+    name: String,
+    mime_type: String,
+    /// Sent as a string, missing for folders
+    #[serde(default)]
+    size: Option<String>,
+    #[serde(default)]
+    sha256_checksum: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FileMetadata<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "mimeType")]
+    mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    parents: Vec<&'a str>,
+}
+
+struct AccessToken {
+    token: String,
+    expires: Instant,
+}
+
+/// Google Drive store, laid out as one folder per guild inside a root folder,
+/// with the backup's directories as subfolders
+pub struct GoogleDrive {
+    http: reqwest::Client,
+    credentials: Credentials,
+    refresh_token: String,
+    access: Mutex<AccessToken>,
+    /// Folder IDs by path, `""` being the guild's folder
+    folders: Mutex<HashMap<String, String>>,
+    /// File IDs by path, filled by listing so uploads replace existing files
+    files: Mutex<HashMap<String, String>>,
+}
+
+async fn parse_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, Error> {
+    let status = response.status();
+    let mut bytes = response.bytes().await?.to_vec();
+    if !status.is_success() {
+        return Err(format!(
+            "Google Drive returned {}: {}",
+            status,
+            String::from_utf8_lossy(&bytes)
+        )
+        .into());
+    }
+    Ok(simd_json::from_slice(&mut bytes)?)
+}
+
+/// Escape a value for a Drive search query
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Ask the user to authorize this device, returning a refresh token
+async fn device_flow(http: &reqwest::Client, credentials: &Credentials) -> Result<String, Error> {
+    let code: DeviceCode = parse_response(
+        http.post(DEVICE_CODE_URL)
+            .form(&[
+                ("client_id", credentials.client_id.as_str()),
+                ("scope", SCOPE),
+            ])
+            .send()
+            .await?,
+    )
+    .await?;
+    eprintln!(
+        "To allow uploads to Google Drive, visit {} and enter the code {}",
+        code.verification_url, code.user_code
+    );
+
+    let mut interval = Duration::from_secs(code.interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        let response = http
+            .post(TOKEN_URL)
+            .form(&[
+                ("client_id", credentials.client_id.as_str()),
+                ("client_secret", credentials.client_secret.as_str()),
+                ("device_code", code.device_code.as_str()),
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ])
+            .send()
+            .await?;
+        let mut bytes = response.bytes().await?.to_vec();
+        let token: TokenResponse = simd_json::from_slice(&mut bytes)?;
+        match token.error.as_deref() {
+            None => {
+                return token
+                    .refresh_token
+                    .ok_or_else(|| "No refresh token returned".into())
+            }
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += Duration::from_secs(5),
+            Some(error) => {
+                return Err(format!("Google Drive authorization failed: {}", error).into())
+            }
+        }
+    }
+}
+
+impl GoogleDrive {
+    /// Authorize (with the saved refresh token, or interactively the first
+    /// time) and find or create `root/guild_folder`
+    pub async fn connect(
+        credentials: Credentials,
+        root: &str,
+        guild_folder: &str,
+    ) -> Result<Self, Error> {
+        let http = reqwest::Client::new();
+        let saved = fs::read(&credentials.token_file)
+            .ok()
+            .and_then(|mut data| simd_json::from_slice::<SavedToken>(&mut data).ok());
+        let refresh_token = match saved {
+            Some(saved) => saved.refresh_token,
+            None => {
+                let refresh_token = device_flow(&http, &credentials).await?;
+                // The refresh token grants access to the drive until revoked
+                let file = oauth::open_private(&credentials.token_file, false)?;
+                simd_json::to_writer(
+                    file,
+                    &SavedToken {
+                        refresh_token: refresh_token.clone(),
+                    },
+                )?;
+                refresh_token
+            }
+        };
+
+        let drive = GoogleDrive {
+            http,
+            credentials,
+            refresh_token,
+            access: Mutex::new(AccessToken {
+                token: String::new(),
+                expires: Instant::now(),
+            }),
+            folders: Mutex::new(HashMap::new()),
+            files: Mutex::new(HashMap::new()),
+        };
+        let root_id = drive.find_or_create_folder(root, None).await?;
+        let guild_id = drive
+            .find_or_create_folder(guild_folder, Some(&root_id))
+            .await?;
+        drive
+            .folders
+            .lock()
+            .unwrap()
+            .insert(String::new(), guild_id);
+        Ok(drive)
+    }
+
+    async fn access_token(&self) -> Result<String, Error> {
+        {
+            let access = self.access.lock().unwrap();
+            if access.expires > Instant::now() + TOKEN_MARGIN {
+                return Ok(access.token.clone());
+            }
+        }
+        let token: TokenResponse = parse_response(
+            self.http
+                .post(TOKEN_URL)
+                .form(&[
+                    ("client_id", self.credentials.client_id.as_str()),
+                    ("client_secret", self.credentials.client_secret.as_str()),
+                    ("refresh_token", self.refresh_token.as_str()),
+                    ("grant_type", "refresh_token"),
+                ])
+                .send()
+                .await?,
+        )
+        .await?;
+        let value = token
+            .access_token
+            .ok_or("Google Drive didn't return an access token")?;
+        *self.access.lock().unwrap() = AccessToken {
+            token: value.clone(),
+            expires: Instant::now() + Duration::from_secs(token.expires_in.unwrap_or(3600)),
+        };
+        Ok(value)
+    }
+
+    async fn request(&self, method: Method, url: &str) -> Result<reqwest::RequestBuilder, Error> {
+        Ok(self
+            .http
+            .request(method, url)
+            .bearer_auth(self.access_token().await?))
+    }
+
+    /// Every file and folder directly inside a folder
+    async fn children(&self, folder: &str) -> Result<Vec<DriveFile>, Error> {
+        let query = format!("{} in parents and trashed = false", quote(folder));
+        let mut files = Vec::new();
+        let mut page: Option<String> = None;
+        loop {
+            let mut req = self.request(Method::GET, FILES_URL).await?.query(&[
+                ("q", query.as_str()),
+                (
+                    "fields",
+                    "nextPageToken,files(id,name,mimeType,size,sha256Checksum)",
+                ),
+                ("pageSize", "1000"),
+            ]);
+            if let Some(page) = &page {
+                req = req.query(&[("pageToken", page.as_str())]);
+            }
+            let list: FileList = parse_response(req.send().await?).await?;
+            files.extend(list.files);
+            match list.next_page_token {
+                Some(next) => page = Some(next),
+                None => return Ok(files),
+            }
+        }
+    }
+
+    async fn find_or_create_folder(
+        &self,
+        name: &str,
+        parent: Option<&str>,
+    ) -> Result<String, Error> {
+        let mut query = format!(
+            "name = {} and mimeType = {} and trashed = false",
+            quote(name),
+            quote(FOLDER_TYPE)
+        );
+        if let Some(parent) = parent {
+            query.push_str(&format!(" and {} in parents", quote(parent)));
+        }
+        let list: FileList = parse_response(
+            self.request(Method::GET, FILES_URL)
+                .await?
+                .query(&[("q", query.as_str()), ("fields", "files(id,name,mimeType)")])
+                .send()
+                .await?,
+        )
+        .await?;
+        if let Some(folder) = list.files.into_iter().next() {
+            return Ok(folder.id);
+        }
+
+        let metadata = FileMetadata {
+            name,
+            mime_type: Some(FOLDER_TYPE),
+            parents: parent.into_iter().collect(),
+        };
+        let created: DriveFile = parse_response(
+            self.request(Method::POST, FILES_URL)
+                .await?
+                .query(&[("fields", "id,name,mimeType")])
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(simd_json::to_vec(&metadata)?)
+                .send()
+                .await?,
+        )
+        .await?;
+        Ok(created.id)
+    }
+
+    /// ID of the folder at `path` (relative to the guild's folder), creating
+    /// it and its parents if needed
+    async fn folder(&self, path: &str) -> Result<String, Error> {
+        let mut id = self
+            .folders
+            .lock()
+            .unwrap()
+            .get("")
+            .cloned()
+            .expect("Guild folder is found when connecting");
+        let mut current = String::new();
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(name);
+            let cached = self.folders.lock().unwrap().get(&current).cloned();
+            id = match cached {
+                Some(id) => id,
+                None => {
+                    let created = self.find_or_create_folder(name, Some(&id)).await?;
+                    self.folders
+                        .lock()
+                        .unwrap()
+                        .insert(current.clone(), created.clone());
+                    created
+                }
+            };
+        }
+        Ok(id)
+    }
+
+    async fn file_id(&self, path: &str) -> Result<Option<String>, Error> {
+        let cached = self.files.lock().unwrap().get(path).cloned();
+        if cached.is_some() {
+            return Ok(cached);
+        }
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.folder(parent).await?;
+        Ok(self
+            .children(&parent)
+            .await?
+            .into_iter()
+            .find(|f| f.name == name && f.mime_type != FOLDER_TYPE)
+            .map(|f| f.id))
+    }
+
+    /// Send the file a chunk at a time to a resumable upload session,
+    /// continuing from what Google has received after a failed chunk
+    async fn upload_session(&self, session: &str, local: &Path) -> Result<String, Error> {
+        let mut file = fs::File::open(local)?;
+        let total = file.metadata()?.len();
+        if total == 0 {
+            let response = self
+                .http
+                .put(session)
+                .header(header::CONTENT_LENGTH, 0)
+                .send()
+                .await?;
+            let uploaded: DriveFile = parse_response(response).await?;
+            return Ok(uploaded.id);
+        }
+
+        let mut offset = 0;
+        let mut failures = 0;
+        let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+        loop {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut length = 0;
+            while length < buffer.len() {
+                let read = file.read(&mut buffer[length..])?;
+                if read == 0 {
+                    break;
+                }
+                length += read;
+            }
+            let end = offset + length as u64 - 1;
+
+            let result = self
+                .http
+                .put(session)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", offset, end, total),
+                )
+                .body(buffer[..length].to_vec())
+                .send()
+                .await;
+            let response = match result {
+                Ok(response) if response.status().is_server_error() => None,
+                Ok(response) => Some(response),
+                Err(_) => None,
+            };
+
+            let response = match response {
+                Some(response) => response,
+                None => {
+                    failures += 1;
+                    if failures > UPLOAD_RETRIES {
+                        return Err(format!("Uploading {} kept failing", local.display()).into());
+                    }
+                    // Ask how much arrived before trying again
+                    let status = self
+                        .http
+                        .put(session)
+                        .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                        .header(header::CONTENT_LENGTH, 0)
+                        .send()
+                        .await?;
+                    offset = received(&status);
+                    continue;
+                }
+            };
+
+            if response.status() == StatusCode::PERMANENT_REDIRECT {
+                offset = received(&response);
+                continue;
+            }
+            let uploaded: DriveFile = parse_response(response).await?;
+            return Ok(uploaded.id);
+        }
+    }
+}
+
+/// Bytes received so far, from the `Range` header of an incomplete upload
+fn received(response: &reqwest::Response) -> u64 {
+    response
+        .headers()
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| r.rsplit('-').next())
+        .and_then(|end| end.parse::<u64>().ok())
+        .map_or(0, |end| end + 1)
+}
+
+impl RemoteStore for GoogleDrive {
+    fn put<'a>(&'a self, path: &'a str, local: &'a Path) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let parent = self.folder(parent).await?;
+
+            // Replace the existing file rather than adding a duplicate
+            let req = match self.file_id(path).await? {
+                Some(id) => self
+                    .request(Method::PATCH, &format!("{}/{}", UPLOAD_URL, id))
+                    .await?
+                    .body(simd_json::to_vec(&FileMetadata {
+                        name,
+                        mime_type: None,
+                        parents: Vec::new(),
+                    })?),
+                None => self
+                    .request(Method::POST, UPLOAD_URL)
+                    .await?
+                    .body(simd_json::to_vec(&FileMetadata {
+                        name,
+                        mime_type: None,
+                        parents: vec![parent.as_str()],
+                    })?),
+            };
+            let response = req
+                .query(&[("uploadType", "resumable")])
+                .header(header::CONTENT_TYPE, "application/json; charset=UTF-8")
+                .send()
+                .await?
+                .error_for_status()?;
+            let session = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or("Google Drive didn't return an upload session")?
+                .to_owned();
+
+            let id = self.upload_session(&session, local).await?;
+            self.files.lock().unwrap().insert(path.to_owned(), id);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(&'a self, path: &'a str, local: &'a Path) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let id = self
+                .file_id(path)
+                .await?
+                .ok_or_else(|| format!("{} isn't in Google Drive", path))?;
+            let mut response = self
+                .request(Method::GET, &format!("{}/{}", FILES_URL, id))
+                .await?
+                .query(&[("alt", "media")])
+                .send()
+                .await?
+                .error_for_status()?;
+            let mut file = tokio::fs::File::create(local).await?;
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<RemoteObject>, Error>> {
+        async move {
+            let prefix = prefix.trim_end_matches('/');
+            let mut objects = Vec::new();
+            let mut pending = vec![(prefix.to_owned(), self.folder(prefix).await?)];
+            while let Some((path, folder)) = pending.pop() {
+                for child in self.children(&folder).await? {
+                    let child_path = if path.is_empty() {
+                        child.name.clone()
+                    } else {
+                        format!("{}/{}", path, child.name)
+                    };
+                    if child.mime_type == FOLDER_TYPE {
+                        self.folders
+                            .lock()
+                            .unwrap()
+                            .insert(child_path.clone(), child.id.clone());
+                        pending.push((child_path, child.id));
+                    } else {
+                        self.files
+                            .lock()
+                            .unwrap()
+                            .insert(child_path.clone(), child.id.clone());
+                        objects.push(RemoteObject {
+                            path: child_path,
+                            size: child.size.and_then(|s| s.parse().ok()).unwrap_or(0),
+                            sha256: child.sha256_checksum,
+                        });
+                    }
+                }
+            }
+            Ok(objects)
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            if let Some(id) = self.file_id(path).await? {
+                self.request(Method::DELETE, &format!("{}/{}", FILES_URL, id))
+                    .await?
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                self.files.lock().unwrap().remove(path);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
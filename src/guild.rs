@@ -0,0 +1,328 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use simd_json::OwnedValue;
+use twilight_http::Client;
+use twilight_model::{
+    guild::{Guild, Member, PremiumTier},
+    id::GuildId,
+};
+
+use crate::{
+    api::Api,
+    archive::write_json,
+    attachments::download_file,
+    membership,
+    model::{CHANNELS_FILE, GUILD_FILE, MEMBERS_FILE, ROLES_FILE},
+    progress::Progress,
+    Error,
+};
+
+pub const INTEGRATIONS_FILE: &str = "integrations.json";
+pub const WELCOME_SCREEN_FILE: &str = "welcome_screen.json";
+pub const ONBOARDING_FILE: &str = "onboarding.json";
+pub const TEMPLATES_FILE: &str = "templates.json";
+pub const BOOSTERS_FILE: &str = "boosters.json";
+pub const COMMANDS_FILE: &str = "commands.json";
+pub const SOUNDBOARD_FILE: &str = "soundboard.json";
+pub const SOUNDBOARD_DIR: &str = "attachments/soundboard";
+const SOUNDBOARD_CDN: &str = "https://cdn.discordapp.com/soundboard-sounds";
+
+const MEMBER_CHUNK_SIZE: u64 = 1000;
+/// Boosts needed for each premium tier. Only used to say how far off the
+/// next tier is, the tier itself is Discord's.
+const BOOST_TIERS: [u64; 3] = [2, 7, 14];
+
+#[derive(Serialize)]
+struct GuildExport {
+    guild: Guild,
+    /// Whether the widget is enabled and which channel it invites to
+    widget: Option<OwnedValue>,
+    /// Vanity invite code and how many times it has been used
+    vanity_url: Option<OwnedValue>,
+    boosts: BoostStatus,
+}
+
+#[derive(Serialize)]
+struct CommandsExport {
+    application_id: String,
+    commands: OwnedValue,
+    /// Per-guild permission overrides for the commands and the application
+    permissions: OwnedValue,
+}
+
+#[derive(Deserialize)]
+struct Application {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct SoundboardSounds {
+    items: Vec<SoundboardSound>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SoundboardSound {
+    sound_id: String,
+    name: String,
+    #[serde(default)]
+    volume: f64,
+    #[serde(default)]
+    emoji_id: Option<String>,
+    #[serde(default)]
+    emoji_name: Option<String>,
+    #[serde(default)]
+    available: bool,
+    /// Who uploaded the sound
+    #[serde(default)]
+    user: Option<OwnedValue>,
+    /// Where the audio was saved, relative to the backup
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BoostStatus {
+    /// Premium tier as Discord reports it, 0 to 3
+    tier: usize,
+    count: u64,
+    /// Boosts needed for the next tier, if there is one
+    next_tier_at: Option<u64>,
+}
+
+impl BoostStatus {
+    fn new(tier: PremiumTier, count: u64) -> Self {
+        let tier = tier as usize;
+        BoostStatus {
+            tier,
+            count,
+            next_tier_at: BOOST_TIERS.get(tier).copied(),
+        }
+    }
+}
+
+fn report(progress: &Progress, what: &str, result: Result<(), Error>) {
+    match result {
+        Ok(()) => progress.info(format!("Exported {}", what)),
+        Err(e) => progress.error(format!("Error exporting {} {:?}", what, e)),
+    }
+}
+
+/// The guild itself, plus the settings that have their own endpoints
+async fn export_guild(
+    client: &Client,
+    api: &Api,
+    guild_id: GuildId,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let guild = client.guild(guild_id).exec().await?.model().await?;
+
+    let widget = match api.get(&format!("/guilds/{}/widget", guild_id)).await {
+        Ok(widget) => Some(widget),
+        Err(e) => {
+            progress.error(format!("Error exporting widget settings {:?}", e));
+            None
+        }
+    };
+    // Only guilds with the vanity URL feature have one
+    let vanity_url = if guild.vanity_url_code.is_some() {
+        match api.get(&format!("/guilds/{}/vanity-url", guild_id)).await {
+            Ok(vanity_url) => Some(vanity_url),
+            Err(e) => {
+                progress.error(format!("Error exporting vanity URL {:?}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let boosts = BoostStatus::new(
+        guild.premium_tier,
+        guild.premium_subscription_count.unwrap_or(0),
+    );
+    write_json(
+        GUILD_FILE,
+        &GuildExport {
+            guild,
+            widget,
+            vanity_url,
+            boosts,
+        },
+    )
+}
+
+/// Every member (needs the server members intent), and separately the ones
+/// currently boosting the guild
+async fn export_members(client: &Client, guild_id: GuildId) -> Result<(), Error> {
+    let mut members: Vec<Member> = Vec::new();
+    loop {
+        let mut req = client.guild_members(guild_id).limit(MEMBER_CHUNK_SIZE)?;
+        if let Some(last) = members.last() {
+            req = req.after(last.user.id);
+        }
+        let chunk = req.exec().await?.models().await?;
+        let count = chunk.len() as u64;
+        members.extend(chunk);
+        if count < MEMBER_CHUNK_SIZE {
+            break;
+        }
+    }
+    write_json(MEMBERS_FILE, &members)?;
+
+    let boosters: Vec<&Member> = members
+        .iter()
+        .filter(|m| m.premium_since.is_some())
+        .collect();
+    write_json(BOOSTERS_FILE, &boosters)
+}
+
+/// Slash commands this bot registered in the guild and their permissions,
+/// so they can be registered again. Other applications' commands aren't
+/// visible to bots, they are listed in the integrations export instead.
+async fn export_commands(api: &Api, guild_id: GuildId) -> Result<(), Error> {
+    let application: Application = api.get("/oauth2/applications/@me").await?;
+    let path = format!(
+        "/applications/{}/guilds/{}/commands",
+        application.id, guild_id
+    );
+    let commands = api.get(&path).await?;
+    let permissions = api.get(&format!("{}/permissions", path)).await?;
+    write_json(
+        COMMANDS_FILE,
+        &CommandsExport {
+            application_id: application.id,
+            commands,
+            permissions,
+        },
+    )
+}
+
+/// Custom soundboard sounds: the audio goes in the asset store next to
+/// attachments, the name, emoji and volume in the metadata file
+async fn export_soundboard(api: &Api, guild_id: GuildId, progress: &Progress) -> Result<(), Error> {
+    let mut sounds: SoundboardSounds = api
+        .get(&format!("/guilds/{}/soundboard-sounds", guild_id))
+        .await?;
+
+    let http = reqwest::Client::new();
+    for sound in sounds.items.iter_mut() {
+        let path = Path::new(SOUNDBOARD_DIR).join(&sound.sound_id);
+        let url = format!("{}/{}", SOUNDBOARD_CDN, sound.sound_id);
+        match download_file(&http, &path, &url, 0, None, progress).await {
+            Ok(_) => sound.path = Some(path.to_string_lossy().into_owned()),
+            Err(e) => progress.error(format!(
+                "Error downloading sound {} {:?}",
+                sound.sound_id, e
+            )),
+        }
+    }
+
+    write_json(SOUNDBOARD_FILE, &sounds.items)
+}
+
+async fn export_roles(client: &Client, guild_id: GuildId) -> Result<(), Error> {
+    let roles = client.roles(guild_id).exec().await?.models().await?;
+    write_json(ROLES_FILE, &roles)
+}
+
+/// Every channel including categories and voice channels, which have no
+/// messages to back up
+async fn export_channels(client: &Client, guild_id: GuildId) -> Result<(), Error> {
+    let channels = client
+        .guild_channels(guild_id)
+        .exec()
+        .await?
+        .models()
+        .await?;
+    write_json(CHANNELS_FILE, &channels)
+}
+
+/// Templates already created from the guild
+async fn export_templates(client: &Client, guild_id: GuildId) -> Result<(), Error> {
+    let templates = client
+        .get_templates(guild_id)
+        .exec()
+        .await?
+        .models()
+        .await?;
+    write_json(TEMPLATES_FILE, &templates)
+}
+
+/// Bots, Twitch/YouTube subscriptions and their role syncing settings
+async fn export_integrations(client: &Client, guild_id: GuildId) -> Result<(), Error> {
+    let integrations = client
+        .guild_integrations(guild_id)
+        .exec()
+        .await?
+        .models()
+        .await?;
+    write_json(INTEGRATIONS_FILE, &integrations)
+}
+
+/// Description and channel shortcuts shown to new members of community guilds
+async fn export_welcome_screen(client: &Client, guild_id: GuildId) -> Result<(), Error> {
+    let welcome_screen = client
+        .guild_welcome_screen(guild_id)
+        .exec()
+        .await?
+        .model()
+        .await?;
+    write_json(WELCOME_SCREEN_FILE, &welcome_screen)
+}
+
+/// Onboarding prompts, their options and the default channels. Not modelled
+/// by twilight so kept exactly as Discord returns it.
+async fn export_onboarding(api: &Api, guild_id: GuildId) -> Result<(), Error> {
+    let onboarding: OwnedValue = api.get(&format!("/guilds/{}/onboarding", guild_id)).await?;
+    write_json(ONBOARDING_FILE, &onboarding)
+}
+
+/// Export the guild's settings alongside its messages. Each export is
+/// independent; failures (usually missing permissions) are logged and skipped.
+pub async fn export_metadata(client: &Client, api: &Api, guild_id: GuildId, progress: &Progress) {
+    progress.info("Exporting guild metadata...".to_owned());
+    report(
+        progress,
+        "guild",
+        export_guild(client, api, guild_id, progress).await,
+    );
+    report(progress, "roles", export_roles(client, guild_id).await);
+    report(progress, "members", export_members(client, guild_id).await);
+    report(progress, "membership", membership::export());
+    report(
+        progress,
+        "channels",
+        export_channels(client, guild_id).await,
+    );
+    report(
+        progress,
+        "templates",
+        export_templates(client, guild_id).await,
+    );
+    report(
+        progress,
+        "integrations",
+        export_integrations(client, guild_id).await,
+    );
+    report(
+        progress,
+        "welcome screen",
+        export_welcome_screen(client, guild_id).await,
+    );
+    report(
+        progress,
+        "onboarding",
+        export_onboarding(api, guild_id).await,
+    );
+    report(
+        progress,
+        "application commands",
+        export_commands(api, guild_id).await,
+    );
+    report(
+        progress,
+        "soundboard",
+        export_soundboard(api, guild_id, progress).await,
+    );
+}
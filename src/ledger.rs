@@ -0,0 +1,71 @@
+use std::{
+    fs::{remove_file, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{ChannelId, MessageId};
+
+use crate::{attachments::IndexEntry, Error};
+
+/// One JSON entry per line, appended whenever something fails during a run
+pub const ERRORS_FILE: &str = "errors.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Failure {
+    /// Fetching messages failed partway through a channel. Its archive is
+    /// closed and valid up to `last_message`, where a retry continues from.
+    Chunk {
+        channel_id: ChannelId,
+        last_message: Option<MessageId>,
+        /// Whether the channel was being fetched oldest first
+        forward: bool,
+        error: String,
+    },
+    /// An attachment download failed, `entry` is what it was planned as
+    Attachment {
+        entry: IndexEntry,
+        error: String,
+        /// The CDN refused it, so the URL needs refreshing before a retry
+        #[serde(default)]
+        expired: bool,
+    },
+}
+
+pub fn record(failure: &Failure) -> Result<(), Error> {
+    let mut line = simd_json::to_vec(failure)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ERRORS_FILE)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Every failure recorded since the ledger was last cleared
+pub fn read() -> Result<Vec<Failure>, Error> {
+    let file = match File::open(ERRORS_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut failures = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let mut line = line?.into_bytes();
+        if line.is_empty() {
+            continue;
+        }
+        failures.push(simd_json::from_slice(&mut line)?);
+    }
+    Ok(failures)
+}
+
+/// Forget the recorded failures, before retrying them
+pub fn clear() -> Result<(), Error> {
+    match remove_file(ERRORS_FILE) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -1,238 +1,730 @@
+mod api;
+mod archive;
+mod attachments;
+mod bot;
+mod catalog;
+mod control;
+mod diskspace;
+mod drift;
+mod error;
+mod estimate;
+mod export;
+mod fixtures;
+mod gdrive;
+mod guild;
+mod ledger;
+mod manifest;
+mod membership;
+mod mentions;
+mod meta;
+mod model;
+mod oauth;
+mod permissions;
+mod progress;
+mod ratelimit;
+mod rclone;
+mod reactions;
+mod readstate;
+mod references;
+mod remote;
+mod resume;
+mod schema;
+mod scrape;
+mod serve;
+mod snapshot;
+mod snapshots;
+mod state;
+mod stats;
+mod tags;
+mod template;
+mod thumbnails;
+mod tui;
+
 use std::{
-    collections::HashSet,
-    env,
-    fs::{remove_file, OpenOptions},
-    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
 };
 
-use serde::{Deserialize, Serialize};
-use twilight_http::Client;
-use twilight_model::{
-    channel::{message::Message, Channel, ChannelType},
-    id::{ChannelId, GuildId, MessageId},
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use twilight_model::id::{ChannelId, GuildId, MessageId};
+
+use crate::{
+    api::HttpConfig,
+    archive::Order,
+    attachments::{parse_rate, parse_size, AttachmentOptions, AttachmentType},
+    catalog::{CatalogEntry, RunKind},
+    control::ControlCommand,
+    error::BackupError,
+    export::{ExportFormat, TimestampArgs},
+    progress::Progress,
+    remote::RemoteStore,
+    scrape::BackupOptions,
+    tags::TagFilter,
 };
 
-const STATE_FILE: &'static str = ".discord_scrape_state";
-const MESSAGE_CHUNK_SIZE: u64 = 100;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct State {
-    current_guild: GuildId,
-    current_channel: Option<ChannelId>,
-    last_message: Option<MessageId>,
-    channels_complete: HashSet<ChannelId>,
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Parser, Debug)]
+#[command(about, version)]
+struct Cli {
+    /// Discord token, a bot token unless `--token-type` says otherwise. Not
+    /// needed with `--token-type oauth`, which uses the token from `auth`
+    #[arg(long, env = "BOT_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Kind of account the token belongs to
+    #[arg(long, value_enum, default_value_t = TokenType::Bot)]
+    token_type: TokenType,
+
+    /// User agent to send instead of twilight's default
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    /// Send API requests to this host instead of Discord, e.g.
+    /// `http://localhost:8080` for a gateway that forwards them
+    #[arg(long, value_name = "URL", default_value = api::DEFAULT_API_BASE, value_parser = api::parse_api_base)]
+    api_base: String,
+
+    /// Discord API version for the endpoints twilight doesn't cover
+    /// (twilight's own requests always use v9)
+    #[arg(long, default_value_t = api::DEFAULT_API_VERSION)]
+    api_version: u8,
+
+    /// Record every API request and response into this directory, for
+    /// replaying later with `mock-api`
+    #[arg(long, value_name = "DIR")]
+    record_fixtures: Option<PathBuf>,
+
+    /// ID of the guild to back up
+    #[arg(long, env = "GUILD_ID", value_parser = parse_id)]
+    guild: Option<u64>,
+
+    /// Show an interactive terminal interface instead of logging to stderr
+    #[arg(long)]
+    tui: bool,
+
+    /// Only fetch messages newer than the newest one already archived for
+    /// each channel, appending them to the existing files
+    #[arg(long)]
+    resume_forward: bool,
+
+    /// Estimate how many messages each channel has before starting, so
+    /// progress can show an ETA
+    #[arg(long)]
+    prescan: bool,
+
+    /// Fetch everyone who reacted to each message, not just the counts
+    #[arg(long)]
+    reactions: bool,
+
+    /// Also store messages that are replied to or forwarded from outside
+    /// the backup (other channels, or before a partial range) in
+    /// `references.json`
+    #[arg(long)]
+    references: bool,
+
+    /// Order of the messages in each channel's file
+    #[arg(long, value_enum, default_value_t = Order::Desc)]
+    order: Order,
+
+    /// Only back up this channel, may be repeated
+    #[arg(long = "channel", value_name = "CHANNEL_ID", value_parser = parse_id)]
+    channels: Vec<u64>,
+
+    /// Newest message to fetch in each channel
+    #[arg(long, value_name = "MESSAGE_ID", conflicts_with_all = ["resume_forward", "around_message"], value_parser = parse_id)]
+    until_message: Option<u64>,
+
+    /// Oldest message to fetch in each channel
+    #[arg(long, value_name = "MESSAGE_ID", conflicts_with_all = ["resume_forward", "around_message"], value_parser = parse_id)]
+    from_message: Option<u64>,
+
+    /// Fetch only the messages surrounding this one in each channel
+    #[arg(long, value_name = "MESSAGE_ID", conflicts_with = "resume_forward", value_parser = parse_id)]
+    around_message: Option<u64>,
+
+    /// How many messages to fetch around `--around-message`
+    #[arg(long, default_value_t = 1000)]
+    around_count: u64,
+
+    /// Don't download attachments, only record them in the index
+    #[arg(long)]
+    skip_attachments: bool,
+
+    /// Skip attachments larger than this, e.g. `25MB`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_attachment_size: Option<u64>,
+
+    /// Only download attachments of these types
+    #[arg(long, value_enum, value_delimiter = ',')]
+    attachment_types: Vec<AttachmentType>,
+
+    /// Generate thumbnails for downloaded images (and videos, with ffmpeg)
+    #[arg(long)]
+    thumbnails: bool,
+
+    /// Number of attachments to download at once
+    #[arg(long, default_value_t = 4)]
+    download_concurrency: usize,
+
+    /// Limit attachment downloads to this rate, e.g. `10MB/s`
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    max_bandwidth: Option<u64>,
+
+    /// Pause the backup while less than this is free on disk
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "1GB")]
+    min_free_space: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn get_active_state() -> std::io::Result<State> {
-    let file = OpenOptions::new().read(true).open(STATE_FILE)?;
-    let reader = BufReader::new(file);
+/// Where `upload` and `restore` copy backups
+#[derive(Args, Debug)]
+struct StoreArgs {
+    /// rclone remote and path holding the backup, e.g. `s3:bucket/guild`
+    #[arg(long, value_name = "REMOTE", required_unless_present = "drive")]
+    rclone: Option<String>,
+
+    /// Use Google Drive, with a folder for the guild
+    #[arg(long, conflicts_with = "rclone")]
+    drive: bool,
+
+    /// Google Drive folder holding the guild folders
+    #[arg(long, default_value = "guild-backup")]
+    drive_folder: String,
+
+    /// OAuth client ID (of the "TVs and limited input devices" type)
+    #[arg(long, env = "GOOGLE_CLIENT_ID", required_if_eq("drive", "true"))]
+    google_client_id: Option<String>,
+
+    #[arg(
+        long,
+        env = "GOOGLE_CLIENT_SECRET",
+        hide_env_values = true,
+        required_if_eq("drive", "true")
+    )]
+    google_client_secret: Option<String>,
+
+    /// Where the Google Drive authorization is kept between runs
+    #[arg(long, default_value = ".gdrive_token")]
+    google_token_file: PathBuf,
+}
 
-    Ok(simd_json::from_reader(reader).expect("Unable to parse state file"))
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TokenType {
+    Bot,
+    User,
+    /// The OAuth2 bearer token stored by `auth`, refreshed when needed
+    Oauth,
 }
 
-fn save_active_state(state: &State) -> std::io::Result<()> {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(STATE_FILE)?;
+impl Cli {
+    fn token(&self) -> &str {
+        self.token
+            .as_deref()
+            .expect("The token is checked before it's used")
+    }
 
-    simd_json::to_writer(file, state).expect("Unable to serialize state");
+    fn authorization(&self) -> String {
+        match self.token_type {
+            TokenType::Bot => format!("Bot {}", self.token()),
+            TokenType::User => self.token().to_owned(),
+            TokenType::Oauth => format!("Bearer {}", self.token()),
+        }
+    }
 
-    Ok(())
+    fn http_config(&self) -> HttpConfig {
+        HttpConfig {
+            authorization: self.authorization(),
+            user_agent: self.user_agent.clone(),
+            api_base: self.api_base.clone(),
+            api_version: self.api_version,
+        }
+    }
+
+    fn backup_options(&self) -> BackupOptions {
+        BackupOptions {
+            resume_forward: self.resume_forward,
+            channels: self
+                .channels
+                .iter()
+                .map(|id| ChannelId::new(*id).expect("IDs are checked by parse_id"))
+                .collect(),
+            until_message: self.until_message.map(message_id),
+            from_message: self.from_message.map(message_id),
+            around_message: self.around_message.map(message_id),
+            around_count: self.around_count,
+            attachments: AttachmentOptions {
+                skip: self.skip_attachments,
+                max_size: self.max_attachment_size,
+                types: self.attachment_types.clone(),
+                thumbnails: self.thumbnails,
+                concurrency: self.download_concurrency,
+            },
+            min_free_space: self.min_free_space,
+            order: self.order,
+            prescan: self.prescan,
+            reactions: self.reactions,
+            references: self.references,
+            single_channel: false,
+        }
+    }
+
+    fn guild_id(&self) -> Result<GuildId, Error> {
+        let guild = self.guild.ok_or("--guild or GUILD_ID is required")?;
+        Ok(GuildId::new(guild).expect("IDs are checked by parse_id"))
+    }
 }
 
-async fn fetch_message_chunk(
-    client: &Client,
-    state: &State,
-) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
-    let req = client
-        .channel_messages(
-            state
-                .current_channel
-                .expect("Fetched message chunk without channel... wot?"),
-        )
-        .limit(MESSAGE_CHUNK_SIZE)?;
-
-    eprintln!(
-        "Fetching message chunk {:?}/{:?}",
-        state.current_channel, state.last_message
-    );
-    Ok(if let Some(last_message) = state.last_message {
-        req.before(last_message).exec().await?.models().await?
-    } else {
-        req.exec().await?.models().await?
-    })
+/// Name of the folder a guild's backup is uploaded into: its ID, from the
+/// arguments or the backup, or `dms` for private channels
+fn guild_folder(cli: &Cli) -> String {
+    if let Some(guild) = cli.guild {
+        return guild.to_string();
+    }
+    match model::read_json::<model::GuildFileData>(model::GUILD_FILE) {
+        Ok(guild) => guild.guild.id.to_string(),
+        Err(_) => "dms".to_owned(),
+    }
 }
 
-async fn fetch_archived_threads(
-    client: &Client,
-    channel: &ChannelId,
-    channels: &mut Vec<Channel>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    channels.append(
-        &mut client
-            .public_archived_threads(*channel)
-            .exec()
-            .await?
-            .model()
-            .await?
-            .threads,
-    );
-    channels.append(
-        &mut client
-            .private_archived_threads(*channel)
-            .exec()
-            .await?
-            .model()
-            .await?
-            .threads,
-    );
-    Ok(())
+async fn remote_store(cli: &Cli, store: &StoreArgs) -> Result<Box<dyn RemoteStore>, Error> {
+    if let Some(rclone) = &store.rclone {
+        return Ok(Box::new(rclone::Rclone::new(rclone.clone())));
+    }
+    let credentials = gdrive::Credentials {
+        client_id: store.google_client_id.clone().unwrap_or_default(),
+        client_secret: store.google_client_secret.clone().unwrap_or_default(),
+        token_file: store.google_token_file.clone(),
+    };
+    Ok(Box::new(
+        gdrive::GoogleDrive::connect(credentials, &store.drive_folder, &guild_folder(cli)).await?,
+    ))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let bot_token = env::var("BOT_TOKEN")?;
-    let guild_id =
-        GuildId::new(u64::from_str_radix(&env::var("GUILD_ID")?, 10)?).expect("Invalid guild ID");
-
-    let client = Client::new(format!("Bot {}", bot_token));
-
-    let mut state = get_active_state().unwrap_or_else(|_| State {
-        current_guild: guild_id,
-        current_channel: None,
-        last_message: None,
-        channels_complete: HashSet::new(),
-    });
-
-    assert_eq!(guild_id, state.current_guild);
-
-    save_active_state(&state)?;
-
-    let mut channels: Vec<Channel> = Vec::new();
-
-    eprintln!("Fetching channels...");
-    channels.extend(
-        client
-            .guild_channels(state.current_guild)
-            .exec()
-            .await?
-            .models()
-            .await?
-            .iter()
-            .map(|c| Channel::Guild(c.to_owned())),
-    );
-
-    eprintln!("Fetching active threads...");
-    channels.append(
-        &mut client
-            .active_threads(state.current_guild)
-            .exec()
-            .await?
-            .model()
-            .await?
-            .threads,
-    );
-
-    let mut messages: Vec<Message> = Vec::new();
-    let mut counter = 0;
-
-    eprintln!("Fetching messages...");
-    while let Some(channel) = channels.pop() {
-        if channel.kind() != ChannelType::GuildText
-            && channel.kind() != ChannelType::GuildPublicThread
-            && channel.kind() != ChannelType::GuildPrivateThread
-        {
-            eprintln!("Skipping {} (bad type {:?})", channel.id(), channel.kind());
-            continue;
-        }
+/// Discord IDs are never 0, so they're checked as they're parsed rather than
+/// where they're used
+fn parse_id(value: &str) -> Result<u64, String> {
+    match value.parse() {
+        Ok(0) => Err("IDs can't be 0".to_owned()),
+        Ok(id) => Ok(id),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
 
-        // Skip channels we've already read
-        if state.channels_complete.contains(&channel.id()) {
-            eprintln!("Skipping {} (already done)", channel.id());
-            continue;
-        }
+fn message_id(id: u64) -> MessageId {
+    MessageId::new(id).expect("IDs are checked by parse_id")
+}
 
-        // Fetch archived threads
-        if channel.kind() == ChannelType::GuildText {
-            if let Err(e) = fetch_archived_threads(&client, &channel.id(), &mut channels).await {
-                eprintln!("Error fetching archived threads {:?}", e);
-            }
-        }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run as a bot serving `/backup start` and `/backup status` in the guild
+    Bot {
+        /// Channel to post backup results in
+        #[arg(long, env = "STAFF_CHANNEL_ID", value_parser = parse_id)]
+        staff_channel: u64,
+
+        /// Every this many seconds, record member and online counts and
+        /// messages per channel to `snapshots.json`
+        #[arg(long, value_name = "SECONDS")]
+        snapshot_interval: Option<u64>,
+    },
+    /// Back up the DMs and group DMs visible to the token (needs `--token-type user`)
+    Dms,
+    /// Download the attachments of messages already in the backup
+    FetchAttachments,
+    /// Generate thumbnails for attachments already downloaded
+    Thumbnails,
+    /// Retry the channels and attachments that failed in earlier runs, as
+    /// recorded in `errors.json`
+    RetryFailed,
+    /// Fetch a channel, or a range of it, again and replace those messages
+    /// in its archive, for when an earlier run was corrupted or filtered the
+    /// wrong messages
+    Rescrape {
+        /// The channel to fetch again
+        #[arg(long, value_name = "CHANNEL_ID", value_parser = parse_id)]
+        channel: u64,
+        /// Only messages newer than this one
+        #[arg(long, value_name = "MESSAGE_ID", value_parser = parse_id)]
+        after: Option<u64>,
+        /// Only messages older than this one
+        #[arg(long, value_name = "MESSAGE_ID", value_parser = clap::value_parser!(u64).range(2..))]
+        before: Option<u64>,
+    },
+    /// Build a guild template (roles, categories, channels and overwrites)
+    /// from the backup, written to `template.json`
+    Template {
+        /// Name of the guild created from the template
+        #[arg(long, default_value = "Restored guild")]
+        name: String,
+    },
+    /// Report which roles and members can see and post in each channel,
+    /// worked out from the roles, channels and members in the backup
+    PermissionsReport {
+        /// Write the Markdown report here instead of to stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Rank the custom emoji and stickers by how often they're used in the
+    /// backed up messages and reactions
+    EmojiStats {
+        /// Write the Markdown report here instead of to stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        filter: TagFilter,
+    },
+    /// Tag each backed up message with its language in `tags.json`, for
+    /// filtering `search` and `emoji-stats`. Only untagged messages are
+    /// tagged, so it can be run after every backup
+    Tag {
+        /// Also tag messages with simple content categories (links, code,
+        /// questions and attachments)
+        #[arg(long)]
+        categories: bool,
+    },
+    /// Find backed up messages containing some text
+    Search {
+        query: String,
+        #[command(flatten)]
+        filter: TagFilter,
+    },
+    /// Create a key pair for signing manifests
+    Keygen {
+        /// Where to write the secret key, the public key is written next to
+        /// it with a `.pub` extension. Defaults to `backup.key` in the config
+        /// directory, outside any backup
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Write `manifest.json`, listing the size and SHA-256 of every file in
+    /// the backup
+    Manifest {
+        /// Sign the manifest with this secret key (from `keygen`)
+        #[arg(long, value_name = "KEY")]
+        sign_key: Option<PathBuf>,
+    },
+    /// Check every file in the backup against the JSON Schemas in
+    /// `schemas/`, listing the malformed files and fields
+    Validate,
+    /// Check the backup's files against `manifest.json`
+    Verify {
+        /// Also check the manifest's signature with this public key
+        #[arg(long, value_name = "PUBLIC_KEY")]
+        signature: Option<PathBuf>,
+
+        /// Instead compare the backup with the live guild, reporting new or
+        /// deleted channels and roles, unarchived messages and member counts
+        #[arg(long, conflicts_with = "signature")]
+        live: bool,
+    },
+    /// List the backups, updates and snapshots made in this directory, from
+    /// `catalog.json`
+    History,
+    /// Save the backup as it is now in `snapshots/<time>/`, hard linking
+    /// files unchanged since the previous snapshot
+    Snapshot,
+    /// Copy the backup to remote storage, skipping files already uploaded
+    Upload {
+        #[command(flatten)]
+        store: StoreArgs,
+    },
+    /// Copy a backup from remote storage into this directory, to continue an
+    /// unfinished one or restore a finished one
+    Restore {
+        #[command(flatten)]
+        store: StoreArgs,
+    },
+    /// Write readable transcripts of the backed up channels to `exports/`
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Markdown)]
+        format: ExportFormat,
+
+        #[command(flatten)]
+        timestamps: TimestampArgs,
+    },
+    /// Browse the backup in a web browser: channels, search and attachments
+    /// served straight from the backup, without exporting it
+    Serve {
+        #[arg(long, default_value_t = 8000)]
+        port: u16,
+
+        #[command(flatten)]
+        timestamps: TimestampArgs,
+    },
+    /// Authorize with OAuth2 in the browser and store the tokens, for
+    /// backing up with `--token-type oauth` when adding a bot isn't possible
+    Auth {
+        /// ID of the OAuth2 application
+        #[arg(long)]
+        client_id: Option<u64>,
+        #[arg(long, env = "OAUTH_CLIENT_SECRET", hide_env_values = true)]
+        client_secret: Option<String>,
+        /// Scope to request, may be repeated
+        #[arg(long = "scope", value_name = "SCOPE", default_values_t = oauth::DEFAULT_SCOPES.iter().map(|s| s.to_string()))]
+        scopes: Vec<String>,
+        /// Port to listen for the redirect on, `http://127.0.0.1:<port>/callback`
+        /// must be registered as a redirect for the application
+        #[arg(long, default_value_t = oauth::DEFAULT_REDIRECT_PORT)]
+        port: u16,
+        /// Refresh the stored tokens instead of authorizing again
+        #[arg(long)]
+        refresh: bool,
+    },
+    /// Pause, resume, skip the current channel of, stop or check on the
+    /// backup running in this directory
+    Control {
+        #[arg(value_enum)]
+        command: ControlCommand,
+    },
+    /// Serve responses recorded with `--record-fixtures` as a mock Discord
+    /// API, for running against with `--api-base`
+    MockApi {
+        /// Directory the fixtures were recorded into
+        fixtures: PathBuf,
+
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+    /// Show who joined, who left and whose roles changed since an earlier
+    /// backup, from the `membership.json` of each
+    MembershipDiff {
+        /// Directory of the earlier backup
+        previous: PathBuf,
+    },
+}
 
-        let file_name = format!("{}.messages.json", channel.id());
-        let mut file = if state.current_channel != Some(channel.id()) {
-            state.current_channel = Some(channel.id());
-            state.last_message = None;
-
-            let meta_file = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(format!("{}.meta.json", channel.id()))?;
-            simd_json::to_writer(meta_file, &channel)?;
-
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(file_name)?;
-            write!(file, "[")?;
-            file
-        } else {
-            OpenOptions::new().write(true).open(file_name)?
-        };
-        save_active_state(&state)?;
-
-        while state.last_message.is_none()
-            || messages.len().try_into().unwrap_or(0) == MESSAGE_CHUNK_SIZE
-        {
-            match fetch_message_chunk(&client, &state).await {
-                Ok(r) => messages = r,
-                Err(e) => {
-                    eprintln!("Error getting message chunk {:?}", e);
-                    break;
-                }
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        // Failures with something to be done about them say what, anything
+        // else is shown as it is
+        Err(e) => match BackupError::classify(e) {
+            Ok(e) => {
+                eprintln!("Error: {}", e);
+                e.exit_code()
             }
-
-            eprintln!(
-                "Received message chunk {}/{}",
-                messages.len(),
-                MESSAGE_CHUNK_SIZE
-            );
-
-            let message_count = messages.len();
-            if message_count == 0 {
-                break;
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                ExitCode::FAILURE
             }
+        },
+    }
+}
 
-            if state.last_message.is_some() {
-                write!(file, ",")?;
-            }
-            for i in 0..message_count {
-                simd_json::to_writer(&mut file, &messages[i])?;
-                if i < message_count - 1 {
-                    write!(file, ",")?;
-                }
-            }
+async fn run() -> Result<(), Error> {
+    let mut cli = Cli::parse();
 
-            state.last_message = messages.last().map(|m| m.id);
-            save_active_state(&state)?;
+    match cli.command {
+        Some(Command::MockApi { ref fixtures, port }) => {
+            return fixtures::replay(fixtures, port).await
+        }
+        Some(Command::Control { command }) => return control::send(command).await,
+        Some(Command::Validate) => return schema::validate(),
+        Some(Command::Serve {
+            port,
+            ref timestamps,
+        }) => return serve::serve(port, timestamps.options(ExportFormat::Html)).await,
+        Some(Command::Auth {
+            client_id,
+            ref client_secret,
+            ref scopes,
+            port,
+            refresh,
+        }) => {
+            if refresh {
+                return oauth::refresh(&cli.api_base).await;
+            }
+            let client_id = client_id.ok_or("--client-id is required to authorize")?;
+            let client_secret = client_secret
+                .as_deref()
+                .ok_or("--client-secret or OAUTH_CLIENT_SECRET is required to authorize")?;
+            return oauth::authorize(&cli.api_base, client_id, client_secret, scopes, port).await;
         }
+        _ => {}
+    }
+    match cli.token_type {
+        TokenType::Oauth => cli.token = Some(oauth::access_token(&cli.api_base).await?),
+        _ if cli.token.is_none() => return Err("--token or BOT_TOKEN is required".into()),
+        _ => {}
+    }
+    if let Some(dir) = cli.record_fixtures.clone() {
+        let addr = fixtures::record(&dir, &cli.api_base)?;
+        cli.api_base = format!("http://{}", addr);
+    }
 
-        write!(file, "]")?;
-        state.channels_complete.insert(channel.id());
-        save_active_state(&state)?;
+    if let Some(Command::Bot {
+        staff_channel,
+        snapshot_interval,
+    }) = cli.command
+    {
+        let staff_channel = ChannelId::new(staff_channel).expect("IDs are checked by parse_id");
+        let snapshot_interval = snapshot_interval.map(Duration::from_secs);
+        // Every backup after the first brings the archive up to date
+        let options = BackupOptions {
+            resume_forward: true,
+            ..cli.backup_options()
+        };
+        return bot::run(
+            cli.token(),
+            &cli.http_config(),
+            cli.guild_id()?,
+            staff_channel,
+            snapshot_interval,
+            options,
+        )
+        .await;
+    }
 
-        counter += 1;
-        eprintln!(
-            "[{}/{:?}] Completed channel {}...",
-            counter,
-            channels.len() + state.channels_complete.len(),
-            channel.id()
+    let http = cli.http_config();
+    let client = http.client();
+
+    let progress = Arc::new(Progress::new(cli.tui));
+    progress.bandwidth.set_limit(cli.max_bandwidth);
+    // Before the interface takes over the terminal, so failing to listen
+    // doesn't leave it in raw mode
+    let _control = match cli.command {
+        None
+        | Some(
+            Command::Dms
+            | Command::RetryFailed
+            | Command::Rescrape { .. }
+            | Command::FetchAttachments,
+        ) => Some(control::listen(progress.clone())?),
+        _ => None,
+    };
+
+    let ui = if cli.tui {
+        Some(tui::spawn(progress.clone()))
+    } else {
+        None
+    };
+
+    let api = http.api();
+    let options = cli.backup_options();
+    let started_at = catalog::now();
+    let result = match cli.command {
+        Some(Command::Thumbnails) => thumbnails::generate_all(&progress),
+        Some(Command::Template { ref name }) => template::generate(name),
+        Some(Command::Snapshot) => snapshot::create(),
+        Some(Command::History) => catalog::history(cli.guild.and_then(GuildId::new)),
+        Some(Command::Upload { ref store }) => match remote_store(&cli, store).await {
+            Ok(store) => remote::upload(store.as_ref(), &progress).await,
+            Err(e) => Err(e),
+        },
+        Some(Command::Restore { ref store }) => match remote_store(&cli, store).await {
+            Ok(store) => remote::download(store.as_ref(), &progress).await,
+            Err(e) => Err(e),
+        },
+        Some(Command::Keygen { ref output }) => manifest::keygen(output.as_deref()),
+        Some(Command::Manifest { ref sign_key }) => manifest::write(sign_key.as_deref()),
+        Some(Command::Verify { live: true, .. }) => match cli.guild_id() {
+            Ok(guild_id) => drift::check(&api, guild_id).await,
+            Err(e) => Err(e),
+        },
+        Some(Command::Verify { ref signature, .. }) => manifest::verify(signature.as_deref()),
+        Some(Command::Export {
+            format,
+            ref timestamps,
+        }) => export::export(&api, &timestamps.options(format)).await,
+        Some(Command::MembershipDiff { ref previous }) => membership::diff(previous),
+        Some(Command::PermissionsReport { ref output }) => permissions::report(output.as_deref()),
+        Some(Command::EmojiStats {
+            ref output,
+            ref filter,
+        }) => stats::emoji_report(output.as_deref(), filter),
+        Some(Command::Tag { categories }) => tags::tag(categories),
+        Some(Command::Search {
+            ref query,
+            ref filter,
+        }) => tags::search(query, filter),
+        Some(Command::FetchAttachments) => {
+            attachments::fetch_attachments(&options.attachments, &progress).await
+        }
+        Some(Command::RetryFailed) => {
+            let guild = cli
+                .guild
+                .map(|id| GuildId::new(id).expect("IDs are checked by parse_id"));
+            scrape::retry_failed(&client, &api, guild, &options, &progress)
+                .await
+                .map_err(Into::into)
+        }
+        Some(Command::Rescrape {
+            channel,
+            after,
+            before,
+        }) => {
+            let guild = cli
+                .guild
+                .map(|id| GuildId::new(id).expect("IDs are checked by parse_id"));
+            // The range is exclusive, the options inclusive
+            let options = BackupOptions {
+                from_message: after.map(|id| message_id(id + 1)),
+                until_message: before.map(|id| message_id(id - 1)),
+                ..options.clone()
+            };
+            let channel = ChannelId::new(channel).expect("IDs are checked by parse_id");
+            scrape::rescrape(&client, &api, guild, channel, &options, &progress)
+                .await
+                .map_err(Into::into)
+        }
+        Some(Command::Dms) => scrape::backup_private_channels(&client, &api, &options, &progress)
+            .await
+            .map_err(Into::into),
+        _ => match cli.guild_id() {
+            Ok(guild_id) => scrape::backup_guild(&client, &api, guild_id, &options, &progress)
+                .await
+                .map_err(Into::into),
+            Err(e) => Err(e),
+        },
+    };
+    if result.is_ok()
+        && cli.token_type == TokenType::User
+        && matches!(cli.command, None | Some(Command::Dms))
+    {
+        // Only a nicety for personal archives, the backup itself is done
+        if let Err(e) = readstate::export(cli.token(), &progress).await {
+            progress.error(format!("Error saving read states {:?}", e));
+        }
+    }
+    progress.finish();
+
+    let run = match cli.command {
+        None if cli.guild.is_none() => None,
+        None if cli.resume_forward => Some((RunKind::Incremental, cli.guild)),
+        None => Some((RunKind::Full, cli.guild)),
+        Some(Command::Dms) => Some((RunKind::Full, None)),
+        Some(Command::RetryFailed) => Some((RunKind::Retry, cli.guild)),
+        Some(Command::Rescrape { .. }) => Some((RunKind::Rescrape, cli.guild)),
+        _ => None,
+    };
+    if let Some((kind, guild)) = run {
+        // Stopping leaves the state file behind to resume from
+        let complete = result.is_ok() && !Path::new(state::STATE_FILE).exists();
+        let entry = CatalogEntry::run(
+            kind,
+            guild.and_then(GuildId::new),
+            started_at,
+            complete,
+            &progress.state(),
         );
+        if let Err(e) = catalog::record(entry) {
+            eprintln!("Error recording the run in the catalog {:?}", e);
+        }
     }
 
-    remove_file(STATE_FILE)?;
-    eprintln!("Done!");
+    if let Some(ui) = ui {
+        ui.join().expect("TUI thread panicked")?;
+        // Output was captured by the interface, repeat anything worth keeping
+        for line in progress.state().errors.iter() {
+            eprintln!("{}", line);
+        }
+    }
 
-    Ok(())
+    result
 }
@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive::{backup_files, write_atomic, write_json},
+    attachments::hash_file,
+    model::read_json,
+    oauth::{config_dir, open_private},
+    Error,
+};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Hex encoded ed25519 signature of the manifest file's bytes
+pub const SIGNATURE_FILE: &str = "manifest.json.sig";
+/// Name of the secret key `keygen` writes by default
+const KEY_FILE: &str = "backup.key";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// Unix time in seconds
+    pub created_at: u64,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    /// Relative to the backup, with `/` separators
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+fn entry(name: String, path: &Path) -> Result<ManifestEntry, Error> {
+    Ok(ManifestEntry {
+        path: name,
+        size: fs::metadata(path)?.len(),
+        sha256: hash_file(path)?,
+    })
+}
+
+/// Every file in the backup with its size and hash, except the manifest
+fn scan() -> Result<Vec<ManifestEntry>, Error> {
+    let mut entries = Vec::new();
+    for (name, path) in backup_files()? {
+        if name == MANIFEST_FILE || name == SIGNATURE_FILE {
+            continue;
+        }
+        entries.push(entry(name, &path)?);
+    }
+    Ok(entries)
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32], Error> {
+    let bytes = hex::decode(fs::read_to_string(path)?.trim())?;
+    Ok(bytes
+        .try_into()
+        .map_err(|_| format!("{} is not a 32 byte key", path.display()))?)
+}
+
+/// Create a signing key in `path` (keep it secret), by default in the config
+/// directory so it's never part of a backup, and its public key next to it
+/// with a `.pub` extension, both hex encoded. Existing keys are never
+/// replaced.
+pub fn keygen(path: Option<&Path>) -> Result<(), Error> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => config_dir()?.join(KEY_FILE),
+    };
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let public = path.with_extension("pub");
+    if public.exists() {
+        return Err(format!("{} already exists, it won't be replaced", public.display()).into());
+    }
+
+    let key = SigningKey::generate(&mut OsRng);
+    let mut file = open_private(&path, true).map_err(|e| -> Error {
+        match e.kind() {
+            ErrorKind::AlreadyExists => {
+                format!("{} already exists, it won't be replaced", path.display()).into()
+            }
+            _ => e.into(),
+        }
+    })?;
+    file.write_all(hex::encode(key.to_bytes()).as_bytes())?;
+    file.sync_all()?;
+    File::options()
+        .write(true)
+        .create_new(true)
+        .open(&public)?
+        .write_all(hex::encode(key.verifying_key().to_bytes()).as_bytes())?;
+    println!("Wrote {} and {}", path.display(), public.display());
+    Ok(())
+}
+
+/// Write the manifest of every file in the backup, signing it with the key
+/// at `sign_key` if given
+pub fn write(sign_key: Option<&Path>) -> Result<(), Error> {
+    let manifest = Manifest {
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        files: scan()?,
+    };
+    write_json(MANIFEST_FILE, &manifest)?;
+    println!("Wrote {} ({} files)", MANIFEST_FILE, manifest.files.len());
+
+    if let Some(sign_key) = sign_key {
+        let key = SigningKey::from_bytes(&read_key(sign_key)?);
+        let signature = key.sign(&fs::read(MANIFEST_FILE)?);
+        write_atomic(
+            Path::new(SIGNATURE_FILE),
+            hex::encode(signature.to_bytes()).as_bytes(),
+        )?;
+        println!("Signed manifest in {}", SIGNATURE_FILE);
+    }
+    Ok(())
+}
+
+/// Bring an existing manifest up to date after some files were rewritten,
+/// hashing only the `changed` files and those not in it yet. Does nothing if
+/// the backup has no manifest.
+pub fn refresh(changed: &[String]) -> Result<(), Error> {
+    if !Path::new(MANIFEST_FILE).exists() {
+        return Ok(());
+    }
+    let manifest: Manifest = read_json(MANIFEST_FILE)?;
+    let previous: HashMap<String, ManifestEntry> = manifest
+        .files
+        .into_iter()
+        .map(|e| (e.path.clone(), e))
+        .collect();
+
+    let mut files = Vec::new();
+    for (name, path) in backup_files()? {
+        if name == MANIFEST_FILE || name == SIGNATURE_FILE {
+            continue;
+        }
+        match previous.get(&name) {
+            Some(known) if !changed.contains(&name) => files.push(known.clone()),
+            _ => files.push(entry(name, &path)?),
+        }
+    }
+    let manifest = Manifest {
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        files,
+    };
+    write_json(MANIFEST_FILE, &manifest)?;
+    println!("Updated {} ({} files)", MANIFEST_FILE, manifest.files.len());
+    if Path::new(SIGNATURE_FILE).exists() {
+        println!(
+            "{} no longer matches, sign the manifest again with manifest --sign-key",
+            SIGNATURE_FILE
+        );
+    }
+    Ok(())
+}
+
+fn verify_signature(public_key: &Path) -> Result<(), Error> {
+    let key = VerifyingKey::from_bytes(&read_key(public_key)?)?;
+    let signature = hex::decode(fs::read_to_string(SIGNATURE_FILE)?.trim())?;
+    let signature = Signature::from_slice(&signature)?;
+    key.verify(&fs::read(MANIFEST_FILE)?, &signature)
+        .map_err(|_| "Manifest signature is invalid, it has been changed since it was signed")?;
+    println!("Manifest signature is valid");
+    Ok(())
+}
+
+/// Check every file in the backup against the manifest, and with a
+/// `public_key` that the manifest itself was signed by that key
+pub fn verify(public_key: Option<&Path>) -> Result<(), Error> {
+    if let Some(public_key) = public_key {
+        verify_signature(public_key)?;
+    }
+
+    let manifest: Manifest = read_json(MANIFEST_FILE)?;
+    let mut current: HashMap<String, ManifestEntry> =
+        scan()?.into_iter().map(|e| (e.path.clone(), e)).collect();
+
+    let mut problems = 0;
+    for expected in &manifest.files {
+        match current.remove(&expected.path) {
+            None => {
+                println!("Missing: {}", expected.path);
+                problems += 1;
+            }
+            Some(actual) if actual.sha256 != expected.sha256 || actual.size != expected.size => {
+                println!("Changed: {}", expected.path);
+                problems += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    let mut added: Vec<&String> = current.keys().collect();
+    added.sort();
+    for path in added {
+        println!("Not in manifest: {}", path);
+    }
+
+    if problems > 0 {
+        return Err(format!("{} files don't match the manifest", problems).into());
+    }
+    println!("All {} files match the manifest", manifest.files.len());
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive::write_json,
+    model::{read_json, MemberData, RoleData, MEMBERS_FILE, ROLES_FILE},
+    Error,
+};
+
+pub const MEMBERSHIP_FILE: &str = "membership.json";
+
+/// A member with their roles resolved to names, in order of joining
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembershipEntry {
+    pub user_id: String,
+    pub username: String,
+    #[serde(default)]
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub joined_at: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<MembershipRole>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembershipRole {
+    pub id: String,
+    pub name: String,
+}
+
+impl MembershipEntry {
+    fn display_name(&self) -> &str {
+        self.nick.as_deref().unwrap_or(&self.username)
+    }
+}
+
+/// Build `membership.json` from the exported members and roles
+pub fn export() -> Result<(), Error> {
+    let members: Vec<MemberData> = read_json(MEMBERS_FILE)?;
+    let roles: Vec<RoleData> = read_json(ROLES_FILE)?;
+    let names: HashMap<u64, &str> = roles.iter().map(|r| (r.id, r.name.as_str())).collect();
+
+    let mut entries: Vec<MembershipEntry> = members
+        .iter()
+        .map(|m| MembershipEntry {
+            user_id: m.user.id.to_string(),
+            username: m.user.name.clone(),
+            nick: m.nick.clone(),
+            joined_at: m.joined_at.clone(),
+            roles: m
+                .roles
+                .iter()
+                .map(|r| MembershipRole {
+                    id: r.0.to_string(),
+                    name: names.get(&r.0).copied().unwrap_or("unknown").to_owned(),
+                })
+                .collect(),
+        })
+        .collect();
+    // Timestamps are all written in the same format so sort as strings
+    entries.sort_by(|a, b| a.joined_at.cmp(&b.joined_at));
+    write_json(MEMBERSHIP_FILE, &entries)
+}
+
+/// Print who joined, who left and whose roles changed between the membership
+/// exported in the `previous` backup and the one in this backup
+pub fn diff(previous: &Path) -> Result<(), Error> {
+    let before: Vec<MembershipEntry> = read_json(previous.join(MEMBERSHIP_FILE))?;
+    let after: Vec<MembershipEntry> = read_json(MEMBERSHIP_FILE)?;
+    let before_ids: HashMap<&str, &MembershipEntry> =
+        before.iter().map(|m| (m.user_id.as_str(), m)).collect();
+    let after_ids: HashMap<&str, &MembershipEntry> =
+        after.iter().map(|m| (m.user_id.as_str(), m)).collect();
+
+    let joined: Vec<&MembershipEntry> = after
+        .iter()
+        .filter(|m| !before_ids.contains_key(m.user_id.as_str()))
+        .collect();
+    let left: Vec<&MembershipEntry> = before
+        .iter()
+        .filter(|m| !after_ids.contains_key(m.user_id.as_str()))
+        .collect();
+
+    println!(
+        "{} members before, {} now ({} joined, {} left)",
+        before.len(),
+        after.len(),
+        joined.len(),
+        left.len()
+    );
+    println!("\nJoined:");
+    for member in &joined {
+        println!(
+            "  {} ({}) at {}",
+            member.display_name(),
+            member.user_id,
+            member.joined_at.as_deref().unwrap_or("unknown")
+        );
+    }
+    println!("\nLeft:");
+    for member in &left {
+        println!("  {} ({})", member.display_name(), member.user_id);
+    }
+
+    println!("\nRole changes:");
+    for member in &after {
+        let old = match before_ids.get(member.user_id.as_str()) {
+            Some(old) => old,
+            None => continue,
+        };
+        // Compared by ID so renamed roles don't count as a change
+        let old_roles: BTreeMap<&str, &str> = old
+            .roles
+            .iter()
+            .map(|r| (r.id.as_str(), r.name.as_str()))
+            .collect();
+        let new_roles: BTreeMap<&str, &str> = member
+            .roles
+            .iter()
+            .map(|r| (r.id.as_str(), r.name.as_str()))
+            .collect();
+        let added: Vec<&str> = new_roles
+            .iter()
+            .filter(|(id, _)| !old_roles.contains_key(*id))
+            .map(|(_, name)| *name)
+            .collect();
+        let removed: Vec<&str> = old_roles
+            .iter()
+            .filter(|(id, _)| !new_roles.contains_key(*id))
+            .map(|(_, name)| *name)
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            continue;
+        }
+        let mut changes = Vec::new();
+        if !added.is_empty() {
+            changes.push(format!("+{}", added.join(", +")));
+        }
+        if !removed.is_empty() {
+            changes.push(format!("-{}", removed.join(", -")));
+        }
+        println!(
+            "  {} ({}): {}",
+            member.display_name(),
+            member.user_id,
+            changes.join(", ")
+        );
+    }
+    Ok(())
+}
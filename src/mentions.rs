@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    api::Api,
+    model::{
+        read_json, ChannelData, GuildFileData, MemberData, MessageData, RoleData, UserData,
+        CHANNELS_FILE, GUILD_FILE, MEMBERS_FILE, ROLES_FILE,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Mention {
+    User(u64),
+    Channel(u64),
+    Role(u64),
+}
+
+/// Parse a `<@id>`, `<@!id>`, `<#id>` or `<@&id>` token at the start of
+/// `text`, returning it and its length
+fn parse_mention(text: &str) -> Option<(Mention, usize)> {
+    let rest = text.strip_prefix('<')?;
+    let (kind, rest): (fn(u64) -> Mention, &str) = if let Some(rest) = rest.strip_prefix("@&") {
+        (Mention::Role, rest)
+    } else if let Some(rest) = rest.strip_prefix("@!") {
+        (Mention::User, rest)
+    } else if let Some(rest) = rest.strip_prefix('@') {
+        (Mention::User, rest)
+    } else if let Some(rest) = rest.strip_prefix('#') {
+        (Mention::Channel, rest)
+    } else {
+        return None;
+    };
+    let end = rest.find('>')?;
+    let id = rest[..end].parse().ok()?;
+    Some((kind(id), text.len() - rest.len() + end + 1))
+}
+
+/// Every mention in `content`, in order
+fn mentions(content: &str) -> impl Iterator<Item = Mention> + '_ {
+    content
+        .match_indices('<')
+        .filter_map(|(i, _)| parse_mention(&content[i..]).map(|(m, _)| m))
+}
+
+/// Names for the users, channels and roles mentioned in messages, from the
+/// exported metadata with the API filling in the rest
+#[derive(Debug, Default)]
+pub struct Resolver {
+    users: HashMap<u64, String>,
+    channels: HashMap<u64, String>,
+    roles: HashMap<u64, String>,
+    /// IDs already looked up, whether or not they were found
+    fetched: HashSet<Mention>,
+    roles_fetched: bool,
+}
+
+impl Resolver {
+    /// Load names from `members.json`, `channels.json` and `roles.json` where
+    /// they were exported
+    pub fn load() -> Self {
+        let mut resolver = Resolver::default();
+        if let Ok(members) = read_json::<Vec<MemberData>>(MEMBERS_FILE) {
+            for member in members {
+                resolver
+                    .users
+                    .insert(member.user.id, member.display_name().to_owned());
+            }
+        }
+        if let Ok(channels) = read_json::<Vec<ChannelData>>(CHANNELS_FILE) {
+            for channel in channels {
+                if let Some(name) = channel.name {
+                    resolver.channels.insert(channel.id, name);
+                }
+            }
+        }
+        if let Ok(roles) = read_json::<Vec<RoleData>>(ROLES_FILE) {
+            for role in roles {
+                resolver.roles.insert(role.id, role.name);
+            }
+        }
+        resolver
+    }
+
+    /// Message authors cover users who have since left the guild
+    pub fn add_authors(&mut self, messages: &[MessageData]) {
+        for message in messages {
+            self.users
+                .entry(message.author.id)
+                .or_insert_with(|| message.author.name.clone());
+        }
+    }
+
+    fn known(&self, mention: Mention) -> bool {
+        match mention {
+            Mention::User(id) => self.users.contains_key(&id),
+            Mention::Channel(id) => self.channels.contains_key(&id),
+            Mention::Role(id) => self.roles.contains_key(&id),
+        }
+    }
+
+    /// Look up anything mentioned in `messages` that isn't in the backup.
+    /// Failed lookups (deleted users, channels the token can't see) are left
+    /// as the raw token.
+    pub async fn fetch_missing(&mut self, api: &Api, messages: &[MessageData]) {
+        let missing: HashSet<Mention> = messages
+            .iter()
+            .flat_map(|m| mentions(&m.content))
+            .filter(|m| !self.known(*m) && !self.fetched.contains(m))
+            .collect();
+
+        for mention in missing {
+            if !self.fetched.insert(mention) {
+                continue;
+            }
+            match mention {
+                Mention::User(id) => {
+                    if let Ok(user) = api.get::<UserData>(&format!("/users/{}", id)).await {
+                        self.users.insert(id, user.name);
+                    }
+                }
+                Mention::Channel(id) => {
+                    if let Ok(channel) = api.get::<ChannelData>(&format!("/channels/{}", id)).await
+                    {
+                        if let Some(name) = channel.name {
+                            self.channels.insert(id, name);
+                        }
+                    }
+                }
+                // Roles can only be fetched all at once, and only for this guild
+                Mention::Role(_) if self.roles_fetched => {}
+                Mention::Role(_) => {
+                    self.roles_fetched = true;
+                    let Ok(guild) = read_json::<GuildFileData>(GUILD_FILE) else {
+                        continue;
+                    };
+                    let path = format!("/guilds/{}/roles", guild.guild.id);
+                    if let Ok(roles) = api.get::<Vec<RoleData>>(&path).await {
+                        for role in roles {
+                            self.roles.entry(role.id).or_insert(role.name);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn name(&self, mention: Mention) -> Option<String> {
+        match mention {
+            Mention::User(id) => self.users.get(&id).map(|n| format!("@{}", n)),
+            Mention::Channel(id) => self.channels.get(&id).map(|n| format!("#{}", n)),
+            Mention::Role(id) => self.roles.get(&id).map(|n| format!("@{}", n)),
+        }
+    }
+
+    /// Replace mention tokens in `content` with names
+    pub fn resolve(&self, content: &str) -> String {
+        let mut out = String::with_capacity(content.len());
+        let mut rest = content;
+        while let Some(start) = rest.find('<') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            match parse_mention(rest) {
+                Some((mention, length)) => {
+                    match self.name(mention) {
+                        Some(name) => out.push_str(&name),
+                        None => out.push_str(&rest[..length]),
+                    }
+                    rest = &rest[length..];
+                }
+                None => {
+                    out.push('<');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> Resolver {
+        let mut resolver = Resolver::default();
+        resolver.users.insert(1, "alice".to_owned());
+        resolver.channels.insert(2, "general".to_owned());
+        resolver.roles.insert(3, "mods".to_owned());
+        resolver
+    }
+
+    #[test]
+    fn parses_each_kind() {
+        assert_eq!(parse_mention("<@1> hi"), Some((Mention::User(1), 4)));
+        assert_eq!(parse_mention("<@!1>"), Some((Mention::User(1), 5)));
+        assert_eq!(parse_mention("<#2>"), Some((Mention::Channel(2), 4)));
+        assert_eq!(parse_mention("<@&3>"), Some((Mention::Role(3), 5)));
+        assert_eq!(parse_mention("<3"), None);
+        assert_eq!(parse_mention("<@abc>"), None);
+        assert_eq!(parse_mention("<@1"), None);
+    }
+
+    #[test]
+    fn finds_every_mention() {
+        let found: Vec<Mention> = mentions("<3 <@1> and <#2>, <@&3>").collect();
+        assert_eq!(
+            found,
+            vec![Mention::User(1), Mention::Channel(2), Mention::Role(3)]
+        );
+    }
+
+    #[test]
+    fn resolves_known_names() {
+        assert_eq!(
+            resolver().resolve("<@!1> see <#2> or ask <@&3>"),
+            "@alice see #general or ask @mods"
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_and_broken_tokens() {
+        assert_eq!(resolver().resolve("<@9> <3 <#2"), "<@9> <3 <#2");
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use simd_json::OwnedValue;
+use twilight_model::id::ChannelId;
+
+use crate::{
+    api::Api,
+    archive::write_json,
+    catalog::now,
+    model::{read_json, Snowflake},
+    Error,
+};
+
+pub fn meta_path(channel: ChannelId) -> String {
+    format!("{}.meta.json", channel)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OverwriteMeta {
+    pub id: Snowflake,
+    /// 0 for a role, 1 for a member
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub allow: Snowflake,
+    pub deny: Snowflake,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SlowmodeChange {
+    /// When the backup first saw this slowmode, RFC 3339 in UTC
+    pub seen_at: String,
+    /// Seconds, 0 for none
+    pub rate_limit_per_user: u64,
+}
+
+/// Everything needed to recreate a channel. Parses both Discord's channel
+/// objects and meta files written by older versions, which were twilight's
+/// channel serialized as is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelMeta {
+    pub id: Snowflake,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<Snowflake>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub nsfw: bool,
+    /// Slowmode in seconds
+    #[serde(default)]
+    pub rate_limit_per_user: Option<u64>,
+    /// Minutes before new threads are archived
+    #[serde(default)]
+    pub default_auto_archive_duration: Option<u64>,
+    #[serde(default)]
+    pub position: Option<i64>,
+    /// Category, or parent channel for threads
+    #[serde(default)]
+    pub parent_id: Option<Snowflake>,
+    #[serde(default)]
+    pub permission_overwrites: Vec<OverwriteMeta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtc_region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_quality_mode: Option<u8>,
+    /// Archive state and such for threads, as Discord returns it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_metadata: Option<OwnedValue>,
+    /// For private channels
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<OwnedValue>,
+    /// Every slowmode seen across backups of this directory, oldest first
+    #[serde(default)]
+    pub slowmode_history: Vec<SlowmodeChange>,
+}
+
+/// Only what's carried over from an earlier meta file, so one of any age
+/// parses
+#[derive(Deserialize)]
+struct History {
+    #[serde(default)]
+    slowmode_history: Vec<SlowmodeChange>,
+}
+
+/// Fetch the channel and write its meta file, keeping the slowmode history of
+/// any earlier meta file and adding to it if the slowmode changed since
+pub async fn write_channel_meta(api: &Api, channel: ChannelId) -> Result<(), Error> {
+    let path = meta_path(channel);
+    let mut meta: ChannelMeta = api.get(&format!("/channels/{}", channel)).await?;
+    if let Ok(previous) = read_json::<History>(&path) {
+        meta.slowmode_history = previous.slowmode_history;
+    }
+    let current = meta.rate_limit_per_user.unwrap_or(0);
+    if meta.slowmode_history.last().map(|c| c.rate_limit_per_user) != Some(current) {
+        meta.slowmode_history.push(SlowmodeChange {
+            seen_at: now(),
+            rate_limit_per_user: current,
+        });
+    }
+    write_json(&path, &meta)
+}
@@ -0,0 +1,228 @@
+//! Lightweight views of the files in a backup, for the commands that read
+//! backups back. Only the fields those commands need are kept and anything
+//! else is ignored, so files written by older versions still parse.
+
+use std::{fs, path::Path};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Error;
+
+pub const ROLES_FILE: &str = "roles.json";
+pub const CHANNELS_FILE: &str = "channels.json";
+pub const MEMBERS_FILE: &str = "members.json";
+pub const GUILD_FILE: &str = "guild.json";
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrNumber {
+    String(String),
+    Number(u64),
+}
+
+/// Snowflakes and permission sets are written as strings, but accept numbers
+fn snowflake<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::Number(n) => Ok(n),
+        StringOrNumber::String(s) => s.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// A snowflake in a list, with the same leniency as [`snowflake`], written
+/// back as a string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Snowflake(pub u64);
+
+impl<'de> Deserialize<'de> for Snowflake {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        snowflake(deserializer).map(Snowflake)
+    }
+}
+
+impl Serialize for Snowflake {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+fn optional_snowflake<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "snowflake")] u64);
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|w| w.0))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoleData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    pub name: String,
+    #[serde(deserialize_with = "snowflake")]
+    pub permissions: u64,
+    #[serde(default)]
+    pub color: u32,
+    #[serde(default)]
+    pub hoist: bool,
+    #[serde(default)]
+    pub mentionable: bool,
+    #[serde(default)]
+    pub position: i64,
+    #[serde(default)]
+    pub managed: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OverwriteData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    /// 0 for a role, 1 for a member
+    #[serde(rename = "type")]
+    pub kind: u8,
+    #[serde(deserialize_with = "snowflake")]
+    pub allow: u64,
+    #[serde(deserialize_with = "snowflake")]
+    pub deny: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChannelData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub position: Option<i64>,
+    #[serde(default, deserialize_with = "optional_snowflake")]
+    pub parent_id: Option<u64>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub nsfw: Option<bool>,
+    #[serde(default)]
+    pub rate_limit_per_user: Option<u64>,
+    #[serde(default)]
+    pub bitrate: Option<u64>,
+    #[serde(default)]
+    pub user_limit: Option<u64>,
+    #[serde(default)]
+    pub permission_overwrites: Vec<OverwriteData>,
+    #[serde(default, deserialize_with = "optional_snowflake")]
+    pub last_message_id: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    #[serde(rename = "username")]
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MemberData {
+    pub user: UserData,
+    #[serde(default)]
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<Snowflake>,
+    #[serde(default)]
+    pub joined_at: Option<String>,
+}
+
+impl MemberData {
+    pub fn display_name(&self) -> &str {
+        self.nick.as_deref().unwrap_or(&self.user.name)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AttachmentData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    pub filename: String,
+    pub url: String,
+}
+
+/// What a reply or forward points at
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReferenceData {
+    #[serde(default, deserialize_with = "optional_snowflake")]
+    pub channel_id: Option<u64>,
+    #[serde(default, deserialize_with = "optional_snowflake")]
+    pub message_id: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MessageData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    pub author: UserData,
+    /// RFC 3339, in UTC
+    pub timestamp: String,
+    #[serde(default)]
+    pub edited_timestamp: Option<String>,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentData>,
+    #[serde(default)]
+    pub message_reference: Option<ReferenceData>,
+    #[serde(default)]
+    pub reactions: Vec<ReactionData>,
+    #[serde(default)]
+    pub sticker_items: Vec<StickerData>,
+}
+
+/// The parts of `guild.json` needed when reading a backup
+#[derive(Deserialize, Debug, Clone)]
+pub struct GuildFileData {
+    pub guild: GuildData,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GuildData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    pub name: String,
+    #[serde(default, deserialize_with = "optional_snowflake")]
+    pub owner_id: Option<u64>,
+    #[serde(default)]
+    pub emojis: Vec<EmojiData>,
+    #[serde(default)]
+    pub stickers: Vec<StickerData>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmojiData {
+    #[serde(default, deserialize_with = "optional_snowflake")]
+    pub id: Option<u64>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub animated: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StickerData {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReactionData {
+    #[serde(default)]
+    pub count: u64,
+    pub emoji: EmojiData,
+}
+
+pub const CHANNEL_TYPE_TEXT: u8 = 0;
+pub const CHANNEL_TYPE_CATEGORY: u8 = 4;
+pub const CHANNEL_TYPE_VOICE: u8 = 2;
+pub const CHANNEL_TYPE_STAGE: u8 = 13;
+
+pub fn read_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, Error> {
+    let mut data = fs::read(path)?;
+    Ok(simd_json::from_slice(&mut data)?)
+}
@@ -0,0 +1,316 @@
+use std::{
+    convert::Infallible,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::Error;
+
+pub const CREDENTIALS_FILE: &str = "credentials.json";
+pub const DEFAULT_REDIRECT_PORT: u16 = 53682;
+pub const DEFAULT_SCOPES: &[&str] = &["identify", "guilds"];
+/// Refresh tokens this close to expiring rather than have them expire
+/// part way through a backup
+const EXPIRY_MARGIN: i64 = 5 * 60;
+
+/// What `auth` stores, readable only by the current user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Credentials {
+    pub client_id: u64,
+    /// Kept so tokens can be refreshed without asking again
+    pub client_secret: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Space separated, as granted rather than as requested
+    pub scope: String,
+    /// Unix timestamp, in seconds
+    pub expires_at: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: i64,
+    scope: String,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Outside the backup directory, so credentials and keys are never uploaded
+/// with a backup
+pub fn config_dir() -> Result<PathBuf, Error> {
+    #[cfg(windows)]
+    let base = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    let base = base.ok_or("Unable to find a config directory to store credentials in")?;
+    Ok(base.join("guild-backup"))
+}
+
+pub fn credentials_path() -> Result<PathBuf, Error> {
+    Ok(config_dir()?.join(CREDENTIALS_FILE))
+}
+
+/// Open `path` for writing with only its owner able to read it, for
+/// credentials and keys. With `create_new` an existing file is an error
+/// instead of being replaced.
+pub fn open_private(path: &Path, create_new: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if create_new {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode only applies to new files
+        if !create_new && path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)
+}
+
+fn load() -> Result<Credentials, Error> {
+    let path = credentials_path()?;
+    let mut data = fs::read(&path)
+        .map_err(|_| format!("No credentials in {}, run auth first", path.display()))?;
+    Ok(simd_json::from_slice(&mut data)?)
+}
+
+fn save(credentials: &Credentials) -> Result<PathBuf, Error> {
+    let path = credentials_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = open_private(&path, false)?;
+    file.write_all(&simd_json::to_vec(credentials)?)?;
+    file.sync_all()?;
+    Ok(path)
+}
+
+fn unix_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Exchange a code or refresh token at the token endpoint
+async fn token_request(
+    api_base: &str,
+    client_id: u64,
+    client_secret: &str,
+    grant: &[(&str, &str)],
+) -> Result<TokenResponse, Error> {
+    let client_id = client_id.to_string();
+    let mut form = vec![
+        ("client_id", client_id.as_str()),
+        ("client_secret", client_secret),
+    ];
+    form.extend_from_slice(grant);
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/oauth2/token", api_base))
+        .form(&form)
+        .send()
+        .await?;
+    let status = response.status();
+    let mut body = response.bytes().await?.to_vec();
+    if !status.is_success() {
+        return Err(match simd_json::from_slice::<TokenError>(&mut body) {
+            Ok(e) => format!(
+                "Token request failed: {} {}",
+                e.error,
+                e.error_description.unwrap_or_default()
+            )
+            .into(),
+            Err(_) => format!("Token request failed with {}", status).into(),
+        });
+    }
+    Ok(simd_json::from_slice(&mut body)?)
+}
+
+fn credentials(client_id: u64, client_secret: &str, token: TokenResponse) -> Credentials {
+    Credentials {
+        client_id,
+        client_secret: client_secret.to_owned(),
+        access_token: token.access_token,
+        refresh_token: token.refresh_token,
+        scope: token.scope,
+        expires_at: unix_now() + token.expires_in,
+    }
+}
+
+/// Wait for the browser to be sent back to the redirect URI, returning the
+/// authorization code
+async fn wait_for_code(port: u16, state: &str) -> Result<String, Error> {
+    let (sender, mut receiver) = mpsc::channel::<Result<String, String>>(1);
+    let expected_state = state.to_owned();
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], port)))?.serve(
+        make_service_fn(move |_| {
+            let sender = sender.clone();
+            let expected_state = expected_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sender = sender.clone();
+                    let result = callback(&request, &expected_state);
+                    async move {
+                        let page = match &result {
+                            Some(Ok(_)) => "Authorized, you can close this tab.",
+                            Some(Err(_)) => "Authorization failed, see the terminal.",
+                            None => "Waiting for authorization.",
+                        };
+                        if let Some(result) = result {
+                            let _ = sender.send(result).await;
+                        }
+                        Ok::<_, Infallible>(Response::new(Body::from(page)))
+                    }
+                }))
+            }
+        }),
+    );
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(server.with_graceful_shutdown(async {
+        let _ = stopped.await;
+    }));
+
+    let result = receiver.recv().await;
+    let _ = stop.send(());
+    let _ = server.await;
+    match result {
+        Some(Ok(code)) => Ok(code),
+        Some(Err(e)) => Err(e.into()),
+        None => Err("Redirect listener stopped".into()),
+    }
+}
+
+/// `None` for requests that aren't the redirect, like the browser asking for
+/// a favicon, or are for an older attempt
+fn callback(request: &Request<Body>, expected_state: &str) -> Option<Result<String, String>> {
+    if request.uri().path() != "/callback" {
+        return None;
+    }
+    let url = Url::parse(&format!("http://localhost{}", request.uri())).ok()?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    if query("state").as_deref() != Some(expected_state) {
+        eprintln!("Ignoring a redirect for a different authorization");
+        return None;
+    }
+    if let Some(error) = query("error") {
+        return Some(Err(format!(
+            "Authorization was denied: {} {}",
+            error,
+            query("error_description").unwrap_or_default()
+        )));
+    }
+    Some(query("code").ok_or_else(|| "Redirect had no code".to_owned()))
+}
+
+/// Authorize the application in the browser and store the tokens. The
+/// redirect URI `http://127.0.0.1:<port>/callback` must be registered for
+/// the application.
+pub async fn authorize(
+    api_base: &str,
+    client_id: u64,
+    client_secret: &str,
+    scopes: &[String],
+    port: u16,
+) -> Result<(), Error> {
+    let state: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let redirect_uri = format!("http://127.0.0.1:{}/callback", port);
+    let url = Url::parse_with_params(
+        &format!("{}/oauth2/authorize", api_base),
+        &[
+            ("response_type", "code"),
+            ("client_id", &client_id.to_string()),
+            ("scope", &scopes.join(" ")),
+            ("redirect_uri", &redirect_uri),
+            ("state", &state),
+            ("prompt", "consent"),
+        ],
+    )?;
+    println!("Open this URL to authorize guild-backup:\n\n{}\n", url);
+
+    let code = wait_for_code(port, &state).await?;
+    let token = token_request(
+        api_base,
+        client_id,
+        client_secret,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+        ],
+    )
+    .await?;
+    let credentials = credentials(client_id, client_secret, token);
+    let path = save(&credentials)?;
+    println!(
+        "Authorized with scopes \"{}\", credentials saved to {}",
+        credentials.scope,
+        path.display()
+    );
+    Ok(())
+}
+
+async fn refresh_credentials(api_base: &str, current: &Credentials) -> Result<Credentials, Error> {
+    let token = token_request(
+        api_base,
+        current.client_id,
+        &current.client_secret,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &current.refresh_token),
+        ],
+    )
+    .await?;
+    let credentials = credentials(current.client_id, &current.client_secret, token);
+    save(&credentials)?;
+    Ok(credentials)
+}
+
+/// Refresh the stored tokens now
+pub async fn refresh(api_base: &str) -> Result<(), Error> {
+    let credentials = refresh_credentials(api_base, &load()?).await?;
+    println!(
+        "Refreshed, the new token expires in {} hours",
+        (credentials.expires_at - unix_now()) / 3600
+    );
+    Ok(())
+}
+
+/// The stored access token, refreshed first if it's about to expire
+pub async fn access_token(api_base: &str) -> Result<String, Error> {
+    let mut credentials = load()?;
+    if credentials.expires_at - EXPIRY_MARGIN <= unix_now() {
+        credentials = refresh_credentials(api_base, &credentials).await?;
+    }
+    Ok(credentials.access_token)
+}
@@ -0,0 +1,373 @@
+use std::{collections::HashMap, fmt::Write as _, fs, path::Path};
+
+use twilight_model::guild::Permissions;
+
+use crate::{
+    model::{
+        read_json, ChannelData, GuildFileData, MemberData, RoleData, CHANNELS_FILE,
+        CHANNEL_TYPE_STAGE, CHANNEL_TYPE_VOICE, GUILD_FILE, MEMBERS_FILE, ROLES_FILE,
+    },
+    Error,
+};
+
+const OVERWRITE_ROLE: u8 = 0;
+const OVERWRITE_MEMBER: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Access {
+    view: bool,
+    post: bool,
+}
+
+impl Access {
+    /// Posting in a voice channel means connecting to it
+    fn new(kind: u8, permissions: Permissions) -> Self {
+        let view = permissions.contains(Permissions::VIEW_CHANNEL);
+        let post = if kind == CHANNEL_TYPE_VOICE || kind == CHANNEL_TYPE_STAGE {
+            Permissions::CONNECT
+        } else {
+            Permissions::SEND_MESSAGES
+        };
+        Access {
+            view,
+            post: view && permissions.contains(post),
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match (self.view, self.post) {
+            (true, true) => "view, post",
+            (true, false) => "view",
+            _ => "no access",
+        }
+    }
+}
+
+fn apply(permissions: Permissions, allow: u64, deny: u64) -> Permissions {
+    (permissions - Permissions::from_bits_truncate(deny)) | Permissions::from_bits_truncate(allow)
+}
+
+struct Guild {
+    id: u64,
+    owner: Option<u64>,
+    everyone: Permissions,
+    roles: HashMap<u64, RoleData>,
+}
+
+impl Guild {
+    /// Discord's permission calculation for someone with `roles` (not
+    /// including @everyone) in a channel
+    fn permissions(
+        &self,
+        channel: &ChannelData,
+        roles: &[u64],
+        member: Option<u64>,
+    ) -> Permissions {
+        if member.is_some() && member == self.owner {
+            return Permissions::all();
+        }
+
+        let mut permissions = self.everyone;
+        for role in roles {
+            if let Some(role) = self.roles.get(role) {
+                permissions |= Permissions::from_bits_truncate(role.permissions);
+            }
+        }
+        if permissions.contains(Permissions::ADMINISTRATOR) {
+            return Permissions::all();
+        }
+
+        let overwrites = &channel.permission_overwrites;
+        if let Some(o) = overwrites
+            .iter()
+            .find(|o| o.kind == OVERWRITE_ROLE && o.id == self.id)
+        {
+            permissions = apply(permissions, o.allow, o.deny);
+        }
+
+        let (mut allow, mut deny) = (0, 0);
+        for o in overwrites
+            .iter()
+            .filter(|o| o.kind == OVERWRITE_ROLE && roles.contains(&o.id))
+        {
+            allow |= o.allow;
+            deny |= o.deny;
+        }
+        permissions = apply(permissions, allow, deny);
+
+        if let Some(member) = member {
+            if let Some(o) = overwrites
+                .iter()
+                .find(|o| o.kind == OVERWRITE_MEMBER && o.id == member)
+            {
+                permissions = apply(permissions, o.allow, o.deny);
+            }
+        }
+        permissions
+    }
+}
+
+fn render_channel(
+    out: &mut String,
+    guild: &Guild,
+    roles: &[RoleData],
+    members: &[MemberData],
+    channel: &ChannelData,
+) {
+    let name = channel.name.as_deref().unwrap_or("unnamed");
+    let _ = writeln!(out, "## #{} ({})\n", name, channel.id);
+
+    let mut viewers = Vec::new();
+    let mut posters = Vec::new();
+    for role in roles {
+        let held: Vec<u64> = if role.id == guild.id {
+            Vec::new()
+        } else {
+            vec![role.id]
+        };
+        let access = Access::new(channel.kind, guild.permissions(channel, &held, None));
+        if access.view {
+            viewers.push(role.name.as_str());
+        }
+        if access.post {
+            posters.push(role.name.as_str());
+        }
+    }
+    let _ = writeln!(out, "- Roles that can view: {}", list(&viewers));
+    let _ = writeln!(out, "- Roles that can post: {}", list(&posters));
+
+    if !members.is_empty() {
+        let (mut view, mut post) = (0, 0);
+        for member in members {
+            let held: Vec<u64> = member.roles.iter().map(|r| r.0).collect();
+            let access = Access::new(
+                channel.kind,
+                guild.permissions(channel, &held, Some(member.user.id)),
+            );
+            view += access.view as usize;
+            post += access.post as usize;
+        }
+        let _ = writeln!(
+            out,
+            "- Members: {}/{} can view, {}/{} can post",
+            view,
+            members.len(),
+            post,
+            members.len()
+        );
+    }
+
+    let overrides: Vec<String> = channel
+        .permission_overwrites
+        .iter()
+        .filter(|o| o.kind == OVERWRITE_MEMBER)
+        .map(|o| {
+            let member = members.iter().find(|m| m.user.id == o.id);
+            let held: Vec<u64> = member
+                .map(|m| m.roles.iter().map(|r| r.0).collect())
+                .unwrap_or_default();
+            let access = Access::new(channel.kind, guild.permissions(channel, &held, Some(o.id)));
+            let name = member
+                .map(|m| m.display_name().to_owned())
+                .unwrap_or_else(|| o.id.to_string());
+            format!("{} ({})", name, access.describe())
+        })
+        .collect();
+    if !overrides.is_empty() {
+        let _ = writeln!(out, "- Member overrides: {}", overrides.join(", "));
+    }
+    out.push('\n');
+}
+
+fn list(names: &[&str]) -> String {
+    if names.is_empty() {
+        "none".to_owned()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// Work out who can see and post in each channel from the roles, channels and
+/// (if exported) members in the backup, written as Markdown.
+pub fn report(output: Option<&Path>) -> Result<(), Error> {
+    let guild_file: GuildFileData = read_json(GUILD_FILE)?;
+    let mut roles: Vec<RoleData> = read_json(ROLES_FILE)?;
+    let mut channels: Vec<ChannelData> = read_json(CHANNELS_FILE)?;
+    let members: Vec<MemberData> = read_json(MEMBERS_FILE).unwrap_or_default();
+
+    roles.sort_by_key(|r| r.position);
+    channels.sort_by_key(|c| (c.parent_id.unwrap_or(c.id), c.position.unwrap_or(0)));
+
+    let everyone = roles
+        .iter()
+        .find(|r| r.id == guild_file.guild.id)
+        .map(|r| Permissions::from_bits_truncate(r.permissions))
+        .unwrap_or_else(Permissions::empty);
+    let guild = Guild {
+        id: guild_file.guild.id,
+        owner: guild_file.guild.owner_id,
+        everyone,
+        roles: roles.iter().map(|r| (r.id, r.clone())).collect(),
+    };
+
+    let mut out = format!("# Permissions report for {}\n\n", guild_file.guild.name);
+    for channel in &channels {
+        render_channel(&mut out, &guild, &roles, &members, channel);
+    }
+
+    match output {
+        Some(path) => fs::write(path, out)?,
+        None => print!("{}", out),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::CHANNEL_TYPE_TEXT;
+
+    const GUILD: u64 = 1;
+    const MODERATOR: u64 = 2;
+    const ADMIN: u64 = 3;
+    const OWNER: u64 = 10;
+    const MEMBER: u64 = 11;
+
+    const HIDDEN: Access = Access {
+        view: false,
+        post: false,
+    };
+    const READ_ONLY: Access = Access {
+        view: true,
+        post: false,
+    };
+    const FULL: Access = Access {
+        view: true,
+        post: true,
+    };
+
+    fn parse<T: serde::de::DeserializeOwned>(json: String) -> T {
+        simd_json::from_slice(&mut json.into_bytes()).unwrap()
+    }
+
+    fn role(id: u64, permissions: Permissions) -> RoleData {
+        parse(format!(
+            "{{\"id\":\"{}\",\"name\":\"role {}\",\"permissions\":\"{}\"}}",
+            id,
+            id,
+            permissions.bits()
+        ))
+    }
+
+    /// A channel with overwrites of `(id, kind, allow, deny)`
+    fn channel(kind: u8, overwrites: &[(u64, u8, Permissions, Permissions)]) -> ChannelData {
+        let overwrites: Vec<String> = overwrites
+            .iter()
+            .map(|(id, kind, allow, deny)| {
+                format!(
+                    "{{\"id\":\"{}\",\"type\":{},\"allow\":\"{}\",\"deny\":\"{}\"}}",
+                    id,
+                    kind,
+                    allow.bits(),
+                    deny.bits()
+                )
+            })
+            .collect();
+        parse(format!(
+            "{{\"id\":\"100\",\"type\":{},\"permission_overwrites\":[{}]}}",
+            kind,
+            overwrites.join(",")
+        ))
+    }
+
+    fn guild() -> Guild {
+        let roles = [
+            role(MODERATOR, Permissions::MANAGE_MESSAGES),
+            role(ADMIN, Permissions::ADMINISTRATOR),
+        ];
+        Guild {
+            id: GUILD,
+            owner: Some(OWNER),
+            everyone: Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES | Permissions::CONNECT,
+            roles: roles.into_iter().map(|r| (r.id, r)).collect(),
+        }
+    }
+
+    fn access(channel: &ChannelData, roles: &[u64], member: Option<u64>) -> Access {
+        Access::new(channel.kind, guild().permissions(channel, roles, member))
+    }
+
+    #[test]
+    fn role_overwrite_opens_a_hidden_channel() {
+        let channel = channel(
+            CHANNEL_TYPE_TEXT,
+            &[
+                (
+                    GUILD,
+                    OVERWRITE_ROLE,
+                    Permissions::empty(),
+                    Permissions::VIEW_CHANNEL,
+                ),
+                (
+                    MODERATOR,
+                    OVERWRITE_ROLE,
+                    Permissions::VIEW_CHANNEL,
+                    Permissions::empty(),
+                ),
+            ],
+        );
+        assert_eq!(access(&channel, &[], None), HIDDEN);
+        assert_eq!(access(&channel, &[MODERATOR], None), FULL);
+    }
+
+    #[test]
+    fn member_overwrite_applies_last() {
+        let channel = channel(
+            CHANNEL_TYPE_TEXT,
+            &[
+                (
+                    MODERATOR,
+                    OVERWRITE_ROLE,
+                    Permissions::SEND_MESSAGES,
+                    Permissions::empty(),
+                ),
+                (
+                    MEMBER,
+                    OVERWRITE_MEMBER,
+                    Permissions::empty(),
+                    Permissions::SEND_MESSAGES,
+                ),
+            ],
+        );
+        assert_eq!(access(&channel, &[MODERATOR], Some(MEMBER)), READ_ONLY);
+    }
+
+    #[test]
+    fn administrators_and_the_owner_ignore_overwrites() {
+        let channel = channel(
+            CHANNEL_TYPE_TEXT,
+            &[(
+                GUILD,
+                OVERWRITE_ROLE,
+                Permissions::empty(),
+                Permissions::VIEW_CHANNEL,
+            )],
+        );
+        assert_eq!(access(&channel, &[ADMIN], None), FULL);
+        assert_eq!(access(&channel, &[], Some(OWNER)), FULL);
+    }
+
+    #[test]
+    fn posting_in_voice_is_connecting() {
+        let channel = channel(
+            CHANNEL_TYPE_VOICE,
+            &[(
+                GUILD,
+                OVERWRITE_ROLE,
+                Permissions::SEND_MESSAGES,
+                Permissions::CONNECT,
+            )],
+        );
+        assert_eq!(access(&channel, &[], None), READ_ONLY);
+    }
+}
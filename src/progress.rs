@@ -0,0 +1,376 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use twilight_model::{channel::Channel, id::ChannelId, user::User};
+
+use crate::{
+    estimate::Estimate,
+    ratelimit::{BucketInfo, RouteStats},
+};
+
+const LOG_LINES: usize = 200;
+const ERROR_LINES: usize = 50;
+const PAUSE_POLL: Duration = Duration::from_millis(200);
+
+/// Live view of a running backup, shared between the scraper and whatever is
+/// displaying it. When not interactive every log line goes straight to stderr.
+pub struct Progress {
+    interactive: bool,
+    inner: Mutex<ProgressState>,
+    pub control: Control,
+    pub bandwidth: Bandwidth,
+}
+
+#[derive(Debug)]
+pub struct ProgressState {
+    pub started: Instant,
+    pub queue: Vec<QueuedChannel>,
+    pub current: Option<ChannelProgress>,
+    pub channels_done: usize,
+    pub messages: u64,
+    pub bytes: u64,
+    pub attachments: u64,
+    pub attachment_bytes: u64,
+    /// Bytes actually downloaded, which is less than `attachment_bytes` when
+    /// partial downloads are resumed
+    pub transferred: u64,
+    /// Expected size of each channel, from the pre-scan
+    pub estimates: HashMap<ChannelId, Estimate>,
+    /// Requests made through twilight by route, with their rate limiting
+    pub rate_limits: HashMap<&'static str, RouteStats>,
+    pub log: VecDeque<String>,
+    pub errors: VecDeque<String>,
+    pub finished: bool,
+}
+
+#[derive(Debug)]
+pub struct QueuedChannel {
+    pub id: ChannelId,
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub struct ChannelProgress {
+    pub id: ChannelId,
+    pub name: String,
+    pub messages: u64,
+    pub bytes: u64,
+    pub started: Instant,
+}
+
+pub fn channel_name(channel: &Channel) -> String {
+    match channel {
+        Channel::Guild(c) => c.name().to_owned(),
+        Channel::Group(c) => c.name.clone().unwrap_or_else(|| recipients(&c.recipients)),
+        Channel::Private(c) => recipients(&c.recipients),
+    }
+}
+
+fn recipients(users: &[User]) -> String {
+    users
+        .iter()
+        .map(|u| u.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Progress {
+    pub fn new(interactive: bool) -> Self {
+        Progress {
+            interactive,
+            inner: Mutex::new(ProgressState {
+                started: Instant::now(),
+                queue: Vec::new(),
+                current: None,
+                channels_done: 0,
+                messages: 0,
+                bytes: 0,
+                attachments: 0,
+                attachment_bytes: 0,
+                transferred: 0,
+                estimates: HashMap::new(),
+                rate_limits: HashMap::new(),
+                log: VecDeque::new(),
+                errors: VecDeque::new(),
+                finished: false,
+            }),
+            control: Control::default(),
+            bandwidth: Bandwidth::default(),
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, ProgressState> {
+        self.inner.lock().expect("Progress lock poisoned")
+    }
+
+    pub fn info(&self, line: String) {
+        if !self.interactive {
+            eprintln!("{}", line);
+        }
+        push_bounded(&mut self.state().log, line, LOG_LINES);
+    }
+
+    pub fn error(&self, line: String) {
+        if !self.interactive {
+            eprintln!("{}", line);
+        }
+        let mut state = self.state();
+        push_bounded(&mut state.log, line.clone(), LOG_LINES);
+        push_bounded(&mut state.errors, line, ERROR_LINES);
+    }
+
+    pub fn set_queue(&self, channels: &[Channel]) {
+        self.state().queue = channels
+            .iter()
+            .rev()
+            .map(|c| QueuedChannel {
+                id: c.id(),
+                name: channel_name(c),
+            })
+            .collect();
+    }
+
+    pub fn channel_started(&self, channel: &Channel) {
+        self.state().current = Some(ChannelProgress {
+            id: channel.id(),
+            name: channel_name(channel),
+            messages: 0,
+            bytes: 0,
+            started: Instant::now(),
+        });
+    }
+
+    pub fn set_estimate(&self, channel: ChannelId, estimate: Estimate) {
+        self.state().estimates.insert(channel, estimate);
+    }
+
+    pub fn chunk_written(&self, messages: u64, bytes: u64) {
+        let mut state = self.state();
+        state.messages += messages;
+        state.bytes += bytes;
+        if let Some(current) = state.current.as_mut() {
+            current.messages += messages;
+            current.bytes += bytes;
+        }
+    }
+
+    pub fn attachment_downloaded(&self, bytes: u64) {
+        let mut state = self.state();
+        state.attachments += 1;
+        state.attachment_bytes += bytes;
+    }
+
+    pub fn channel_finished(&self) {
+        let mut state = self.state();
+        state.current = None;
+        state.channels_done += 1;
+    }
+
+    /// Count bytes downloaded, waiting first if that would go over the
+    /// bandwidth cap
+    pub async fn transfer(&self, bytes: u64) {
+        self.state().transferred += bytes;
+        self.bandwidth.throttle(bytes).await;
+    }
+
+    pub fn request_made(
+        &self,
+        route: &'static str,
+        elapsed: Duration,
+        waited: Option<Duration>,
+        bucket: Option<BucketInfo>,
+    ) {
+        let mut state = self.state();
+        let stats = state.rate_limits.entry(route).or_default();
+        stats.requests += 1;
+        stats.busy += elapsed;
+        if let Some(waited) = waited {
+            stats.limited += 1;
+            stats.waited += waited;
+        }
+        if bucket.is_some() {
+            stats.bucket = bucket;
+        }
+    }
+
+    pub fn finish(&self) {
+        self.state().finished = true;
+    }
+}
+
+impl ProgressState {
+    /// Messages per second since the run started
+    pub fn throughput(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.messages as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Messages expected in the channels still to do, including the rest of
+    /// the current one. `None` without a pre-scan.
+    pub fn remaining_messages(&self) -> Option<u64> {
+        if self.estimates.is_empty() {
+            return None;
+        }
+        let expected = |id: &ChannelId| self.estimates.get(id).map_or(0, |e| e.messages);
+        let queued: u64 = self.queue.iter().map(|c| expected(&c.id)).sum();
+        let current = self
+            .current
+            .as_ref()
+            .map_or(0, |c| expected(&c.id).saturating_sub(c.messages));
+        Some(queued + current)
+    }
+
+    /// Time left at the current rate, or at the rate the rate limits allow
+    /// if that's slower, when there are estimates to go by
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        let current = match self.remaining_messages() {
+            Some(remaining) if throughput > 0.0 => {
+                Some(Duration::from_secs_f64(remaining as f64 / throughput))
+            }
+            _ => None,
+        };
+        current.max(self.rate_limited_eta())
+    }
+
+    /// One line description of the run so far, for status reports
+    pub fn summary(&self) -> String {
+        let elapsed = self.started.elapsed();
+        format!(
+            "{} channels, {} messages ({}), {} attachments ({}, {} downloaded at {}/s) in {}, {} waiting on rate limits",
+            self.channels_done,
+            self.messages,
+            format_bytes(self.bytes),
+            self.attachments,
+            format_bytes(self.attachment_bytes),
+            format_bytes(self.transferred),
+            format_bytes((self.transferred as f64 / elapsed.as_secs_f64().max(1.0)) as u64),
+            format_duration(elapsed),
+            format_duration(self.rate_limit_wait())
+        )
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+fn push_bounded(lines: &mut VecDeque<String>, line: String, max: usize) {
+    if lines.len() >= max {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+/// Requests from the user to the running scraper, checked between chunks.
+#[derive(Debug, Default)]
+pub struct Control {
+    paused: AtomicBool,
+    skip: AtomicBool,
+    stop: AtomicBool,
+}
+
+impl Control {
+    pub fn toggle_pause(&self) {
+        self.paused.fetch_xor(true, Ordering::SeqCst);
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn request_skip(&self) {
+        self.skip.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether a skip was requested, clearing the request
+    pub fn take_skip(&self) -> bool {
+        self.skip.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn request_stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn should_stop(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait_while_paused(&self) {
+        while self.is_paused() && !self.should_stop() {
+            tokio::time::sleep(PAUSE_POLL).await;
+        }
+    }
+}
+
+/// Cap on download speed shared by every download, as a token bucket holding
+/// up to a second's worth of bytes so short bursts aren't slowed
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    /// Bytes per second, 0 for no limit
+    limit: AtomicU64,
+    /// Bytes that can be downloaded without waiting (negative when over the
+    /// cap) and when that was worked out
+    bucket: Mutex<Option<(f64, Instant)>>,
+}
+
+impl Bandwidth {
+    pub fn set_limit(&self, bytes_per_second: Option<u64>) {
+        self.limit
+            .store(bytes_per_second.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    async fn throttle(&self, bytes: u64) {
+        let limit = self.limit.load(Ordering::SeqCst) as f64;
+        if limit == 0.0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("Bandwidth lock poisoned");
+            let now = Instant::now();
+            let (tokens, last) = bucket.unwrap_or((limit, now));
+            let tokens =
+                (tokens + now.duration_since(last).as_secs_f64() * limit).min(limit) - bytes as f64;
+            *bucket = Some((tokens, now));
+            if tokens < 0.0 {
+                Duration::from_secs_f64(-tokens / limit)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
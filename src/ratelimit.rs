@@ -0,0 +1,177 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use twilight_http::Client;
+use twilight_http_ratelimiting::request::Path;
+
+use crate::progress::{format_duration, Progress, ProgressState};
+
+/// Messages are fetched this many to a request
+const MESSAGES_PER_REQUEST: u64 = 100;
+/// Route of message history requests, the bulk of any backup
+pub const MESSAGES_ROUTE: &str = "GET /channels/{id}/messages";
+pub const REACTIONS_ROUTE: &str = "GET /channels/{id}/messages/{id}/reactions/{emoji}";
+
+/// A bucket as twilight's rate limiter last saw it
+#[derive(Debug, Clone, Copy)]
+pub struct BucketInfo {
+    pub limit: u64,
+    pub remaining: u64,
+    /// How long the bucket takes to refill
+    pub reset_after: Duration,
+}
+
+/// Requests made to one route and the time they spent held back by rate
+/// limits
+#[derive(Debug, Default, Clone)]
+pub struct RouteStats {
+    pub requests: u64,
+    /// Requests that had to wait for their bucket or the global limit
+    pub limited: u64,
+    pub waited: Duration,
+    /// Time spent in requests, waiting included
+    pub busy: Duration,
+    pub bucket: Option<BucketInfo>,
+}
+
+impl RouteStats {
+    /// Time a request takes on average, or the time the bucket allows per
+    /// request if that's longer, so what the route can sustain
+    fn time_per_request(&self) -> Option<Duration> {
+        if self.requests == 0 {
+            return None;
+        }
+        let average = self.busy / self.requests as u32;
+        let allowed = self
+            .bucket
+            .filter(|b| b.limit > 0)
+            .map_or(Duration::ZERO, |b| b.reset_after / b.limit as u32);
+        Some(average.max(allowed))
+    }
+}
+
+async fn bucket(client: &Client, path: &Path) -> Option<(BucketInfo, Option<Duration>)> {
+    let bucket = client.ratelimiter()?.bucket(path).await.ok()??;
+    Some((
+        BucketInfo {
+            limit: bucket.limit(),
+            remaining: bucket.remaining(),
+            reset_after: bucket.reset_after(),
+        },
+        bucket.time_remaining(),
+    ))
+}
+
+async fn globally_locked(client: &Client) -> bool {
+    match client.ratelimiter() {
+        Some(ratelimiter) => ratelimiter.globally_locked().await.unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Make a request through twilight, recording against `route` how long it
+/// had to wait for twilight's rate limiter. A request is counted as waiting
+/// when its bucket was empty (for up to the bucket's reset) or the global
+/// limit was hit (for all of it).
+pub async fn timed<T>(
+    client: &Client,
+    progress: &Progress,
+    route: &'static str,
+    path: Path,
+    request: impl Future<Output = T>,
+) -> T {
+    let global = globally_locked(client).await;
+    let before = bucket(client, &path).await;
+    let started = Instant::now();
+    let result = request.await;
+    let elapsed = started.elapsed();
+    let after = bucket(client, &path).await;
+
+    let waited = match before {
+        _ if global => Some(elapsed),
+        Some((bucket, reset)) if bucket.remaining == 0 => {
+            Some(elapsed.min(reset.unwrap_or(bucket.reset_after)))
+        }
+        _ => None,
+    };
+    progress.request_made(
+        route,
+        elapsed,
+        waited,
+        after.or(before).map(|(bucket, _)| bucket),
+    );
+    result
+}
+
+impl ProgressState {
+    pub fn rate_limit_wait(&self) -> Duration {
+        self.rate_limits.values().map(|r| r.waited).sum()
+    }
+
+    /// Time left if the remaining messages are fetched no faster than the
+    /// message route's buckets allow. `None` without a pre-scan or before any
+    /// messages were fetched.
+    pub fn rate_limited_eta(&self) -> Option<Duration> {
+        let remaining = self.remaining_messages()?;
+        let per_request = self.rate_limits.get(MESSAGES_ROUTE)?.time_per_request()?;
+        let requests = remaining.div_ceil(MESSAGES_PER_REQUEST);
+        Some(per_request * requests as u32)
+    }
+
+    /// One line on rate limiting so far, for the interface and status
+    pub fn rate_limit_summary(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64().max(1.0);
+        let waited = self.rate_limit_wait();
+        let mut line = format!(
+            "waited {} on rate limits ({:.0}% of the run)",
+            format_duration(waited),
+            waited.as_secs_f64() / elapsed * 100.0
+        );
+        if let Some((route, stats)) = self.hottest_routes().first() {
+            line.push_str(&format!(
+                ", hottest {} ({} requests, {} limited)",
+                route, stats.requests, stats.limited
+            ));
+            if let Some(bucket) = stats.bucket {
+                line.push_str(&format!(
+                    ", bucket {}/{} per {:.1}s",
+                    bucket.remaining,
+                    bucket.limit,
+                    bucket.reset_after.as_secs_f64()
+                ));
+            }
+        }
+        line
+    }
+
+    /// Routes by the time spent waiting on them, then by requests
+    pub fn hottest_routes(&self) -> Vec<(&'static str, &RouteStats)> {
+        let mut routes: Vec<(&'static str, &RouteStats)> =
+            self.rate_limits.iter().map(|(r, s)| (*r, s)).collect();
+        routes.sort_by(|a, b| {
+            b.1.waited
+                .cmp(&a.1.waited)
+                .then(b.1.requests.cmp(&a.1.requests))
+        });
+        routes
+    }
+
+    /// A line per route, for the end of run report
+    pub fn rate_limit_report(&self) -> Vec<String> {
+        self.hottest_routes()
+            .into_iter()
+            .map(|(route, stats)| {
+                format!(
+                    "  {}: {} requests, {} rate limited, waited {}, {} in requests",
+                    route,
+                    stats.requests,
+                    stats.limited,
+                    format_duration(stats.waited),
+                    format_duration(stats.busy)
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,146 @@
+use std::{collections::HashMap, path::Path, process::Stdio};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use serde::Deserialize;
+use tokio::{fs::File, io, process::Command};
+
+use crate::{
+    remote::{RemoteObject, RemoteStore},
+    Error,
+};
+
+/// Store backed by the `rclone` command, so any remote configured in rclone
+/// (S3, Backblaze, Dropbox, SFTP...) can be used
+pub struct Rclone {
+    /// An rclone path such as `s3:bucket/backups`
+    root: String,
+}
+
+#[derive(Deserialize)]
+struct ListEntry {
+    #[serde(rename = "Path")]
+    path: String,
+    #[serde(rename = "Size")]
+    size: i64,
+    #[serde(rename = "IsDir")]
+    is_dir: bool,
+    /// Missing for remotes that don't support SHA-256
+    #[serde(rename = "Hashes", default)]
+    hashes: HashMap<String, String>,
+}
+
+impl Rclone {
+    pub fn new(root: String) -> Self {
+        Rclone {
+            root: root.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    fn remote_path(&self, path: &str) -> String {
+        if path.is_empty() {
+            self.root.clone()
+        } else {
+            format!("{}/{}", self.root, path)
+        }
+    }
+}
+
+fn failed(command: &str, status: std::process::ExitStatus, stderr: &[u8]) -> Error {
+    format!(
+        "rclone {} exited with {}: {}",
+        command,
+        status,
+        String::from_utf8_lossy(stderr).trim()
+    )
+    .into()
+}
+
+/// Run rclone with no input, returning its output
+async fn run(args: &[&str]) -> Result<Vec<u8>, Error> {
+    let output = Command::new("rclone")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(failed(args[0], output.status, &output.stderr));
+    }
+    Ok(output.stdout)
+}
+
+impl RemoteStore for Rclone {
+    fn put<'a>(&'a self, path: &'a str, local: &'a Path) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            // `copyto` handles large files and retries better than `rcat`
+            run(&["copyto", &local.to_string_lossy(), &self.remote_path(path)])
+                .await
+                .map(|_| ())
+        }
+        .boxed()
+    }
+
+    fn get<'a>(&'a self, path: &'a str, local: &'a Path) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut child = Command::new("rclone")
+                .args(["cat", &self.remote_path(path)])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            let mut stdout = child.stdout.take().expect("rclone stdout is piped");
+            io::copy(&mut stdout, &mut File::create(local).await?).await?;
+
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                return Err(failed("cat", output.status, &output.stderr));
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<RemoteObject>, Error>> {
+        async move {
+            let root = self.remote_path(prefix);
+            let args = [
+                "lsjson",
+                "--recursive",
+                "--files-only",
+                "--hash",
+                "--hash-type",
+                "SHA256",
+                &root,
+            ];
+            let mut output = match run(&args).await {
+                Ok(output) => output,
+                // Nothing has been uploaded yet
+                Err(e) if e.to_string().contains("directory not found") => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+            let entries: Vec<ListEntry> = simd_json::from_slice(&mut output)?;
+            Ok(entries
+                .into_iter()
+                .filter(|e| !e.is_dir)
+                .map(|mut e| RemoteObject {
+                    sha256: e.hashes.remove("sha256"),
+                    path: if prefix.is_empty() {
+                        e.path
+                    } else {
+                        format!("{}/{}", prefix.trim_end_matches('/'), e.path)
+                    },
+                    size: e.size.max(0) as u64,
+                })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn delete<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            run(&["deletefile", &self.remote_path(path)])
+                .await
+                .map(|_| ())
+        }
+        .boxed()
+    }
+}
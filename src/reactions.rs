@@ -0,0 +1,209 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use twilight_http::{request::channel::reaction::RequestReactionType, Client};
+use twilight_http_ratelimiting::request::Path;
+use twilight_model::{
+    channel::{message::Message, ReactionType},
+    id::{ChannelId, EmojiId, MessageId, UserId},
+};
+
+use crate::{
+    archive::write_atomic,
+    progress::Progress,
+    ratelimit::{self, REACTIONS_ROUTE},
+    state::{save_active_state, State},
+    Error,
+};
+
+const REACTION_PAGE_SIZE: u64 = 100;
+
+/// Everyone who reacted with an emoji, one line per reaction on a message
+pub fn reactions_path(channel: ChannelId) -> String {
+    format!("{}.reactions.json", channel)
+}
+
+#[derive(Deserialize)]
+struct ReactionLine {
+    message_id: MessageId,
+}
+
+/// Remove the reactions of the messages `replaced` selects from the channel's
+/// reactions file, before they're fetched again
+pub fn drop_reactions(
+    channel: ChannelId,
+    replaced: impl Fn(MessageId) -> bool,
+) -> Result<(), Error> {
+    let path = reactions_path(channel);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut kept = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let reaction: ReactionLine = simd_json::from_slice(&mut line.clone().into_bytes())?;
+        if !replaced(reaction.message_id) {
+            kept.extend_from_slice(line.as_bytes());
+            kept.push(b'\n');
+        }
+    }
+    write_atomic(std::path::Path::new(&path), &kept)?;
+    Ok(())
+}
+
+/// A reaction whose users are still being fetched. Kept in the state with
+/// the users found so far so large reactions resume where they stopped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingReaction {
+    pub message_id: MessageId,
+    /// Set for custom emoji
+    #[serde(default)]
+    pub emoji_id: Option<EmojiId>,
+    /// The emoji itself, or the name of a custom one
+    #[serde(default)]
+    pub emoji_name: Option<String>,
+    /// Last user fetched, the cursor for the next page
+    #[serde(default)]
+    pub after: Option<UserId>,
+    #[serde(default)]
+    pub users: Vec<UserId>,
+}
+
+#[derive(Serialize)]
+struct ReactionUsers<'a> {
+    message_id: MessageId,
+    emoji_id: Option<EmojiId>,
+    emoji_name: Option<&'a str>,
+    users: &'a [UserId],
+    /// False if fetching the users failed partway
+    complete: bool,
+}
+
+impl PendingReaction {
+    fn request_type(&self) -> RequestReactionType<'_> {
+        match self.emoji_id {
+            Some(id) => RequestReactionType::Custom {
+                id,
+                name: self.emoji_name.as_deref(),
+            },
+            None => RequestReactionType::Unicode {
+                name: self.emoji_name.as_deref().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// The reactions on some messages, to be saved in the state before calling
+/// [`fetch`]
+pub fn plan(messages: &[Message]) -> Vec<PendingReaction> {
+    let mut pending = Vec::new();
+    for message in messages {
+        for reaction in message.reactions.iter().filter(|r| r.count > 0) {
+            let (emoji_id, emoji_name) = match &reaction.emoji {
+                ReactionType::Custom { id, name, .. } => (Some(*id), name.clone()),
+                ReactionType::Unicode { name } => (None, Some(name.clone())),
+            };
+            pending.push(PendingReaction {
+                message_id: message.id,
+                emoji_id,
+                emoji_name,
+                after: None,
+                users: Vec::new(),
+            });
+        }
+    }
+    pending
+}
+
+async fn fetch_page(
+    client: &Client,
+    channel: ChannelId,
+    reaction: &PendingReaction,
+    progress: &Progress,
+) -> Result<Vec<UserId>, Error> {
+    let emoji = reaction.request_type();
+    let mut req = client
+        .reactions(channel, reaction.message_id, &emoji)
+        .limit(REACTION_PAGE_SIZE)?;
+    if let Some(after) = reaction.after {
+        req = req.after(after);
+    }
+    let path = Path::ChannelsIdMessagesIdReactions(channel.get());
+    let users = ratelimit::timed(client, progress, REACTIONS_ROUTE, path, req.exec())
+        .await?
+        .models()
+        .await?;
+    Ok(users.into_iter().map(|u| u.id).collect())
+}
+
+fn write_reaction(
+    channel: ChannelId,
+    reaction: &PendingReaction,
+    complete: bool,
+) -> Result<(), Error> {
+    let mut line = simd_json::to_vec(&ReactionUsers {
+        message_id: reaction.message_id,
+        emoji_id: reaction.emoji_id,
+        emoji_name: reaction.emoji_name.as_deref(),
+        users: &reaction.users,
+        complete,
+    })?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(reactions_path(channel))?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Fetch every user for the reactions pending in the channel's state, a page
+/// at a time, saving the cursor after each page. Returns early if asked to
+/// stop.
+pub async fn fetch(
+    client: &Client,
+    channel: ChannelId,
+    state: &mut State,
+    progress: &Progress,
+) -> Result<(), Error> {
+    while !state.channel(channel).pending_reactions.is_empty() {
+        if progress.control.should_stop() {
+            return Ok(());
+        }
+
+        let reaction = &mut state.channel(channel).pending_reactions[0];
+        let complete = match fetch_page(client, channel, reaction, progress).await {
+            Ok(users) => {
+                let done = (users.len() as u64) < REACTION_PAGE_SIZE;
+                reaction.after = users.last().copied().or(reaction.after);
+                reaction.users.extend(users);
+                if !done {
+                    save_active_state(state)?;
+                    continue;
+                }
+                true
+            }
+            Err(e) => {
+                progress.error(format!(
+                    "Error fetching reactions on {} {:?}",
+                    reaction.message_id, e
+                ));
+                false
+            }
+        };
+
+        let pending = &mut state.channel(channel).pending_reactions;
+        write_reaction(channel, &pending[0], complete)?;
+        pending.remove(0);
+        save_active_state(state)?;
+    }
+    Ok(())
+}
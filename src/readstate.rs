@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use twilight_gateway::{shard::Events, Event, EventTypeFlags, Intents, Shard};
+
+use crate::{
+    archive::write_json,
+    model::{read_json, Snowflake},
+    progress::Progress,
+    Error,
+};
+
+/// Where the backing up user had read up to in each channel
+pub const READ_STATES_FILE: &str = "read_states.json";
+const GATEWAY_URL: &str = "wss://gateway.discord.gg";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadState {
+    /// The channel
+    pub id: Snowflake,
+    #[serde(default)]
+    pub last_message_id: Option<Snowflake>,
+    #[serde(default)]
+    pub mention_count: u64,
+}
+
+/// Read states are a plain list in older versions of the gateway and
+/// wrapped in an object in newer ones
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReadStates {
+    List(Vec<ReadState>),
+    Versioned { entries: Vec<ReadState> },
+}
+
+#[derive(Deserialize)]
+struct ReadyData {
+    #[serde(default)]
+    read_state: Option<ReadStates>,
+}
+
+#[derive(Deserialize)]
+struct Frame {
+    #[serde(default)]
+    t: Option<String>,
+    #[serde(default)]
+    d: Option<ReadyData>,
+}
+
+async fn ready(events: &mut Events) -> Result<Vec<ReadState>, Error> {
+    while let Some(event) = events.next().await {
+        let Event::ShardPayload(payload) = event else {
+            continue;
+        };
+        let mut bytes = payload.bytes;
+        let frame: Frame = match simd_json::from_slice(&mut bytes) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        if frame.t.as_deref() != Some("READY") {
+            continue;
+        }
+        return Ok(match frame.d.and_then(|d| d.read_state) {
+            Some(ReadStates::List(entries)) | Some(ReadStates::Versioned { entries }) => entries,
+            None => Vec::new(),
+        });
+    }
+    Err("Gateway closed before it was ready".into())
+}
+
+/// Save the user's read states, which only user accounts have and which are
+/// only sent when connecting to the gateway
+pub async fn export(token: &str, progress: &Progress) -> Result<(), Error> {
+    let (shard, mut events) = Shard::builder(token.to_owned(), Intents::empty())
+        .event_types(EventTypeFlags::SHARD_PAYLOAD)
+        .gateway_url(Some(GATEWAY_URL.to_owned()))
+        .build();
+    shard.start().await?;
+    let result = ready(&mut events).await;
+    shard.shutdown();
+
+    let states = result?;
+    write_json(READ_STATES_FILE, &states)?;
+    progress.info(format!(
+        "Saved read states for {} channels in {}",
+        states.len(),
+        READ_STATES_FILE
+    ));
+    Ok(())
+}
+
+/// The last message read in each channel, if read states were saved
+pub fn last_read() -> HashMap<u64, u64> {
+    read_json::<Vec<ReadState>>(READ_STATES_FILE)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|state| Some((state.id.0, state.last_message_id?.0)))
+        .collect()
+}
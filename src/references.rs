@@ -0,0 +1,116 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use simd_json::OwnedValue;
+use twilight_http::Client;
+use twilight_model::id::{ChannelId, MessageId};
+
+use crate::{
+    archive::{message_files, MessageReader},
+    model::ReferenceData,
+    progress::Progress,
+    scrape::ArchivedMessage,
+    Error,
+};
+
+/// Messages referenced from the backup but not in it, one per line
+pub const REFERENCES_FILE: &str = "references.json";
+
+#[derive(Deserialize)]
+struct ReferencingMessage {
+    id: MessageId,
+    #[serde(default)]
+    message_reference: Option<ReferenceData>,
+    /// Included by Discord for replies
+    #[serde(default)]
+    referenced_message: Option<OwnedValue>,
+}
+
+/// The messages stored in `references.json`
+pub fn read<T: DeserializeOwned>() -> Result<Vec<T>, Error> {
+    let file = match File::open(REFERENCES_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut messages = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let mut line = line?.into_bytes();
+        if line.is_empty() {
+            continue;
+        }
+        messages.push(simd_json::from_slice(&mut line)?);
+    }
+    Ok(messages)
+}
+
+/// Store every message replied to or forwarded from the backup that isn't
+/// in it, such as ones from other channels or from before a partial
+/// backup's range. Replies carry the message they reply to, anything else is
+/// fetched. Messages that can't be fetched (usually deleted) are reported and
+/// skipped.
+pub async fn backup(client: &Client, progress: &Progress) -> Result<(), Error> {
+    let mut archived: HashSet<MessageId> = read::<ArchivedMessage>()?
+        .into_iter()
+        .map(|m| m.id)
+        .collect();
+    let mut references = Vec::new();
+    for (_, path) in message_files()? {
+        for message in MessageReader::<ReferencingMessage>::open(&path)? {
+            let message = message?;
+            archived.insert(message.id);
+            if let Some(reference) = message.message_reference {
+                references.push((reference, message.referenced_message));
+            }
+        }
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(REFERENCES_FILE)?;
+    let mut stored = 0;
+    for (reference, embedded) in references {
+        if progress.control.should_stop() {
+            return Ok(());
+        }
+        let (channel, message) = match (
+            reference.channel_id.and_then(ChannelId::new),
+            reference.message_id.and_then(MessageId::new),
+        ) {
+            (Some(channel), Some(message)) => (channel, message),
+            _ => continue,
+        };
+        if archived.contains(&message) {
+            continue;
+        }
+
+        let mut line = match embedded {
+            Some(embedded) => simd_json::to_vec(&embedded)?,
+            None => match client.message(channel, message).exec().await {
+                Ok(response) => simd_json::to_vec(&response.model().await?)?,
+                Err(e) => {
+                    progress.error(format!(
+                        "Error fetching referenced message {}/{} {:?}",
+                        channel, message, e
+                    ));
+                    archived.insert(message);
+                    continue;
+                }
+            },
+        };
+        line.push(b'\n');
+        file.write_all(&line)?;
+        archived.insert(message);
+        stored += 1;
+    }
+    progress.info(format!(
+        "Stored {} referenced messages in {}",
+        stored, REFERENCES_FILE
+    ));
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path},
+    time::SystemTime,
+};
+
+use futures_util::future::BoxFuture;
+
+use crate::{
+    archive::backup_files,
+    attachments::{hash_file, ATTACHMENT_DIR},
+    manifest::{self, Manifest, ManifestEntry, MANIFEST_FILE},
+    model::read_json,
+    progress::Progress,
+    state::STATE_FILE,
+    Error,
+};
+
+/// A file stored remotely
+#[derive(Debug, Clone)]
+pub struct RemoteObject {
+    /// Relative to the root of the store, with `/` separators
+    pub path: String,
+    pub size: u64,
+    /// Hex encoded, for stores that keep one
+    pub sha256: Option<String>,
+}
+
+/// Somewhere backups can be copied to. Files are streamed to and from disk
+/// rather than held in memory, since attachments can be large.
+pub trait RemoteStore: Send + Sync {
+    /// Upload the file at `local` to `path`, replacing anything there
+    fn put<'a>(&'a self, path: &'a str, local: &'a Path) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Download `path` into the file at `local`
+    fn get<'a>(&'a self, path: &'a str, local: &'a Path) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Every file under `prefix` (everything when empty)
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<RemoteObject>, Error>>;
+
+    fn delete<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Hashes from `manifest.json`, for files that haven't changed since it was
+/// written, so they don't need hashing again
+struct KnownHashes {
+    written: Option<SystemTime>,
+    files: HashMap<String, ManifestEntry>,
+}
+
+impl KnownHashes {
+    fn load() -> Self {
+        match read_json::<Manifest>(MANIFEST_FILE) {
+            Ok(manifest) => KnownHashes {
+                written: fs::metadata(MANIFEST_FILE).and_then(|m| m.modified()).ok(),
+                files: manifest
+                    .files
+                    .into_iter()
+                    .map(|e| (e.path.clone(), e))
+                    .collect(),
+            },
+            Err(_) => KnownHashes {
+                written: None,
+                files: HashMap::new(),
+            },
+        }
+    }
+
+    fn hash(&self, name: &str, path: &Path) -> Result<String, Error> {
+        let metadata = fs::metadata(path)?;
+        let known = self.files.get(name).filter(|e| {
+            e.size == metadata.len()
+                && matches!(
+                    (metadata.modified(), self.written),
+                    (Ok(modified), Some(written)) if modified < written
+                )
+        });
+        match known {
+            Some(entry) => Ok(entry.sha256.clone()),
+            None => hash_file(path),
+        }
+    }
+}
+
+/// Whether the stored copy of a file is the same as the local one. Some
+/// files are rewritten at the same size (a re-sorted messages file, a
+/// regenerated manifest), so hashes are compared when the store keeps them.
+/// Without one only attachments, which are never rewritten, are trusted by
+/// their size.
+fn unchanged(
+    name: &str,
+    path: &Path,
+    object: &RemoteObject,
+    hashes: &KnownHashes,
+) -> Result<bool, Error> {
+    if object.size != fs::metadata(path)?.len() {
+        return Ok(false);
+    }
+    Ok(match &object.sha256 {
+        Some(remote) => hashes.hash(name, path)?.eq_ignore_ascii_case(remote),
+        None => name.starts_with(&format!("{}/", ATTACHMENT_DIR)),
+    })
+}
+
+/// Copy the backup to a store. Files already there unchanged are skipped, so
+/// repeated uploads only send what's new or rewritten.
+pub async fn upload(store: &dyn RemoteStore, progress: &Progress) -> Result<(), Error> {
+    progress.info("Listing remote files...".to_owned());
+    let remote: HashMap<String, RemoteObject> = store
+        .list("")
+        .await?
+        .into_iter()
+        .map(|o| (o.path.clone(), o))
+        .collect();
+    let hashes = KnownHashes::load();
+
+    let mut uploaded = 0;
+    for (name, path) in backup_files()? {
+        if let Some(object) = remote.get(&name) {
+            if unchanged(&name, &path, object, &hashes)? {
+                continue;
+            }
+        }
+        progress.info(format!("Uploading {}...", name));
+        if let Err(e) = store.put(&name, &path).await {
+            progress.error(format!("Error uploading {} {:?}", name, e));
+            continue;
+        }
+        uploaded += 1;
+    }
+
+    // Not part of the backup, but copied so an interrupted backup can be
+    // continued from the store, and removed once the backup is finished
+    if Path::new(STATE_FILE).exists() {
+        store.put(STATE_FILE, Path::new(STATE_FILE)).await?;
+    } else if remote.contains_key(STATE_FILE) {
+        store.delete(STATE_FILE).await?;
+    }
+    progress.info(format!("Uploaded {} files", uploaded));
+    Ok(())
+}
+
+/// Whether a file is already here as the store has it. Sizes alone miss
+/// files rewritten at the same size, so its hash is compared against the
+/// store's, or failing that the one in the backup's manifest. Without
+/// either only attachments, which are never rewritten, are trusted by their
+/// size.
+fn restored(object: &RemoteObject, manifest: &HashMap<String, String>) -> Result<bool, Error> {
+    let local = Path::new(&object.path);
+    if fs::metadata(local).map(|m| m.len()).ok() != Some(object.size) {
+        return Ok(false);
+    }
+    let expected = object
+        .sha256
+        .as_ref()
+        .or_else(|| manifest.get(&object.path));
+    Ok(match expected {
+        Some(expected) => hash_file(local)?.eq_ignore_ascii_case(expected),
+        None => object.path.starts_with(&format!("{}/", ATTACHMENT_DIR)),
+    })
+}
+
+/// Copy a backup from a store into this directory, skipping files already
+/// here unchanged, so an interrupted copy can be run again. An unfinished
+/// backup brings its state along and continues where it stopped, after
+/// checking which of its files are complete.
+pub async fn download(store: &dyn RemoteStore, progress: &Progress) -> Result<(), Error> {
+    progress.info("Listing remote files...".to_owned());
+    let mut objects = store.list("").await?;
+    for object in &objects {
+        let local = Path::new(&object.path);
+        // Only ever into this directory, whatever the store lists
+        if object.path.is_empty()
+            || !local
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "The store lists {:?}, which is outside the backup, refusing to restore it",
+                object.path
+            )
+            .into());
+        }
+    }
+    // The manifest first, for the hashes of everything else
+    objects.sort_by_key(|o| o.path != MANIFEST_FILE);
+
+    let mut manifest = HashMap::new();
+    let mut downloaded = 0;
+    for object in objects {
+        let local = Path::new(&object.path);
+        // The manifest is always fetched, a local one may be from an older
+        // backup
+        if object.path != MANIFEST_FILE && restored(&object, &manifest)? {
+            continue;
+        }
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        progress.info(format!("Downloading {}...", object.path));
+        store.get(&object.path, local).await?;
+        let size = fs::metadata(local)?.len();
+        if size != object.size {
+            return Err(format!(
+                "{} downloaded as {} bytes, expected {}",
+                object.path, size, object.size
+            )
+            .into());
+        }
+        if object.path == MANIFEST_FILE {
+            manifest = read_json::<Manifest>(MANIFEST_FILE)?
+                .files
+                .into_iter()
+                .map(|e| (e.path, e.sha256))
+                .collect();
+        }
+        downloaded += 1;
+    }
+    progress.info(format!("Downloaded {} files", downloaded));
+
+    if Path::new(STATE_FILE).exists() {
+        progress.info("The backup is unfinished, run it again to continue".to_owned());
+    } else if Path::new(MANIFEST_FILE).exists() {
+        if let Err(e) = manifest::verify(None) {
+            progress.error(format!("{} (the manifest may be older than the backup)", e));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    path::Path,
+};
+
+use twilight_model::id::MessageId;
+
+use crate::{
+    archive::{open_length, MessageReader},
+    attachments::{read_index, IndexEntry, Status},
+    progress::Progress,
+    scrape::ArchivedMessage,
+    state::{ChannelStatus, State},
+    Error,
+};
+
+/// Paths are recorded with `/` so a backup made on Windows resumes elsewhere
+fn portable_path(entry: &mut IndexEntry) {
+    if let Some(path) = &mut entry.path {
+        *path = path.replace('\\', "/");
+    }
+}
+
+/// What an open messages file holds once repaired
+#[derive(Default)]
+struct Archived {
+    count: u64,
+    oldest: Option<MessageId>,
+    newest: Option<MessageId>,
+}
+
+/// Bring an open messages file back to its last complete message and return
+/// what's in it
+fn repair_open(file_name: &str, progress: &Progress) -> Result<Archived, Error> {
+    let path = Path::new(file_name);
+    let size = fs::metadata(path)?.len();
+    // Possibly finished but not yet marked complete, so drop any closing
+    // bracket, or stopped just after a separator, so drop that
+    let closed = open_length(path)?;
+    let damaged = |e: Error| -> Error {
+        format!(
+            "{} is damaged, copy it again or delete it {:?}",
+            file_name, e
+        )
+        .into()
+    };
+
+    let mut archived = Archived::default();
+    let mut reader = MessageReader::<ArchivedMessage>::open(path)?;
+    for message in reader.by_ref() {
+        match message {
+            Ok(message) => {
+                archived.count += 1;
+                archived.oldest = Some(archived.oldest.map_or(message.id, |o| o.min(message.id)));
+                archived.newest = archived.newest.max(Some(message.id));
+            }
+            Err(_) if reader.cut_off() => break,
+            Err(e) => return Err(damaged(e)),
+        }
+    }
+    let length = if reader.cut_off() {
+        reader.complete_length()
+    } else {
+        closed
+    };
+
+    if length < size {
+        OpenOptions::new().write(true).open(path)?.set_len(length)?;
+        if length < closed {
+            progress.info(format!(
+                "Dropped {} bytes of cut off messages from {}",
+                closed - length,
+                file_name
+            ));
+        }
+    }
+    Ok(archived)
+}
+
+/// Check what the state says against the files actually in the backup, which
+/// may have been copied from another machine or restored from remote storage
+/// part way through, so that continuing picks up from what's really there:
+/// - open messages files are cut back to their last complete message, and
+///   each channel continues from the messages in its file
+/// - finished channels whose messages file is missing are fetched again, and
+///   damaged ones are reported before anything is changed
+/// - downloaded attachments that are missing or incomplete are queued to be
+///   downloaded again
+pub fn revalidate(state: &mut State, progress: &Progress) -> Result<(), Error> {
+    let mut refetch = Vec::new();
+    for channel in state.channels.iter_mut() {
+        channel
+            .pending_attachments
+            .iter_mut()
+            .for_each(portable_path);
+        let file_name = format!("{}.messages.json", channel.id);
+        if !Path::new(&file_name).exists() {
+            if channel.status != ChannelStatus::InProgress {
+                progress.info(format!("{} is missing, fetching it again", file_name));
+                refetch.push(channel.id);
+            }
+            // In progress channels without a file are started again anyway
+            continue;
+        }
+        if channel.status != ChannelStatus::InProgress {
+            for message in MessageReader::<ArchivedMessage>::open(Path::new(&file_name))? {
+                message.map_err(|e| {
+                    format!(
+                        "{} is damaged, copy it again or delete it {:?}",
+                        file_name, e
+                    )
+                })?;
+            }
+            continue;
+        }
+
+        let archived = repair_open(&file_name, progress)?;
+        // Forward channels are fetched oldest first, others newest first
+        let cursor = if channel.forward {
+            archived.newest
+        } else {
+            archived.oldest
+        };
+        if cursor != channel.last_message {
+            progress.info(format!(
+                "Continuing {} from {:?} as found in its messages file",
+                channel.id, cursor
+            ));
+            channel.last_message = cursor;
+        }
+        if channel.limit.is_some() {
+            channel.fetched = archived.count;
+        }
+    }
+    state.channels.retain(|c| !refetch.contains(&c.id));
+
+    // The latest entry for each attachment says what happened to it
+    let mut latest: HashMap<_, IndexEntry> = HashMap::new();
+    for entry in read_index()? {
+        latest.insert(entry.attachment_id, entry);
+    }
+    let mut missing = 0;
+    for mut entry in latest.into_values() {
+        if entry.status != Status::Downloaded || entry.is_present() {
+            continue;
+        }
+        portable_path(&mut entry);
+        missing += 1;
+        if state.get(entry.channel_id).is_some() {
+            let channel = state.channel(entry.channel_id);
+            if !channel
+                .pending_attachments
+                .iter()
+                .any(|e| e.attachment_id == entry.attachment_id)
+            {
+                channel.pending_attachments.push(entry);
+            }
+        }
+    }
+    if missing > 0 {
+        progress.info(format!(
+            "{} downloaded attachments are missing, they'll be downloaded again \
+             (or with fetch-attachments for channels already finished)",
+            missing
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,588 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use simd_json::{OwnedValue, StaticNode};
+
+use crate::{
+    archive::{backup_files, MessageReader},
+    attachments::INDEX_FILE,
+    catalog::CATALOG_FILE,
+    guild::{
+        BOOSTERS_FILE, COMMANDS_FILE, INTEGRATIONS_FILE, ONBOARDING_FILE, SOUNDBOARD_FILE,
+        TEMPLATES_FILE, WELCOME_SCREEN_FILE,
+    },
+    ledger::ERRORS_FILE,
+    manifest::MANIFEST_FILE,
+    membership::MEMBERSHIP_FILE,
+    model::{CHANNELS_FILE, GUILD_FILE, MEMBERS_FILE, ROLES_FILE},
+    readstate::READ_STATES_FILE,
+    references::REFERENCES_FILE,
+    snapshot::SNAPSHOT_INDEX,
+    snapshots::SNAPSHOTS_FILE,
+    state::STATE_FILE,
+    tags::TAGS_FILE,
+    template::TEMPLATE_FILE,
+    Error,
+};
+
+/// The schemas in `schemas/`, which other tools can use to read backups.
+/// They refer to each other by these names.
+const SCHEMAS: &[(&str, &str)] = &[
+    ("common.json", include_str!("../schemas/common.json")),
+    ("messages.json", include_str!("../schemas/messages.json")),
+    (
+        "references.json",
+        include_str!("../schemas/references.json"),
+    ),
+    ("meta.json", include_str!("../schemas/meta.json")),
+    ("reactions.json", include_str!("../schemas/reactions.json")),
+    (
+        "attachment-index.json",
+        include_str!("../schemas/attachment-index.json"),
+    ),
+    ("errors.json", include_str!("../schemas/errors.json")),
+    ("tags.json", include_str!("../schemas/tags.json")),
+    ("snapshots.json", include_str!("../schemas/snapshots.json")),
+    ("catalog.json", include_str!("../schemas/catalog.json")),
+    (
+        "read_states.json",
+        include_str!("../schemas/read_states.json"),
+    ),
+    (
+        "membership.json",
+        include_str!("../schemas/membership.json"),
+    ),
+    ("manifest.json", include_str!("../schemas/manifest.json")),
+    ("snapshot.json", include_str!("../schemas/snapshot.json")),
+    ("template.json", include_str!("../schemas/template.json")),
+    ("guild.json", include_str!("../schemas/guild.json")),
+    ("channels.json", include_str!("../schemas/channels.json")),
+    ("roles.json", include_str!("../schemas/roles.json")),
+    ("members.json", include_str!("../schemas/members.json")),
+    (
+        "integrations.json",
+        include_str!("../schemas/integrations.json"),
+    ),
+    (
+        "welcome_screen.json",
+        include_str!("../schemas/welcome_screen.json"),
+    ),
+    (
+        "onboarding.json",
+        include_str!("../schemas/onboarding.json"),
+    ),
+    ("templates.json", include_str!("../schemas/templates.json")),
+    ("commands.json", include_str!("../schemas/commands.json")),
+    (
+        "soundboard.json",
+        include_str!("../schemas/soundboard.json"),
+    ),
+    ("state.json", include_str!("../schemas/state.json")),
+];
+
+/// Problems listed for each file before the rest are only counted
+const MAX_PROBLEMS_SHOWN: usize = 20;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    Document,
+    /// One JSON document per line
+    Lines,
+    /// A messages file, which is missing its closing bracket while its
+    /// channel is being backed up
+    Messages,
+}
+
+/// The schema a file in the backup should match, by its path. Other files,
+/// such as attachments, aren't checked.
+fn schema_for(name: &str) -> Option<(&'static str, Layout)> {
+    use Layout::*;
+    Some(match name {
+        INDEX_FILE => ("attachment-index.json", Lines),
+        _ if name.contains('/') => return None,
+        _ if name.ends_with(".messages.json") => ("messages.json", Messages),
+        _ if name.ends_with(".meta.json") => ("meta.json", Document),
+        _ if name.ends_with(".reactions.json") => ("reactions.json", Lines),
+        ERRORS_FILE => ("errors.json", Lines),
+        REFERENCES_FILE => ("references.json", Lines),
+        TAGS_FILE => ("tags.json", Lines),
+        SNAPSHOTS_FILE => ("snapshots.json", Lines),
+        CATALOG_FILE => ("catalog.json", Document),
+        READ_STATES_FILE => ("read_states.json", Document),
+        MEMBERSHIP_FILE => ("membership.json", Document),
+        MANIFEST_FILE => ("manifest.json", Document),
+        SNAPSHOT_INDEX => ("snapshot.json", Document),
+        TEMPLATE_FILE => ("template.json", Document),
+        GUILD_FILE => ("guild.json", Document),
+        CHANNELS_FILE => ("channels.json", Document),
+        ROLES_FILE => ("roles.json", Document),
+        MEMBERS_FILE | BOOSTERS_FILE => ("members.json", Document),
+        INTEGRATIONS_FILE => ("integrations.json", Document),
+        WELCOME_SCREEN_FILE => ("welcome_screen.json", Document),
+        ONBOARDING_FILE => ("onboarding.json", Document),
+        TEMPLATES_FILE => ("templates.json", Document),
+        COMMANDS_FILE => ("commands.json", Document),
+        SOUNDBOARD_FILE => ("soundboard.json", Document),
+        STATE_FILE => ("state.json", Document),
+        _ => return None,
+    })
+}
+
+/// Something in a file that doesn't match its schema
+struct Problem {
+    /// For files with a document per line, starting at 1
+    line: Option<usize>,
+    /// JSON pointer to the value, empty for the whole document
+    pointer: String,
+    message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if !self.pointer.is_empty() {
+            write!(f, "{}: ", self.pointer)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+fn get<'a>(value: &'a OwnedValue, key: &str) -> Option<&'a OwnedValue> {
+    match value {
+        OwnedValue::Object(object) => object.get(key),
+        _ => None,
+    }
+}
+
+fn type_name(value: &OwnedValue) -> &'static str {
+    match value {
+        OwnedValue::Static(StaticNode::Null) => "null",
+        OwnedValue::Static(StaticNode::Bool(_)) => "boolean",
+        OwnedValue::Static(StaticNode::I64(_) | StaticNode::U64(_)) => "integer",
+        OwnedValue::Static(StaticNode::F64(n)) if n.fract() == 0.0 => "integer",
+        OwnedValue::Static(StaticNode::F64(_)) => "number",
+        OwnedValue::String(_) => "string",
+        OwnedValue::Array(_) => "array",
+        OwnedValue::Object(_) => "object",
+    }
+}
+
+fn number(value: &OwnedValue) -> Option<f64> {
+    match value {
+        OwnedValue::Static(StaticNode::I64(n)) => Some(*n as f64),
+        OwnedValue::Static(StaticNode::U64(n)) => Some(*n as f64),
+        OwnedValue::Static(StaticNode::F64(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+fn show(value: &OwnedValue) -> String {
+    simd_json::to_string(value).unwrap_or_default()
+}
+
+fn report(problems: &mut Vec<Problem>, pointer: &str, message: String) {
+    problems.push(Problem {
+        line: None,
+        pointer: pointer.to_owned(),
+        message,
+    });
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// The formats the schemas use, the rest are ignored like most validators do
+fn check_format(format: &str, value: &str) -> Result<(), String> {
+    match format {
+        "snowflake" if value.parse::<u64>().is_err() => {
+            Err(format!("\"{}\" isn't a snowflake", value))
+        }
+        "date-time" if chrono::DateTime::parse_from_rfc3339(value).is_err() => {
+            Err(format!("\"{}\" isn't an RFC 3339 timestamp", value))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks documents against the schemas. Supports the parts of JSON Schema
+/// the schemas use: `$ref`, `type`, `const`, `enum`, `format`, `minimum`,
+/// `required`, `properties`, `items`, `allOf` and `anyOf`.
+struct Validator {
+    schemas: HashMap<&'static str, OwnedValue>,
+}
+
+impl Validator {
+    fn new() -> Result<Self, Error> {
+        let mut schemas = HashMap::new();
+        for (name, text) in SCHEMAS {
+            let mut text = text.as_bytes().to_vec();
+            let schema: OwnedValue = simd_json::from_slice(&mut text)
+                .map_err(|e| format!("Schema {} is invalid {:?}", name, e))?;
+            schemas.insert(*name, schema);
+        }
+        Ok(Validator { schemas })
+    }
+
+    /// The schema a `$ref` in `base` points at, with the file it's in
+    fn resolve(
+        &self,
+        base: &'static str,
+        reference: &str,
+    ) -> Result<(&'static str, &OwnedValue), String> {
+        let (file, fragment) = reference.split_once('#').unwrap_or((reference, ""));
+        let (file, mut schema) = if file.is_empty() {
+            (base, &self.schemas[base])
+        } else {
+            self.schemas
+                .get_key_value(file)
+                .map(|(name, schema)| (*name, schema))
+                .ok_or_else(|| format!("unknown schema {}", file))?
+        };
+        for key in fragment.split('/').filter(|k| !k.is_empty()) {
+            schema = get(schema, key).ok_or_else(|| format!("unknown schema {}", reference))?;
+        }
+        Ok((file, schema))
+    }
+
+    fn check(
+        &self,
+        base: &'static str,
+        schema: &OwnedValue,
+        value: &OwnedValue,
+        pointer: &str,
+        problems: &mut Vec<Problem>,
+    ) {
+        if let Some(OwnedValue::String(reference)) = get(schema, "$ref") {
+            match self.resolve(base, reference) {
+                Ok((file, schema)) => self.check(file, schema, value, pointer, problems),
+                Err(e) => report(problems, pointer, e),
+            }
+            return;
+        }
+
+        let found = type_name(value);
+        let expected: Vec<&str> = match get(schema, "type") {
+            Some(OwnedValue::String(t)) => vec![t.as_str()],
+            Some(OwnedValue::Array(types)) => types
+                .iter()
+                .filter_map(|t| match t {
+                    OwnedValue::String(t) => Some(t.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if !expected.is_empty()
+            && !expected
+                .iter()
+                .any(|t| *t == found || (*t == "number" && found == "integer"))
+        {
+            report(
+                problems,
+                pointer,
+                format!("expected {}, found {}", expected.join(" or "), found),
+            );
+            return;
+        }
+
+        if let Some(constant) = get(schema, "const") {
+            if constant != value {
+                report(
+                    problems,
+                    pointer,
+                    format!("expected {}, found {}", show(constant), show(value)),
+                );
+            }
+        }
+        if let Some(OwnedValue::Array(options)) = get(schema, "enum") {
+            if !options.contains(value) {
+                let options: Vec<String> = options.iter().map(show).collect();
+                report(
+                    problems,
+                    pointer,
+                    format!(
+                        "expected one of {}, found {}",
+                        options.join(", "),
+                        show(value)
+                    ),
+                );
+            }
+        }
+        if let (Some(OwnedValue::String(format)), OwnedValue::String(s)) =
+            (get(schema, "format"), value)
+        {
+            if let Err(e) = check_format(format, s) {
+                report(problems, pointer, e);
+            }
+        }
+        if let (Some(minimum), Some(n)) = (get(schema, "minimum").and_then(number), number(value)) {
+            if n < minimum {
+                report(problems, pointer, format!("{} is less than {}", n, minimum));
+            }
+        }
+
+        if let OwnedValue::Object(object) = value {
+            if let Some(OwnedValue::Array(required)) = get(schema, "required") {
+                for key in required {
+                    if let OwnedValue::String(key) = key {
+                        if !object.contains_key(key.as_str()) {
+                            report(problems, pointer, format!("missing field \"{}\"", key));
+                        }
+                    }
+                }
+            }
+            if let Some(OwnedValue::Object(properties)) = get(schema, "properties") {
+                for (key, property) in properties.iter() {
+                    if let Some(field) = object.get(key.as_str()) {
+                        let pointer = format!("{}/{}", pointer, escape_pointer(key));
+                        self.check(base, property, field, &pointer, problems);
+                    }
+                }
+            }
+        }
+        if let (Some(items), OwnedValue::Array(values)) = (get(schema, "items"), value) {
+            for (i, item) in values.iter().enumerate() {
+                self.check(base, items, item, &format!("{}/{}", pointer, i), problems);
+            }
+        }
+
+        if let Some(OwnedValue::Array(all)) = get(schema, "allOf") {
+            for schema in all {
+                self.check(base, schema, value, pointer, problems);
+            }
+        }
+        if let Some(OwnedValue::Array(any)) = get(schema, "anyOf") {
+            // Report the option that came closest to matching
+            let mut closest: Option<Vec<Problem>> = None;
+            for schema in any {
+                let mut attempt = Vec::new();
+                self.check(base, schema, value, pointer, &mut attempt);
+                if attempt.is_empty() {
+                    closest = None;
+                    break;
+                }
+                if closest.as_ref().is_none_or(|c| attempt.len() < c.len()) {
+                    closest = Some(attempt);
+                }
+            }
+            problems.extend(closest.into_iter().flatten());
+        }
+    }
+
+    fn check_document(
+        &self,
+        schema: &'static str,
+        data: &mut [u8],
+        line: Option<usize>,
+    ) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        match simd_json::from_slice::<OwnedValue>(data) {
+            Ok(value) => self.check(schema, &self.schemas[schema], &value, "", &mut problems),
+            Err(e) => problems.push(Problem {
+                line: None,
+                pointer: String::new(),
+                message: format!("not valid JSON: {}", e),
+            }),
+        }
+        for problem in problems.iter_mut() {
+            problem.line = line;
+        }
+        problems
+    }
+
+    fn check_file(
+        &self,
+        path: &Path,
+        schema: &'static str,
+        layout: Layout,
+    ) -> Result<Vec<Problem>, Error> {
+        Ok(match layout {
+            Layout::Document => self.check_document(schema, &mut fs::read(path)?, None),
+            // A message at a time, which is all a huge file can be read as
+            Layout::Messages => {
+                let item =
+                    get(&self.schemas[schema], "items").ok_or("messages schema has no items")?;
+                let mut problems = Vec::new();
+                for (i, message) in MessageReader::<OwnedValue>::open(path)?.enumerate() {
+                    match message {
+                        Ok(message) => {
+                            self.check(schema, item, &message, &format!("/{}", i), &mut problems)
+                        }
+                        Err(e) => {
+                            report(&mut problems, &format!("/{}", i), e.to_string());
+                            break;
+                        }
+                    }
+                }
+                problems
+            }
+            Layout::Lines => {
+                let mut problems = Vec::new();
+                for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                    let mut line = line?.into_bytes();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    problems.extend(self.check_document(schema, &mut line, Some(i + 1)));
+                }
+                problems
+            }
+        })
+    }
+}
+
+/// Check every file in the backup in the current directory against its
+/// schema, listing each malformed value by file, line and JSON pointer.
+/// Archives written by older versions pass as long as this version can
+/// still read them. Run it in a snapshot's directory to check the snapshot.
+pub fn validate() -> Result<(), Error> {
+    let validator = Validator::new()?;
+    let mut files = backup_files()?;
+    if Path::new(STATE_FILE).exists() {
+        files.push((STATE_FILE.to_owned(), Path::new(STATE_FILE).to_path_buf()));
+    }
+
+    let mut checked = 0;
+    let mut malformed = 0;
+    for (name, path) in files {
+        let Some((schema, layout)) = schema_for(&name) else {
+            continue;
+        };
+        checked += 1;
+        let problems = match validator.check_file(&path, schema, layout) {
+            Ok(problems) => problems,
+            Err(e) => vec![Problem {
+                line: None,
+                pointer: String::new(),
+                message: format!("couldn't be read {:?}", e),
+            }],
+        };
+        if problems.is_empty() {
+            continue;
+        }
+        malformed += 1;
+        println!("{} (against {}):", name, schema);
+        for problem in problems.iter().take(MAX_PROBLEMS_SHOWN) {
+            println!("  {}", problem);
+        }
+        if problems.len() > MAX_PROBLEMS_SHOWN {
+            println!("  and {} more", problems.len() - MAX_PROBLEMS_SHOWN);
+        }
+    }
+
+    println!("Checked {} files", checked);
+    if malformed > 0 {
+        return Err(format!("{} of {} files are malformed", malformed, checked).into());
+    }
+    println!("All files match their schemas");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = "{\"id\":\"1\",\"author\":{\"id\":\"2\",\"username\":\"tester\"},\
+        \"timestamp\":\"2021-01-01T00:00:00+00:00\",\"content\":\"hi\"}";
+
+    /// Every `$ref` in `schema` and below
+    fn refs<'a>(schema: &'a OwnedValue, found: &mut Vec<&'a str>) {
+        match schema {
+            OwnedValue::Object(object) => {
+                for (key, value) in object.iter() {
+                    match value {
+                        OwnedValue::String(reference) if key == "$ref" => found.push(reference),
+                        value => refs(value, found),
+                    }
+                }
+            }
+            OwnedValue::Array(values) => values.iter().for_each(|v| refs(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_schema_is_embedded() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("schemas");
+        for file in fs::read_dir(dir).unwrap() {
+            let name = file.unwrap().file_name().into_string().unwrap();
+            assert!(
+                SCHEMAS.iter().any(|(embedded, _)| *embedded == name),
+                "{} isn't in SCHEMAS",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn every_ref_resolves() {
+        let validator = Validator::new().unwrap();
+        for (name, schema) in validator.schemas.iter() {
+            let mut found = Vec::new();
+            refs(schema, &mut found);
+            for reference in found {
+                assert!(
+                    validator.resolve(name, reference).is_ok(),
+                    "{} in {} doesn't resolve",
+                    reference,
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn files_get_their_schemas() {
+        assert_eq!(
+            schema_for("20.messages.json").map(|(s, _)| s),
+            Some("messages.json")
+        );
+        assert_eq!(
+            schema_for(INDEX_FILE).map(|(s, _)| s),
+            Some("attachment-index.json")
+        );
+        assert!(schema_for("attachments/20/1-cat.png").is_none());
+        assert!(schema_for("notes.txt").is_none());
+    }
+
+    #[test]
+    fn valid_message_passes() {
+        let validator = Validator::new().unwrap();
+        let mut data = format!("[{}]", MESSAGE).into_bytes();
+        let problems: Vec<String> = validator
+            .check_document("messages.json", &mut data, None)
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(problems, Vec::<String>::new());
+    }
+
+    #[test]
+    fn problems_are_located() {
+        let validator = Validator::new().unwrap();
+        let mut data = format!(
+            "[{},{{\"id\":\"x\",\"author\":{{\"id\":\"2\",\"username\":\"tester\"}}}}]",
+            MESSAGE
+        )
+        .into_bytes();
+        let problems: Vec<String> = validator
+            .check_document("messages.json", &mut data, Some(3))
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "line 3: /1: missing field \"timestamp\"",
+                "line 3: /1/id: \"x\" isn't a snowflake",
+            ]
+        );
+    }
+}
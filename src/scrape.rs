@@ -0,0 +1,998 @@
+use std::{
+    collections::HashSet,
+    fs::{self, remove_file, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use serde::Deserialize;
+use twilight_http::Client;
+use twilight_http_ratelimiting::request::Path as RatelimitPath;
+use twilight_model::{
+    channel::{message::Message, Channel, ChannelType},
+    id::{ChannelId, GuildId, MessageId},
+};
+
+use crate::{
+    api::Api,
+    archive::{open_length, sort_messages, splice_messages, MessageReader, Order},
+    attachments::{AttachmentOptions, Downloader, IndexEntry, INDEX_FILE},
+    diskspace,
+    error::BackupError,
+    estimate::{self, Estimate},
+    guild,
+    ledger::{self, Failure},
+    manifest,
+    meta::{meta_path, write_channel_meta},
+    progress::{format_bytes, Progress},
+    ratelimit::{self, MESSAGES_ROUTE},
+    reactions::{self, reactions_path},
+    references, resume,
+    state::{get_active_state, save_active_state, ChannelState, ChannelStatus, State, STATE_FILE},
+    Error,
+};
+
+const MESSAGE_CHUNK_SIZE: u64 = 100;
+
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    /// Fetch only messages newer than those already on disk, appending them
+    pub resume_forward: bool,
+    /// Only back up these channels, all of them when empty
+    pub channels: HashSet<ChannelId>,
+    /// Newest message (inclusive) to fetch in each channel
+    pub until_message: Option<MessageId>,
+    /// Oldest message (inclusive) to fetch in each channel
+    pub from_message: Option<MessageId>,
+    /// Fetch `around_count` messages centred on this one instead
+    pub around_message: Option<MessageId>,
+    pub around_count: u64,
+    pub attachments: AttachmentOptions,
+    /// Pause while less than this many bytes are free on disk
+    pub min_free_space: u64,
+    /// Order of the messages in finished channel files
+    pub order: Order,
+    /// Estimate the size of every channel before fetching, for ETAs
+    pub prescan: bool,
+    /// Fetch every user who reacted to each message
+    pub reactions: bool,
+    /// Store messages replied to or forwarded from outside the backup
+    pub references: bool,
+    /// Back up only the channels given, as rescrape and retry-failed do,
+    /// without looking for their archived threads or counting them against
+    /// the rest of the backup
+    pub single_channel: bool,
+}
+
+/// Just enough of an archived message to find where an archive ends
+#[derive(Deserialize)]
+pub struct ArchivedMessage {
+    pub id: MessageId,
+}
+
+async fn fetch_message_chunk(
+    client: &Client,
+    cursor: &ChannelState,
+    progress: &Progress,
+) -> Result<Vec<Message>, Error> {
+    let req = client
+        .channel_messages(cursor.id)
+        .limit(MESSAGE_CHUNK_SIZE)?;
+
+    progress.info(format!(
+        "Fetching message chunk {}/{:?}",
+        cursor.id, cursor.last_message
+    ));
+    let path = RatelimitPath::ChannelsIdMessages(cursor.id.get());
+    let response = match cursor.last_message.or(cursor.seed) {
+        Some(last_message) if cursor.forward => req.after(last_message).exec(),
+        Some(last_message) => req.before(last_message).exec(),
+        None => req.exec(),
+    };
+    let mut messages = ratelimit::timed(client, progress, MESSAGES_ROUTE, path, response)
+        .await?
+        .models()
+        .await?;
+    if cursor.forward && cursor.last_message.or(cursor.seed).is_some() {
+        // Written oldest first so the last message is the next cursor
+        messages.sort_by_key(|m| m.id);
+    }
+    Ok(messages)
+}
+
+/// The message just after `id`, so it is included when paginating with `before`
+fn after_id(id: MessageId) -> MessageId {
+    MessageId::new(id.get() + 1).expect("Message ID overflowed")
+}
+
+/// Find the newest message within `count` messages after `around`, so a
+/// window around it can be fetched newest first like a normal backup. Returns
+/// that message and how many messages newer than `around` it covers.
+async fn find_window_top(
+    client: &Client,
+    channel: ChannelId,
+    around: MessageId,
+    count: u64,
+) -> Result<(MessageId, u64), Error> {
+    let mut top = around;
+    let mut newer = 0;
+    while newer < count {
+        let chunk = client
+            .channel_messages(channel)
+            .limit(MESSAGE_CHUNK_SIZE)?
+            .after(top)
+            .exec()
+            .await?
+            .models()
+            .await?;
+
+        let mut ids: Vec<MessageId> = chunk.iter().map(|m| m.id).collect();
+        ids.sort();
+        ids.truncate((count - newer) as usize);
+        match ids.last() {
+            Some(id) => top = *id,
+            None => break,
+        }
+        newer += ids.len() as u64;
+
+        if (chunk.len() as u64) < MESSAGE_CHUNK_SIZE {
+            break;
+        }
+    }
+    Ok((top, newer))
+}
+
+/// Reopen a finished messages file for appending, dropping its closing
+/// bracket. Returns the newest message ID already archived, if any.
+fn reopen_archive(file_name: &str) -> Result<(File, Option<MessageId>), Error> {
+    let mut head = None;
+    for message in MessageReader::<ArchivedMessage>::open(Path::new(file_name))? {
+        head = head.max(Some(message?.id));
+    }
+
+    let end = open_length(Path::new(file_name)).map_err(BackupError::io(file_name))?;
+    let file = OpenOptions::new()
+        .append(true)
+        .open(file_name)
+        .map_err(BackupError::io(file_name))?;
+    file.set_len(end).map_err(BackupError::io(file_name))?;
+    Ok((file, head))
+}
+
+async fn fetch_archived_threads(
+    client: &Client,
+    channel: &ChannelId,
+    channels: &mut Vec<Channel>,
+) -> Result<(), Error> {
+    channels.append(
+        &mut client
+            .public_archived_threads(*channel)
+            .exec()
+            .await?
+            .model()
+            .await?
+            .threads,
+    );
+    channels.append(
+        &mut client
+            .private_archived_threads(*channel)
+            .exec()
+            .await?
+            .model()
+            .await?
+            .threads,
+    );
+    Ok(())
+}
+
+fn is_message_channel(kind: ChannelType) -> bool {
+    matches!(
+        kind,
+        ChannelType::GuildText
+            | ChannelType::GuildPublicThread
+            | ChannelType::GuildPrivateThread
+            | ChannelType::Private
+            | ChannelType::Group
+    )
+}
+
+fn is_voice_channel(kind: ChannelType) -> bool {
+    matches!(kind, ChannelType::GuildVoice | ChannelType::GuildStageVoice)
+}
+
+/// Estimate the size of the channels that will be backed up. Archived
+/// threads are only found once their parent is fetched, so aren't included.
+async fn prescan(
+    client: &Client,
+    api: &Api,
+    state: &State,
+    channels: &[Channel],
+    options: &BackupOptions,
+    progress: &Progress,
+) {
+    progress.info("Estimating channel sizes...".to_owned());
+    let mut search = true;
+    let mut total = Estimate::default();
+    let mut counted = 0;
+    for channel in channels {
+        let id = channel.id();
+        if (!options.channels.is_empty() && !options.channels.contains(&id))
+            || !is_message_channel(channel.kind())
+            || state.is_complete(id)
+        {
+            continue;
+        }
+        match estimate::estimate(client, api, state.current_guild, id, &mut search).await {
+            Ok(estimate) => {
+                total.messages += estimate.messages;
+                total.bytes += estimate.bytes;
+                counted += 1;
+                progress.set_estimate(id, estimate);
+            }
+            Err(e) => progress.error(format!("Error estimating {} {:?}", id, e)),
+        }
+    }
+    progress.info(format!(
+        "Estimated {} messages ({}) in {} channels",
+        total.messages,
+        format_bytes(total.bytes),
+        counted
+    ));
+}
+
+/// Load the state left by an interrupted run, checked against the files on
+/// disk, or start fresh. A state file belongs to a single guild, or to
+/// private channels when `guild` is `None`.
+fn load_state(guild: Option<GuildId>, progress: &Progress) -> Result<State, Error> {
+    let state = match get_active_state()? {
+        Some(mut state) => {
+            for channel in state.channels.iter() {
+                if Path::new(&aside_path(&format!("{}.messages.json", channel.id))).exists() {
+                    return Err(format!(
+                        "A rescrape of {} was interrupted, run rescrape --channel {} again to \
+                         roll it back",
+                        channel.id, channel.id
+                    )
+                    .into());
+                }
+            }
+            resume::revalidate(&mut state, progress)?;
+            state
+        }
+        None => State::new(guild),
+    };
+
+    if guild != state.current_guild {
+        return Err(BackupError::WrongGuild {
+            state: state.current_guild,
+            requested: guild,
+        }
+        .into());
+    }
+
+    save_active_state(&state)?;
+    Ok(state)
+}
+
+pub async fn backup_guild(
+    client: &Client,
+    api: &Api,
+    guild_id: GuildId,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), BackupError> {
+    let mut state = load_state(Some(guild_id), progress)?;
+
+    guild::export_metadata(client, api, guild_id, progress).await;
+
+    let channels = guild_channels(client, guild_id, progress).await?;
+
+    Ok(backup_channels(client, api, &mut state, channels, options, progress).await?)
+}
+
+/// The guild's channels and its active threads
+async fn guild_channels(
+    client: &Client,
+    guild_id: GuildId,
+    progress: &Progress,
+) -> Result<Vec<Channel>, Error> {
+    let mut channels: Vec<Channel> = Vec::new();
+
+    progress.info("Fetching channels...".to_owned());
+    channels.extend(
+        client
+            .guild_channels(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .iter()
+            .map(|c| Channel::Guild(c.to_owned())),
+    );
+
+    progress.info("Fetching active threads...".to_owned());
+    channels.append(
+        &mut client
+            .active_threads(guild_id)
+            .exec()
+            .await?
+            .model()
+            .await?
+            .threads,
+    );
+
+    Ok(channels)
+}
+
+/// Back up the DM and group DM channels visible to the (user) token
+pub async fn backup_private_channels(
+    client: &Client,
+    api: &Api,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), BackupError> {
+    let mut state = load_state(None, progress)?;
+
+    progress.info("Fetching private channels...".to_owned());
+    let channels: Vec<Channel> = api.get("/users/@me/channels").await?;
+
+    Ok(backup_channels(client, api, &mut state, channels, options, progress).await?)
+}
+
+async fn backup_channels(
+    client: &Client,
+    api: &Api,
+    state: &mut State,
+    mut channels: Vec<Channel>,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut counter = 0;
+    diskspace::preflight(options.min_free_space, progress).await?;
+    let mut downloader = Downloader::new(&options.attachments)?;
+
+    let pending: Vec<ChannelState> = state
+        .channels
+        .iter()
+        .filter(|c| !c.pending_attachments.is_empty() || !c.pending_reactions.is_empty())
+        .cloned()
+        .collect();
+    for channel in pending {
+        if !channel.pending_attachments.is_empty() {
+            progress.info(format!(
+                "Resuming {} attachment downloads in {}...",
+                channel.pending_attachments.len(),
+                channel.id
+            ));
+            downloader
+                .download(channel.pending_attachments, progress)
+                .await?;
+            state.channel(channel.id).pending_attachments.clear();
+            save_active_state(state)?;
+        }
+        if !channel.pending_reactions.is_empty() {
+            progress.info(format!(
+                "Resuming {} reactions in {}...",
+                channel.pending_reactions.len(),
+                channel.id
+            ));
+            reactions::fetch(client, channel.id, state, progress).await?;
+        }
+    }
+
+    if options.prescan {
+        prescan(client, api, state, &channels, options, progress).await;
+    }
+
+    progress.info("Fetching messages...".to_owned());
+    while let Some(channel) = channels.pop() {
+        progress.set_queue(&channels);
+
+        if !options.channels.is_empty() && !options.channels.contains(&channel.id()) {
+            progress.info(format!("Skipping {} (not selected)", channel.id()));
+            continue;
+        }
+
+        if is_voice_channel(channel.kind()) {
+            // No history worth scraping, but the meta file records the
+            // bitrate, user limit, region override and video quality
+            match write_channel_meta(api, channel.id()).await {
+                Ok(()) => progress.info(format!("Saved voice settings for {}", channel.id())),
+                Err(e) => progress.error(format!(
+                    "Error saving voice settings for {} {:?}",
+                    channel.id(),
+                    e
+                )),
+            }
+            continue;
+        }
+
+        if !is_message_channel(channel.kind()) {
+            progress.info(format!(
+                "Skipping {} (bad type {:?})",
+                channel.id(),
+                channel.kind()
+            ));
+            continue;
+        }
+
+        // Skip channels we've already read
+        if state.is_complete(channel.id()) {
+            progress.info(format!("Skipping {} (already done)", channel.id()));
+            continue;
+        }
+        if state.get(channel.id()).map(|c| c.status) == Some(ChannelStatus::Failed) {
+            progress.info(format!(
+                "Skipping {} (failed earlier, see retry-failed)",
+                channel.id()
+            ));
+            continue;
+        }
+
+        // Fetch archived threads
+        if channel.kind() == ChannelType::GuildText && !options.single_channel {
+            if let Err(e) = fetch_archived_threads(client, &channel.id(), &mut channels).await {
+                progress.error(format!("Error fetching archived threads {:?}", e));
+            }
+            progress.set_queue(&channels);
+        }
+
+        diskspace::check_estimate(options.min_free_space, progress)?;
+        progress.channel_started(&channel);
+
+        let file_name = format!("{}.messages.json", channel.id());
+        let id = channel.id();
+        // An in progress channel without a messages file crashed before it
+        // was created, so is started again. Only a file created by this run
+        // is removed when the channel is skipped.
+        let mut created = false;
+        let mut file = if state.is_in_progress(id) && Path::new(&file_name).exists() {
+            OpenOptions::new()
+                .append(true)
+                .open(&file_name)
+                .map_err(BackupError::io(&file_name))?
+        } else if options.resume_forward && Path::new(&file_name).exists() {
+            let (file, head) = reopen_archive(&file_name)?;
+            let cursor = state.restart(id);
+            cursor.last_message = head;
+            cursor.forward = head.is_some();
+
+            // Channel settings may have changed since the archive was made
+            write_channel_meta(api, id).await?;
+
+            progress.info(format!("Resuming {} forward from {:?}", channel.id(), head));
+            file
+        } else if Path::new(&file_name).exists() {
+            return Err(format!(
+                "{} already exists, pass --resume-forward to update it",
+                file_name
+            )
+            .into());
+        } else {
+            let mut cursor = ChannelState::new(id);
+            cursor.seed = options.until_message.map(after_id);
+            if let Some(around) = options.around_message {
+                let newer = options.around_count / 2;
+                let (top, found) = find_window_top(client, id, around, newer).await?;
+                cursor.seed = Some(after_id(top));
+                cursor.limit = Some(found + options.around_count - newer);
+            }
+            *state.restart(id) = cursor;
+            // Recorded first, so a crash before the messages file exists
+            // starts the channel again instead of refusing to overwrite it
+            save_active_state(state)?;
+
+            write_channel_meta(api, id).await?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&file_name)
+                .map_err(|e| match e.kind() {
+                    // Left by a run whose state file was lost or replaced
+                    ErrorKind::AlreadyExists => BackupError::CorruptState {
+                        path: STATE_FILE.into(),
+                        reason: format!("{} exists but its channel was never started", file_name),
+                    },
+                    _ => BackupError::io(&file_name)(e),
+                })?;
+            write!(file, "[").map_err(BackupError::io(&file_name))?;
+            file.sync_all().map_err(BackupError::io(&file_name))?;
+            created = true;
+            file
+        };
+        save_active_state(state)?;
+
+        let mut skipped = false;
+        let mut failed = false;
+        loop {
+            progress.control.wait_while_paused().await;
+            diskspace::wait_for_space(options.min_free_space, progress).await?;
+            if progress.control.should_stop() {
+                progress.info("Stopping, run again to resume".to_owned());
+                return Ok(());
+            }
+            if progress.control.take_skip() {
+                skipped = true;
+                break;
+            }
+
+            let mut messages = match fetch_message_chunk(client, state.channel(id), progress).await
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    progress.error(format!("Error getting message chunk {:?}", e));
+                    let cursor = state.channel(id);
+                    cursor.errors += 1;
+                    ledger::record(&Failure::Chunk {
+                        channel_id: id,
+                        last_message: cursor.last_message,
+                        forward: cursor.forward,
+                        error: e.to_string(),
+                    })?;
+                    failed = true;
+                    break;
+                }
+            };
+
+            progress.info(format!(
+                "Received message chunk {}/{}",
+                messages.len(),
+                MESSAGE_CHUNK_SIZE
+            ));
+
+            // Trim the chunk to the requested slice
+            let mut finished = (messages.len() as u64) < MESSAGE_CHUNK_SIZE;
+            if let Some(from) = options.from_message {
+                let fetched = messages.len();
+                messages.retain(|m| m.id >= from);
+                finished |= messages.len() < fetched;
+            }
+            if let Some(limit) = state.channel(id).limit {
+                let remaining = limit.saturating_sub(state.channel(id).fetched) as usize;
+                if messages.len() >= remaining {
+                    messages.truncate(remaining);
+                    finished = true;
+                }
+            }
+
+            let message_count = messages.len();
+            if message_count == 0 {
+                break;
+            }
+
+            let mut bytes = 0;
+            if state.channel(id).last_message.is_some() {
+                write!(file, ",")?;
+            }
+            for (i, message) in messages.iter().enumerate() {
+                let encoded = simd_json::to_vec(message)?;
+                file.write_all(&encoded)?;
+                bytes += encoded.len() as u64;
+                if i < message_count - 1 {
+                    write!(file, ",")?;
+                }
+            }
+            progress.chunk_written(message_count as u64, bytes);
+
+            let cursor = state.channel(id);
+            cursor.last_message = messages.last().map(|m| m.id);
+            cursor.fetched += message_count as u64;
+            cursor.bytes += bytes;
+            cursor.pending_attachments = downloader.plan(&messages)?;
+            if options.reactions {
+                cursor.pending_reactions = reactions::plan(&messages);
+            }
+            let downloads = cursor.pending_attachments.clone();
+            let has_reactions = !cursor.pending_reactions.is_empty();
+            save_active_state(state)?;
+
+            if !downloads.is_empty() {
+                downloader.download(downloads, progress).await?;
+                state.channel(id).pending_attachments.clear();
+                save_active_state(state)?;
+            }
+            if has_reactions {
+                reactions::fetch(client, id, state, progress).await?;
+            }
+
+            if finished {
+                break;
+            }
+        }
+
+        if skipped && !created && !state.channel(id).forward {
+            // Holds messages archived by earlier runs, so is kept and left
+            // for `retry-failed` to finish like a failed channel
+            let cursor = state.channel(id);
+            ledger::record(&Failure::Chunk {
+                channel_id: id,
+                last_message: cursor.last_message,
+                forward: cursor.forward,
+                error: "Skipped".to_owned(),
+            })?;
+            failed = true;
+        }
+        if failed {
+            // Not complete, but what was fetched is kept for `retry-failed`
+            write!(file, "]")?;
+            state.channel(id).status = ChannelStatus::Failed;
+            save_active_state(state)?;
+            progress.channel_finished();
+            progress.info(format!("Failed channel {}", channel.id()));
+            continue;
+        } else if skipped && state.channel(id).forward {
+            // Keep the existing archive, it is still valid up to here
+            write!(file, "]")?;
+            sort_messages(Path::new(&file_name), options.order)?;
+            state.channels.retain(|c| c.id != id);
+            save_active_state(state)?;
+            progress.channel_finished();
+            progress.info(format!("Skipped channel {}", channel.id()));
+            continue;
+        } else if skipped {
+            // Drop the partial output so the channel starts fresh next run
+            drop(file);
+            remove_file(&file_name)?;
+            remove_file(meta_path(id))?;
+            let _ = remove_file(reactions_path(channel.id()));
+            state.channels.retain(|c| c.id != id);
+            save_active_state(state)?;
+            progress.channel_finished();
+            progress.info(format!("Skipped channel {}", channel.id()));
+            continue;
+        }
+
+        write!(file, "]")?;
+        // Fetched newest first, appending oldest first when resumed forward
+        if options.order == Order::Asc || state.channel(id).forward {
+            sort_messages(Path::new(&file_name), options.order)?;
+        }
+        state.channel(id).status = ChannelStatus::Complete;
+        save_active_state(state)?;
+        progress.channel_finished();
+
+        if options.single_channel {
+            progress.info(format!("Completed channel {}", channel.id()));
+        } else {
+            counter += 1;
+            progress.info(format!(
+                "[{}/{:?}] Completed channel {}...",
+                counter,
+                channels.len() + state.completed(),
+                channel.id()
+            ));
+        }
+    }
+
+    if options.references {
+        progress.info("Fetching referenced messages...".to_owned());
+        references::backup(client, progress).await?;
+        if progress.control.should_stop() {
+            progress.info("Stopping, run again to resume".to_owned());
+            return Ok(());
+        }
+    }
+
+    remove_file(STATE_FILE)?;
+    let (summary, report) = {
+        let state = progress.state();
+        (state.summary(), state.rate_limit_report())
+    };
+    progress.info(format!("Done! {}", summary));
+    if !report.is_empty() {
+        progress.info("Requests by route:".to_owned());
+        for line in report {
+            progress.info(line);
+        }
+    }
+
+    Ok(())
+}
+
+/// Retry everything recorded in the error ledger: attachments are downloaded
+/// again and failed channels continue from where they stopped. Anything that
+/// fails again is recorded again.
+pub async fn retry_failed(
+    client: &Client,
+    api: &Api,
+    guild: Option<GuildId>,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), BackupError> {
+    Ok(retry_ledger(client, api, guild, options, progress).await?)
+}
+
+async fn retry_ledger(
+    client: &Client,
+    api: &Api,
+    guild: Option<GuildId>,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), Error> {
+    if Path::new(STATE_FILE).exists() {
+        return Err("A backup was interrupted, run it again to finish before retrying".into());
+    }
+    let failures = ledger::read()?;
+    if failures.is_empty() {
+        progress.info("Nothing to retry".to_owned());
+        return Ok(());
+    }
+    // Anything that fails again is recorded afresh as it's retried, and
+    // anything a stopped retry didn't get to is put back
+    ledger::clear()?;
+    let (chunks, attachments): (Vec<Failure>, Vec<Failure>) = failures
+        .into_iter()
+        .partition(|f| matches!(f, Failure::Chunk { .. }));
+
+    if let Err(e) = retry_attachments(api, &attachments, options, progress).await {
+        restore_failures(&attachments)?;
+        restore_failures(&chunks)?;
+        return Err(e);
+    }
+
+    // Failed channels are retried whatever the channel selection, and
+    // without their archived threads, which were queued with them
+    let options = BackupOptions {
+        channels: HashSet::new(),
+        single_channel: true,
+        ..options.clone()
+    };
+    for (i, failure) in chunks.iter().enumerate() {
+        let Failure::Chunk {
+            channel_id,
+            last_message,
+            forward,
+            ..
+        } = *failure
+        else {
+            continue;
+        };
+        let channel: Result<Channel, Error> = match client.channel(channel_id).exec().await {
+            Ok(response) => response.model().await.map_err(Into::into),
+            Err(e) => Err(e.into()),
+        };
+        let channel = match channel {
+            Ok(channel) => channel,
+            // Perhaps deleted, the rest can still be retried
+            Err(e) => {
+                progress.error(format!(
+                    "Unable to fetch {} to retry it {:?}",
+                    channel_id, e
+                ));
+                ledger::record(failure)?;
+                continue;
+            }
+        };
+
+        let mut state = State::new(guild);
+        let cursor = state.channel(channel_id);
+        cursor.last_message = last_message;
+        cursor.forward = forward;
+        if let Err(e) = retry_chunk(client, api, state, channel, &options, progress).await {
+            // Once the state file is written it resumes this channel itself
+            let rest = if Path::new(STATE_FILE).exists() {
+                &chunks[i + 1..]
+            } else {
+                &chunks[i..]
+            };
+            restore_failures(rest)?;
+            return Err(e);
+        }
+        if progress.control.should_stop() {
+            // The state file resumes this channel, retrying the rest now
+            // would replace it
+            restore_failures(&chunks[i + 1..])?;
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Put failures back in the ledger, for a retry that stopped before them
+fn restore_failures(failures: &[Failure]) -> Result<(), Error> {
+    for failure in failures {
+        ledger::record(failure)?;
+    }
+    Ok(())
+}
+
+async fn retry_attachments(
+    api: &Api,
+    failures: &[Failure],
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut attachments: Vec<IndexEntry> = Vec::new();
+    let mut expired: Vec<IndexEntry> = Vec::new();
+    for failure in failures.iter() {
+        match failure {
+            Failure::Attachment {
+                entry,
+                expired: true,
+                ..
+            } => expired.push(entry.clone()),
+            Failure::Attachment { entry, .. } => attachments.push(entry.clone()),
+            _ => {}
+        }
+    }
+    if attachments.is_empty() && expired.is_empty() {
+        return Ok(());
+    }
+    let mut downloader = Downloader::new(&options.attachments)?;
+    if !attachments.is_empty() {
+        progress.info(format!("Retrying {} attachments...", attachments.len()));
+        downloader.download(attachments, progress).await?;
+    }
+    if !expired.is_empty() {
+        progress.info(format!(
+            "Refreshing the URLs of {} expired attachments...",
+            expired.len()
+        ));
+        downloader.refresh(api, expired, progress).await?;
+    }
+    Ok(())
+}
+
+/// Reopen a failed channel's archive where it stopped, then resume it like
+/// an interrupted run from the cursor in `state`
+async fn retry_chunk(
+    client: &Client,
+    api: &Api,
+    mut state: State,
+    channel: Channel,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let channel_id = channel.id();
+    let file_name = format!("{}.messages.json", channel_id);
+    let end = open_length(Path::new(&file_name)).map_err(BackupError::io(&file_name))?;
+    OpenOptions::new()
+        .write(true)
+        .open(&file_name)
+        .and_then(|file| file.set_len(end))
+        .map_err(BackupError::io(&file_name))?;
+    save_active_state(&state)?;
+
+    let last_message = state.channel(channel_id).last_message;
+    progress.info(format!("Retrying {} from {:?}", channel_id, last_message));
+    backup_channels(client, api, &mut state, vec![channel], options, progress).await
+}
+
+/// Where a file is kept while its channel is rescraped, to be put back if the
+/// rescrape doesn't finish
+fn aside_path(file_name: &str) -> String {
+    format!(".{}.rescrape", file_name)
+}
+
+/// Put back the files set aside for a rescrape. Files that had no copy
+/// didn't exist before, so are removed.
+fn restore_aside(files: &[String]) -> Result<(), Error> {
+    for file_name in files {
+        let aside = aside_path(file_name);
+        if Path::new(&aside).exists() {
+            fs::rename(&aside, file_name)?;
+        } else if Path::new(file_name).exists() {
+            remove_file(file_name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fetch a channel's messages between `options.from_message` and
+/// `options.until_message` (the whole channel without either) again,
+/// replacing those in its archive. Messages outside the range are kept as
+/// they are, messages since deleted are dropped, and with `--reactions`
+/// their reactions are fetched again too. The original files are set aside
+/// until the new messages are in, so a rescrape that fails, is stopped or is
+/// interrupted (rolled back by the next rescrape) leaves the archive as it
+/// was.
+pub async fn rescrape(
+    client: &Client,
+    api: &Api,
+    guild: Option<GuildId>,
+    channel_id: ChannelId,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), BackupError> {
+    Ok(rescrape_range(client, api, guild, channel_id, options, progress).await?)
+}
+
+async fn rescrape_range(
+    client: &Client,
+    api: &Api,
+    guild: Option<GuildId>,
+    channel_id: ChannelId,
+    options: &BackupOptions,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let file_name = format!("{}.messages.json", channel_id);
+    let files = vec![
+        file_name.clone(),
+        meta_path(channel_id),
+        reactions_path(channel_id),
+    ];
+    if Path::new(&aside_path(&file_name)).exists() {
+        restore_aside(&files)?;
+        let _ = remove_file(STATE_FILE);
+        progress.info(format!(
+            "Rolled back an interrupted rescrape of {}",
+            channel_id
+        ));
+    } else if Path::new(STATE_FILE).exists() {
+        return Err("A backup was interrupted, run it again to finish before rescraping".into());
+    }
+    if !Path::new(&file_name).exists() {
+        return Err(format!("{} isn't in the backup, back it up first", file_name).into());
+    }
+    let channel = client.channel(channel_id).exec().await?.model().await?;
+    let (from, until) = (options.from_message, options.until_message);
+    let replaced = |id: MessageId| from.is_none_or(|f| id >= f) && until.is_none_or(|u| id <= u);
+
+    // Copies first, so the messages file being set aside marks a complete set
+    for file_name in files.iter().skip(1) {
+        let aside = aside_path(file_name);
+        let _ = remove_file(&aside);
+        if Path::new(file_name).exists() {
+            fs::copy(file_name, &aside)?;
+        }
+    }
+    fs::rename(&file_name, aside_path(&file_name))?;
+    if options.reactions {
+        reactions::drop_reactions(channel_id, replaced)?;
+    }
+
+    // Backed up like a partial backup of just this channel, its archived
+    // threads are already in the backup
+    let options = BackupOptions {
+        resume_forward: false,
+        channels: HashSet::new(),
+        around_message: None,
+        single_channel: true,
+        ..options.clone()
+    };
+    let recorded = ledger::read()?.len();
+    let mut state = State::new(guild);
+    save_active_state(&state)?;
+    progress.info(format!(
+        "Rescraping {} from {:?} until {:?}",
+        channel_id, from, until
+    ));
+    let result = backup_channels(client, api, &mut state, vec![channel], &options, progress).await;
+
+    if result.is_err() || state.get(channel_id).map(|c| c.status) != Some(ChannelStatus::Complete) {
+        restore_aside(&files)?;
+        // A failed chunk can't be retried against the original archive
+        let failures = ledger::read()?;
+        ledger::clear()?;
+        for (i, failure) in failures.iter().enumerate() {
+            if i < recorded || !matches!(failure, Failure::Chunk { .. }) {
+                ledger::record(failure)?;
+            }
+        }
+        let _ = remove_file(STATE_FILE);
+        result?;
+        return Err(format!(
+            "Rescrape of {} didn't finish, the archive is unchanged",
+            channel_id
+        )
+        .into());
+    }
+
+    let fetched = state.channel(channel_id).fetched;
+    let dropped = splice_messages(
+        Path::new(&file_name),
+        Path::new(&aside_path(&file_name)),
+        replaced,
+        options.order,
+    )?;
+    for file_name in files.iter() {
+        let _ = remove_file(aside_path(file_name));
+    }
+    progress.info(format!(
+        "Replaced {} messages in {} with {} fetched again",
+        dropped, file_name, fetched
+    ));
+
+    // New attachments are picked up as files missing from the manifest
+    manifest::refresh(&[files, vec![INDEX_FILE.to_owned()]].concat())
+}
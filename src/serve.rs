@@ -0,0 +1,412 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt::Write as _,
+    fs,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use clap::ValueEnum;
+use futures_util::stream;
+use hyper::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::io::AsyncReadExt;
+use twilight_model::id::ChannelId;
+
+use crate::{
+    archive::{message_files, MessageIndex},
+    attachments::ATTACHMENT_DIR,
+    export::{channel_title, escape_html, html_page, quote, ExportOptions, Exporter},
+    mentions::Resolver,
+    model::MessageData,
+    progress::format_bytes,
+    readstate, references,
+    tags::{self, parse_language, Category, TagFilter},
+    Error,
+};
+
+/// Messages on each page of a channel, oldest first
+const PAGE_SIZE: usize = 500;
+/// Search results shown, the rest are only counted
+const MAX_RESULTS: usize = 200;
+/// Bytes of an attachment read at a time while sending it
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const SEARCH_FORM: &str = "<form action=\"/search\"><input name=\"q\" placeholder=\"Search\"> \
+    <input name=\"language\" placeholder=\"Language (e.g. eng)\" size=\"18\"> \
+    <select name=\"category\"><option value=\"\">Any category</option>\
+    <option>link</option><option>code</option><option>question</option><option>attachment</option>\
+    </select> <button>Search</button></form>\n";
+
+struct Viewer {
+    options: ExportOptions,
+    /// Authors are added as their channels are viewed
+    resolver: Mutex<Resolver>,
+    last_read: HashMap<u64, u64>,
+    /// Each messages file's index, built again once the file changes
+    indexes: Mutex<HashMap<PathBuf, CachedIndex>>,
+}
+
+/// An index with the length and modification time of the file it was built
+/// from
+struct CachedIndex {
+    len: u64,
+    modified: SystemTime,
+    index: Arc<MessageIndex>,
+}
+
+/// Decode a percent encoded path, or with `form` a query string value where
+/// `+` is a space
+fn decode(value: &str, form: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') if form => out.push(b' '),
+            (None, byte) => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn query(request: &Request<Body>) -> HashMap<String, String> {
+    request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (decode(key, true), decode(value, true)))
+        .collect()
+}
+
+fn page(status: StatusCode, title: &str, body: &str) -> Response<Body> {
+    let body = format!(
+        "<p><a href=\"/\">Channels</a></p>\n{}<h1>{}</h1>\n{}",
+        SEARCH_FORM,
+        escape_html(title),
+        body
+    );
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(html_page(title, &body)))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    page(StatusCode::NOT_FOUND, "Not found", "")
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+impl Viewer {
+    fn respond(&self, request: &Request<Body>) -> Result<Response<Body>, Error> {
+        if request.method() != Method::GET {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())?);
+        }
+        let path = request.uri().path();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match segments.as_slice() {
+            [] => self.channels(),
+            ["search"] => self.search(&query(request)),
+            ["channels", id] => match id.parse().ok().and_then(ChannelId::new) {
+                Some(channel) => self.channel(channel, &query(request)),
+                None => Ok(not_found()),
+            },
+            [ATTACHMENT_DIR, ..] => Ok(self.attachment(&decode(path, false))),
+            _ => Ok(not_found()),
+        }
+    }
+
+    /// The index of a messages file, from the cache while the file is
+    /// unchanged
+    fn index(&self, path: &Path) -> Result<Arc<MessageIndex>, Error> {
+        let metadata = fs::metadata(path)?;
+        let (len, modified) = (metadata.len(), metadata.modified()?);
+        if let Some(cached) = self.indexes.lock().unwrap().get(path) {
+            if (cached.len, cached.modified) == (len, modified) {
+                return Ok(cached.index.clone());
+            }
+        }
+        // Built without holding the lock, so other channels are served
+        // meanwhile
+        let index = Arc::new(MessageIndex::build(path)?);
+        self.indexes.lock().unwrap().insert(
+            path.to_path_buf(),
+            CachedIndex {
+                len,
+                modified,
+                index: index.clone(),
+            },
+        );
+        Ok(index)
+    }
+
+    fn channels(&self) -> Result<Response<Body>, Error> {
+        let mut channels: Vec<(String, ChannelId, u64)> = Vec::new();
+        for (channel, path) in message_files()? {
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            channels.push((channel_title(channel), channel, size));
+        }
+        channels.sort();
+        let mut body = String::from("<ul>\n");
+        for (title, channel, size) in channels {
+            let _ = writeln!(
+                body,
+                "<li><a href=\"/channels/{}\">{}</a> ({})</li>",
+                channel,
+                escape_html(&title),
+                format_bytes(size)
+            );
+        }
+        body.push_str("</ul>\n");
+        Ok(page(StatusCode::OK, "Channels", &body))
+    }
+
+    /// A page of a channel's messages, `?page=N` or the page with
+    /// `?message=ID`
+    fn channel(
+        &self,
+        channel: ChannelId,
+        query: &HashMap<String, String>,
+    ) -> Result<Response<Body>, Error> {
+        let path = PathBuf::from(format!("{}.messages.json", channel));
+        if !path.exists() {
+            return Ok(not_found());
+        }
+        // Only the page shown is read, found through an index of the file
+        let index = self.index(&path)?;
+        let pages = index.len().div_ceil(PAGE_SIZE).max(1);
+        let number = match query.get("message").and_then(|id| id.parse::<u64>().ok()) {
+            Some(id) => index.position(id) / PAGE_SIZE + 1,
+            None => query
+                .get("page")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(1usize),
+        }
+        .clamp(1, pages);
+        let start = ((number - 1) * PAGE_SIZE).min(index.len());
+        let shown: Vec<MessageData> = index.read(start..(start + PAGE_SIZE).min(index.len()))?;
+
+        // Replies on the page quote these
+        let replied: HashSet<u64> = shown
+            .iter()
+            .filter_map(|m| m.message_reference.as_ref()?.message_id)
+            .collect();
+        let mut quotes: HashMap<_, _> = references::read::<MessageData>()?
+            .iter()
+            .filter(|m| replied.contains(&m.id))
+            .map(quote)
+            .collect();
+        for &id in &replied {
+            let position = index.position(id);
+            if position < index.len() {
+                let message: MessageData = index.read(position..position + 1)?.remove(0);
+                if message.id == id {
+                    quotes.insert(message.id, quote(&message).1);
+                }
+            }
+        }
+        // The "You were here" line goes on the page with the first unread
+        // message, or the last page if everything was read
+        let last_read = self.last_read.get(&channel.get()).copied().filter(|read| {
+            let first_unread = index.position(read + 1);
+            first_unread / PAGE_SIZE + 1 == number
+                || (first_unread == index.len() && number == pages)
+        });
+
+        let mut resolver = self.resolver.lock().unwrap();
+        resolver.add_authors(&shown);
+        let exporter = Exporter {
+            options: &self.options,
+            resolver: &resolver,
+            quotes: &quotes,
+            channel,
+            last_read,
+        };
+        let mut nav = format!("<p>Page {} of {}", number, pages);
+        if number > 1 {
+            let _ = write!(nav, " <a href=\"?page={}\">Older</a>", number - 1);
+        }
+        if number < pages {
+            let _ = write!(nav, " <a href=\"?page={}\">Newer</a>", number + 1);
+        }
+        nav.push_str("</p>\n");
+        let body = format!("{0}{1}{0}", nav, exporter.html_messages(&shown));
+        Ok(page(StatusCode::OK, &channel_title(channel), &body))
+    }
+
+    /// Messages containing `q`, filtered by language and category with the
+    /// tags from `tags.json`
+    fn search(&self, query: &HashMap<String, String>) -> Result<Response<Body>, Error> {
+        let text = query.get("q").map(|q| q.trim()).unwrap_or_default();
+        let language = query.get("language").filter(|l| !l.trim().is_empty());
+        let category = query.get("category").filter(|c| !c.is_empty());
+        let filter = TagFilter {
+            language: match language.map(|l| parse_language(l.trim())) {
+                Some(Ok(language)) => Some(language),
+                Some(Err(e)) => {
+                    return Ok(page(StatusCode::BAD_REQUEST, "Search", &escape_html(&e)))
+                }
+                None => None,
+            },
+            category: match category.map(|c| Category::from_str(c, true)) {
+                Some(Ok(category)) => Some(category),
+                Some(Err(e)) => {
+                    return Ok(page(StatusCode::BAD_REQUEST, "Search", &escape_html(&e)))
+                }
+                None => None,
+            },
+        };
+        if text.is_empty() && filter.language.is_none() && filter.category.is_none() {
+            return Ok(page(StatusCode::OK, "Search", ""));
+        }
+        let (found, count) = match tags::find(text, &filter, MAX_RESULTS) {
+            Ok(found) => found,
+            Err(e) => {
+                return Ok(page(
+                    StatusCode::BAD_REQUEST,
+                    "Search",
+                    &escape_html(&e.to_string()),
+                ))
+            }
+        };
+
+        let mut body = format!("<p>{} messages found</p>\n<ul>\n", count);
+        let mut titles: HashMap<ChannelId, String> = HashMap::new();
+        for (channel, message) in found.iter() {
+            let title = titles
+                .entry(*channel)
+                .or_insert_with(|| channel_title(*channel));
+            let _ = writeln!(
+                body,
+                "<li><a href=\"/channels/{0}?message={1}#{1}\">{2}</a> <strong>{3}</strong> {4}: {5}</li>",
+                channel,
+                message.id,
+                escape_html(title),
+                escape_html(&message.author.name),
+                escape_html(&message.timestamp),
+                escape_html(&message.content)
+            );
+        }
+        body.push_str("</ul>\n");
+        if count > MAX_RESULTS {
+            let _ = writeln!(
+                body,
+                "<p>Only the first {} are shown, search for more to narrow it down</p>",
+                MAX_RESULTS
+            );
+        }
+        Ok(page(StatusCode::OK, &format!("Search: {}", text), &body))
+    }
+
+    /// A file from the asset store, sent as it's read. Only plain relative
+    /// paths inside it are served.
+    fn attachment(&self, path: &str) -> Response<Body> {
+        let path = Path::new(path.trim_start_matches('/'));
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return not_found();
+        }
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(_) => return not_found(),
+        };
+        let mut response = Response::builder().header(CONTENT_TYPE, content_type(path));
+        if let Ok(metadata) = file.metadata() {
+            response = response.header(CONTENT_LENGTH, metadata.len());
+        }
+        let chunks = stream::try_unfold(tokio::fs::File::from_std(file), |mut file| async move {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let read = file.read(&mut chunk).await?;
+            chunk.truncate(read);
+            Ok::<_, std::io::Error>((read > 0).then_some((chunk, file)))
+        });
+        response.body(Body::wrap_stream(chunks)).unwrap()
+    }
+}
+
+/// Serve a read-only view of the backup in the current directory on
+/// `127.0.0.1:port`. Files are read as pages are requested, so it starts
+/// straight away and shows a backup still being made as it grows. Each
+/// request is answered on a blocking thread, keeping file reads off the
+/// server's.
+pub async fn serve(port: u16, options: ExportOptions) -> Result<(), Error> {
+    let viewer = Arc::new(Viewer {
+        options,
+        resolver: Mutex::new(Resolver::load()),
+        last_read: readstate::last_read(),
+        indexes: Mutex::new(HashMap::new()),
+    });
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let viewer = viewer.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let viewer = viewer.clone();
+                async move {
+                    let response =
+                        tokio::task::spawn_blocking(move || viewer.respond(&request)).await;
+                    let error = match response {
+                        Ok(Ok(response)) => return Ok::<_, Infallible>(response),
+                        Ok(Err(e)) => format!("{:?}", e),
+                        Err(e) => format!("{:?}", e),
+                    };
+                    Ok(page(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Error",
+                        &escape_html(&error),
+                    ))
+                }
+            }))
+        }
+    }));
+    println!("Serving the backup on http://{}", addr);
+    server.await?;
+    Ok(())
+}
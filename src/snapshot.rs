@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+
+use crate::{
+    archive::{backup_files, write_json},
+    attachments::{hash_file, ATTACHMENT_DIR, INDEX_FILE},
+    catalog::{self, CatalogEntry, RunKind},
+    manifest::ManifestEntry,
+    model::read_json,
+    Error,
+};
+
+pub const SNAPSHOT_DIR: &str = "snapshots";
+/// Every file in a snapshot with its size and hash, relative to the snapshot
+pub const SNAPSHOT_INDEX: &str = "snapshot.json";
+
+/// The newest snapshot, with its index
+fn latest() -> Result<Option<(PathBuf, Vec<ManifestEntry>)>, Error> {
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(SNAPSHOT_DIR) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.join(SNAPSHOT_INDEX).exists())
+            .collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // Named by time so the last is the newest
+    dirs.sort();
+    match dirs.pop() {
+        Some(dir) => {
+            let index = read_json(dir.join(SNAPSHOT_INDEX))?;
+            Ok(Some((dir, index)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Hard link `from` to `to`, copying instead where links aren't supported
+fn link_or_copy(from: &Path, to: &Path) -> Result<(), Error> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Freeze the backup as it is now into a dated directory under `snapshots/`.
+/// Files unchanged since the previous snapshot are hard linked to it instead
+/// of stored again, and attachments (which are never modified once
+/// downloaded) are linked straight from the backup. Everything else is
+/// copied, since messages files are appended to in place.
+pub fn create() -> Result<(), Error> {
+    let started_at = catalog::now();
+    let previous = latest()?;
+    let previous_files: HashMap<&str, &ManifestEntry> = previous
+        .as_ref()
+        .map(|(_, index)| index.iter().map(|e| (e.path.as_str(), e)).collect())
+        .unwrap_or_default();
+
+    // To the millisecond, and never into an existing snapshot, which would
+    // mix two of them and leave `latest` unable to tell them apart
+    fs::create_dir_all(SNAPSHOT_DIR)?;
+    let dir = Path::new(SNAPSHOT_DIR).join(Utc::now().format("%Y-%m-%dT%H%M%S%.3fZ").to_string());
+    if let Err(e) = fs::create_dir(&dir) {
+        return Err(match e.kind() {
+            ErrorKind::AlreadyExists => {
+                format!("{} already exists, try again", dir.display()).into()
+            }
+            _ => e.into(),
+        });
+    }
+
+    let mut index = Vec::new();
+    let (mut linked, mut copied) = (0, 0);
+    for (name, path) in backup_files()? {
+        let target = dir.join(&name);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Downloaded attachments never change, their hash can be reused
+        let attachment = name.starts_with(ATTACHMENT_DIR) && name != INDEX_FILE;
+        let size = fs::metadata(&path)?.len();
+        let before = previous_files.get(name.as_str()).filter(|p| p.size == size);
+        let sha256 = match before {
+            Some(before) if attachment => before.sha256.clone(),
+            _ => hash_file(&path)?,
+        };
+        let entry = ManifestEntry {
+            path: name,
+            size,
+            sha256,
+        };
+
+        let unchanged = before.filter(|p| p.sha256 == entry.sha256);
+        match (unchanged, &previous) {
+            (Some(_), Some((previous_dir, _))) => {
+                link_or_copy(&previous_dir.join(&entry.path), &target)?;
+                linked += 1;
+            }
+            _ if attachment => {
+                link_or_copy(&path, &target)?;
+                linked += 1;
+            }
+            _ => {
+                fs::copy(&path, &target)?;
+                copied += 1;
+            }
+        }
+        index.push(entry);
+    }
+
+    write_json(&dir.join(SNAPSHOT_INDEX).to_string_lossy(), &index)?;
+    catalog::record(CatalogEntry {
+        id: 0,
+        kind: RunKind::Snapshot,
+        guild_id: catalog::backup_guild(),
+        started_at,
+        finished_at: catalog::now(),
+        complete: true,
+        channels: 0,
+        messages: 0,
+        bytes: index.iter().map(|e| e.size).sum(),
+        attachments: 0,
+        snapshot: Some(dir.to_string_lossy().into_owned()),
+        base: None,
+    })?;
+    println!(
+        "Created snapshot {} ({} files linked, {} copied)",
+        dir.display(),
+        linked,
+        copied
+    );
+    Ok(())
+}
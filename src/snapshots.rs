@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{ChannelId, GuildId};
+
+use crate::{api::Api, Error};
+
+/// One JSON entry per line, appended at each snapshot
+pub const SNAPSHOTS_FILE: &str = "snapshots.json";
+
+#[derive(Deserialize)]
+struct GuildCounts {
+    #[serde(default)]
+    approximate_member_count: Option<u64>,
+    #[serde(default)]
+    approximate_presence_count: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    /// Unix time in seconds
+    timestamp: u64,
+    member_count: Option<u64>,
+    online_count: Option<u64>,
+    /// Seconds covered by `messages`
+    interval: u64,
+    /// Messages sent in each channel since the previous snapshot
+    messages: HashMap<String, u64>,
+}
+
+/// Messages seen on the gateway per channel, between snapshots
+#[derive(Clone, Default)]
+pub struct Activity(Arc<Mutex<HashMap<ChannelId, u64>>>);
+
+impl Activity {
+    pub fn message(&self, channel: ChannelId) {
+        *self.0.lock().unwrap().entry(channel).or_insert(0) += 1;
+    }
+
+    fn take(&self) -> HashMap<ChannelId, u64> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+async fn snapshot(
+    api: &Api,
+    guild_id: GuildId,
+    interval: Duration,
+    activity: &Activity,
+) -> Result<(), Error> {
+    let counts: GuildCounts = api
+        .get(&format!("/guilds/{}?with_counts=true", guild_id))
+        .await?;
+    let snapshot = Snapshot {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        member_count: counts.approximate_member_count,
+        online_count: counts.approximate_presence_count,
+        interval: interval.as_secs(),
+        messages: activity
+            .take()
+            .into_iter()
+            .map(|(channel, count)| (channel.to_string(), count))
+            .collect(),
+    };
+
+    let mut line = simd_json::to_vec(&snapshot)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(SNAPSHOTS_FILE)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Record member and online counts, and how active each channel has been,
+/// every `interval` until the bot stops
+pub async fn record(api: Api, guild_id: GuildId, interval: Duration, activity: Activity) {
+    let mut timer = tokio::time::interval(interval);
+    // The first tick is immediate, wait for a full interval of activity
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        if let Err(e) = snapshot(&api, guild_id, interval, &activity).await {
+            eprintln!("Error recording snapshot {:?}", e);
+        }
+    }
+}
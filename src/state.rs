@@ -0,0 +1,274 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{BufReader, ErrorKind},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{ChannelId, GuildId, MessageId};
+
+use crate::{
+    archive::write_atomic, attachments::IndexEntry, error::BackupError, reactions::PendingReaction,
+};
+
+pub const STATE_FILE: &str = ".discord_scrape_state";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelStatus {
+    /// Its messages file is open (has no closing bracket) and is resumed
+    /// from `last_message`
+    #[default]
+    InProgress,
+    Complete,
+    /// Stopped by an error, its messages file is closed
+    Failed,
+}
+
+/// Progress through a single channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelState {
+    pub id: ChannelId,
+    #[serde(default)]
+    pub status: ChannelStatus,
+    pub last_message: Option<MessageId>,
+    /// Whether `last_message` is the newest message fetched (paginating with
+    /// `after`) rather than the oldest
+    #[serde(default)]
+    pub forward: bool,
+    /// Cursor to start from before anything was fetched
+    #[serde(default)]
+    pub seed: Option<MessageId>,
+    /// Maximum number of messages to fetch
+    #[serde(default)]
+    pub limit: Option<u64>,
+    /// Number of messages fetched so far
+    #[serde(default)]
+    pub fetched: u64,
+    /// Bytes of messages written so far
+    #[serde(default)]
+    pub bytes: u64,
+    /// Number of chunks that failed to fetch
+    #[serde(default)]
+    pub errors: u64,
+    /// Attachments queued for download that haven't finished yet
+    #[serde(default)]
+    pub pending_attachments: Vec<IndexEntry>,
+    /// Reactions on the last chunk whose users haven't all been fetched
+    #[serde(default)]
+    pub pending_reactions: Vec<PendingReaction>,
+}
+
+impl ChannelState {
+    pub fn new(id: ChannelId) -> Self {
+        ChannelState {
+            id,
+            status: ChannelStatus::InProgress,
+            last_message: None,
+            forward: false,
+            seed: None,
+            limit: None,
+            fetched: 0,
+            bytes: 0,
+            errors: 0,
+            pending_attachments: Vec::new(),
+            pending_reactions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct State {
+    /// `None` when backing up private channels
+    pub current_guild: Option<GuildId>,
+    /// Every channel started so far, in the order they were started
+    #[serde(default)]
+    pub channels: Vec<ChannelState>,
+
+    // State files written before channels had their own entries, read only
+    // to be converted by `migrate`
+    #[serde(default, skip_serializing)]
+    current_channel: Option<ChannelId>,
+    #[serde(default, skip_serializing)]
+    last_message: Option<MessageId>,
+    #[serde(default, skip_serializing)]
+    forward: bool,
+    #[serde(default, skip_serializing)]
+    seed: Option<MessageId>,
+    #[serde(default, skip_serializing)]
+    limit: Option<u64>,
+    #[serde(default, skip_serializing)]
+    fetched: u64,
+    #[serde(default, skip_serializing)]
+    pending_attachments: Vec<IndexEntry>,
+    #[serde(default, skip_serializing)]
+    pending_reactions: Vec<PendingReaction>,
+    #[serde(default, skip_serializing)]
+    channels_complete: HashSet<ChannelId>,
+}
+
+impl State {
+    pub fn new(guild: Option<GuildId>) -> Self {
+        State {
+            current_guild: guild,
+            channels: Vec::new(),
+            current_channel: None,
+            last_message: None,
+            forward: false,
+            seed: None,
+            limit: None,
+            fetched: 0,
+            pending_attachments: Vec::new(),
+            pending_reactions: Vec::new(),
+            channels_complete: HashSet::new(),
+        }
+    }
+
+    pub fn get(&self, channel: ChannelId) -> Option<&ChannelState> {
+        self.channels.iter().find(|c| c.id == channel)
+    }
+
+    /// The channel's entry, added if it hasn't been started yet
+    pub fn channel(&mut self, channel: ChannelId) -> &mut ChannelState {
+        match self.channels.iter().position(|c| c.id == channel) {
+            Some(i) => &mut self.channels[i],
+            None => {
+                self.channels.push(ChannelState::new(channel));
+                self.channels.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Start the channel over, forgetting anything recorded for it
+    pub fn restart(&mut self, channel: ChannelId) -> &mut ChannelState {
+        let entry = self.channel(channel);
+        *entry = ChannelState::new(channel);
+        entry
+    }
+
+    pub fn is_complete(&self, channel: ChannelId) -> bool {
+        self.get(channel)
+            .is_some_and(|c| c.status == ChannelStatus::Complete)
+    }
+
+    pub fn is_in_progress(&self, channel: ChannelId) -> bool {
+        self.get(channel)
+            .is_some_and(|c| c.status == ChannelStatus::InProgress)
+    }
+
+    pub fn completed(&self) -> usize {
+        self.channels
+            .iter()
+            .filter(|c| c.status == ChannelStatus::Complete)
+            .count()
+    }
+
+    /// Convert a state file with a single global cursor to per-channel
+    /// entries
+    fn migrate(&mut self) {
+        for id in self.channels_complete.drain() {
+            if let Some(i) = self.channels.iter().position(|c| c.id == id) {
+                self.channels[i].status = ChannelStatus::Complete;
+            } else {
+                let mut entry = ChannelState::new(id);
+                entry.status = ChannelStatus::Complete;
+                self.channels.push(entry);
+            }
+        }
+        if let Some(id) = self.current_channel.take() {
+            let last_message = self.last_message.take();
+            let (forward, seed, limit, fetched) =
+                (self.forward, self.seed, self.limit, self.fetched);
+            let pending_attachments = std::mem::take(&mut self.pending_attachments);
+            let pending_reactions = std::mem::take(&mut self.pending_reactions);
+
+            let entry = self.channel(id);
+            entry.last_message = last_message;
+            entry.forward = forward;
+            entry.seed = seed;
+            entry.limit = limit;
+            entry.fetched = fetched;
+            entry.pending_attachments = pending_attachments;
+            entry.pending_reactions = pending_reactions;
+        }
+    }
+}
+
+/// The state left by an interrupted run, `None` if there isn't one
+pub fn get_active_state() -> Result<Option<State>, BackupError> {
+    let path = Path::new(STATE_FILE);
+    let file = match OpenOptions::new().read(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(source) => {
+            return Err(BackupError::Io {
+                path: Some(path.to_path_buf()),
+                source,
+            })
+        }
+    };
+    let reader = BufReader::new(file);
+
+    let mut state: State =
+        simd_json::from_reader(reader).map_err(|e| BackupError::CorruptState {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+    state.migrate();
+    Ok(Some(state))
+}
+
+pub fn save_active_state(state: &State) -> Result<(), BackupError> {
+    let path = Path::new(STATE_FILE);
+    let data = simd_json::to_vec(state).map_err(|e| BackupError::CorruptState {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })?;
+    write_atomic(path, &data).map_err(BackupError::io(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_single_cursor() {
+        // As written before channels had their own entries
+        let mut data = br#"{"current_guild":"10","current_channel":"20","last_message":"500",
+            "channels_complete":["30","31"]}"#
+            .to_vec();
+        let mut state: State = simd_json::from_slice(&mut data).unwrap();
+        state.migrate();
+
+        assert_eq!(state.current_guild, GuildId::new(10));
+        assert_eq!(state.channels.len(), 3);
+        assert_eq!(state.completed(), 2);
+        for id in [30, 31] {
+            assert!(state.is_complete(ChannelId::new(id).unwrap()));
+        }
+        let current = state.get(ChannelId::new(20).unwrap()).unwrap();
+        assert_eq!(current.status, ChannelStatus::InProgress);
+        assert_eq!(current.last_message, MessageId::new(500));
+
+        // Saved in the new format only, so migrating again changes nothing
+        let mut saved = simd_json::to_vec(&state).unwrap();
+        assert!(!String::from_utf8_lossy(&saved).contains("current_channel"));
+        let mut state: State = simd_json::from_slice(&mut saved).unwrap();
+        state.migrate();
+        assert_eq!(state.channels.len(), 3);
+        assert_eq!(state.completed(), 2);
+    }
+
+    #[test]
+    fn migrate_between_channels() {
+        let mut data = br#"{"current_guild":"10","current_channel":null,"last_message":null,
+            "channels_complete":["30"]}"#
+            .to_vec();
+        let mut state: State = simd_json::from_slice(&mut data).unwrap();
+        state.migrate();
+
+        assert_eq!(state.channels.len(), 1);
+        assert!(state.is_complete(ChannelId::new(30).unwrap()));
+    }
+}
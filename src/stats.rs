@@ -0,0 +1,169 @@
+use std::{collections::HashMap, fmt::Write as _, fs, path::Path};
+
+use crate::{
+    archive::{message_files, MessageReader},
+    model::{read_json, GuildFileData, MessageData, GUILD_FILE},
+    tags::{Filter, TagFilter},
+    Error,
+};
+
+#[derive(Debug, Default)]
+struct Usage {
+    name: String,
+    animated: bool,
+    /// Times used in message content
+    messages: u64,
+    /// Reactions with it
+    reactions: u64,
+    /// Whether it's one of the guild's own
+    in_guild: bool,
+}
+
+impl Usage {
+    fn total(&self) -> u64 {
+        self.messages + self.reactions
+    }
+}
+
+/// Custom emoji in `<:name:id>` or `<a:name:id>` form, as (id, name, animated)
+fn custom_emoji(content: &str) -> Vec<(u64, &str, bool)> {
+    let mut found = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let (animated, body) = match (rest.strip_prefix("a:"), rest.strip_prefix(':')) {
+            (Some(body), _) => (true, body),
+            (None, Some(body)) => (false, body),
+            _ => continue,
+        };
+        let Some(end) = body.find('>') else {
+            break;
+        };
+        if let Some((name, id)) = body[..end].split_once(':') {
+            if let Ok(id) = id.parse() {
+                found.push((id, name, animated));
+            }
+        }
+    }
+    found
+}
+
+/// Rank the custom emoji and stickers by how often they're used in messages
+/// (those matching `filter`) and reactions, including the guild's unused
+/// ones, written as Markdown.
+pub fn emoji_report(output: Option<&Path>, filter: &TagFilter) -> Result<(), Error> {
+    let filter = Filter::load(filter)?;
+    let guild: Option<GuildFileData> = read_json(GUILD_FILE).ok();
+    let mut emoji: HashMap<u64, Usage> = HashMap::new();
+    let mut stickers: HashMap<u64, (String, u64, bool)> = HashMap::new();
+    if let Some(guild) = &guild {
+        for e in guild.guild.emojis.iter() {
+            if let Some(id) = e.id {
+                emoji.insert(
+                    id,
+                    Usage {
+                        name: e.name.clone().unwrap_or_default(),
+                        animated: e.animated,
+                        in_guild: true,
+                        ..Usage::default()
+                    },
+                );
+            }
+        }
+        for s in guild.guild.stickers.iter() {
+            stickers.insert(s.id, (s.name.clone(), 0, true));
+        }
+    }
+
+    for (_, path) in message_files()? {
+        for message in MessageReader::<MessageData>::open(&path)? {
+            let message = message?;
+            if !filter.matches(&message) {
+                continue;
+            }
+            for (id, name, animated) in custom_emoji(&message.content) {
+                let usage = emoji.entry(id).or_insert_with(|| Usage {
+                    name: name.to_owned(),
+                    animated,
+                    ..Usage::default()
+                });
+                usage.messages += 1;
+            }
+            for reaction in message.reactions.iter() {
+                let Some(id) = reaction.emoji.id else {
+                    continue;
+                };
+                let usage = emoji.entry(id).or_insert_with(|| Usage {
+                    name: reaction.emoji.name.clone().unwrap_or_default(),
+                    animated: reaction.emoji.animated,
+                    ..Usage::default()
+                });
+                usage.reactions += reaction.count;
+            }
+            for sticker in message.sticker_items.iter() {
+                stickers
+                    .entry(sticker.id)
+                    .or_insert_with(|| (sticker.name.clone(), 0, false))
+                    .1 += 1;
+            }
+        }
+    }
+
+    let mut ranked: Vec<(&u64, &Usage)> = emoji.iter().collect();
+    ranked.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.1.name.cmp(&b.1.name)));
+    let mut ranked_stickers: Vec<(&u64, &(String, u64, bool))> = stickers.iter().collect();
+    ranked_stickers.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.1 .0.cmp(&b.1 .0)));
+
+    let title = guild
+        .as_ref()
+        .map(|g| g.guild.name.as_str())
+        .unwrap_or("this backup");
+    let mut out = format!("# Emoji usage in {}\n\n", title);
+    out.push_str("| # | Emoji | ID | Messages | Reactions | Total |\n");
+    out.push_str("|---|---|---|---|---|---|\n");
+    for (rank, (id, usage)) in ranked.iter().enumerate() {
+        let mut name = format!(":{}:", usage.name);
+        if usage.animated {
+            name.push_str(" (animated)");
+        }
+        if !usage.in_guild {
+            name.push_str(" (not in this server)");
+        }
+        let _ = writeln!(
+            out,
+            "| {} | {} | {} | {} | {} | {} |",
+            rank + 1,
+            name,
+            id,
+            usage.messages,
+            usage.reactions,
+            usage.total()
+        );
+    }
+
+    out.push_str("\n# Sticker usage\n\n");
+    out.push_str("| # | Sticker | ID | Messages |\n");
+    out.push_str("|---|---|---|---|\n");
+    for (rank, (id, (name, count, in_guild))) in ranked_stickers.iter().enumerate() {
+        let suffix = if *in_guild {
+            ""
+        } else {
+            " (not in this server)"
+        };
+        let _ = writeln!(
+            out,
+            "| {} | {}{} | {} | {} |",
+            rank + 1,
+            name,
+            suffix,
+            id,
+            count
+        );
+    }
+
+    match output {
+        Some(path) => fs::write(path, out)?,
+        None => print!("{}", out),
+    }
+    Ok(())
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::Path,
+};
+
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use twilight_model::id::ChannelId;
+use whatlang::Lang;
+
+use crate::{
+    archive::{message_files, MessageReader},
+    model::{MessageData, Snowflake},
+    Error,
+};
+
+/// One JSON entry per line, for each message tagged so far
+pub const TAGS_FILE: &str = "tags.json";
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// Contains a link
+    Link,
+    /// Contains a code block
+    Code,
+    /// Asks a question
+    Question,
+    /// Has attachments
+    Attachment,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tag {
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    /// ISO 639-3 code, only when it could be detected reliably
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<Category>,
+}
+
+/// Every message tagged so far, by ID
+pub fn read() -> Result<HashMap<u64, Tag>, Error> {
+    let file = match File::open(TAGS_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut tags = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let mut line = line?.into_bytes();
+        if line.is_empty() {
+            continue;
+        }
+        let tag: Tag = simd_json::from_slice(&mut line)?;
+        tags.insert(tag.message_id.0, tag);
+    }
+    Ok(tags)
+}
+
+pub fn parse_language(value: &str) -> Result<String, String> {
+    match Lang::from_code(value.to_ascii_lowercase()) {
+        Some(lang) => Ok(lang.code().to_owned()),
+        None => Err(format!(
+            "Unknown language \"{}\", expected an ISO 639-3 code like eng",
+            value
+        )),
+    }
+}
+
+/// Message text without links, mentions and custom emoji, which throw off
+/// language detection
+fn prose(content: &str) -> String {
+    content
+        .split_whitespace()
+        .filter(|word| {
+            !(word.starts_with("http://") || word.starts_with("https://") || word.starts_with('<'))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn language(content: &str) -> Option<String> {
+    let info = whatlang::detect(&prose(content))?;
+    info.is_reliable().then(|| info.lang().code().to_owned())
+}
+
+fn categories(message: &MessageData) -> Vec<Category> {
+    let mut categories = Vec::new();
+    if message.content.contains("http://") || message.content.contains("https://") {
+        categories.push(Category::Link);
+    }
+    if message.content.contains("```") {
+        categories.push(Category::Code);
+    }
+    if message.content.trim_end().ends_with('?') {
+        categories.push(Category::Question);
+    }
+    if !message.attachments.is_empty() {
+        categories.push(Category::Attachment);
+    }
+    categories
+}
+
+/// Tag every message not tagged yet with its language, and with
+/// `with_categories` its content categories, appending to `tags.json` so
+/// it can be run again after each backup
+pub fn tag(with_categories: bool) -> Result<(), Error> {
+    let existing = read()?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(TAGS_FILE)?;
+    let mut tagged = 0;
+    let mut languages: HashMap<String, u64> = HashMap::new();
+    for (channel, path) in message_files()? {
+        for message in MessageReader::<MessageData>::open(&path)? {
+            let message = message?;
+            if existing.contains_key(&message.id) {
+                continue;
+            }
+            let tag = Tag {
+                channel_id: Snowflake(channel.get()),
+                message_id: Snowflake(message.id),
+                language: language(&message.content),
+                categories: if with_categories {
+                    categories(&message)
+                } else {
+                    Vec::new()
+                },
+            };
+            if let Some(language) = &tag.language {
+                *languages.entry(language.clone()).or_default() += 1;
+            }
+            let mut line = simd_json::to_vec(&tag)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            tagged += 1;
+        }
+    }
+    file.sync_all()?;
+
+    let mut languages: Vec<(String, u64)> = languages.into_iter().collect();
+    languages.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    println!("Tagged {} messages in {}", tagged, TAGS_FILE);
+    for (language, count) in languages {
+        println!("  {} {}", language, count);
+    }
+    Ok(())
+}
+
+/// Limits a report or search to messages with these tags
+#[derive(Args, Debug, Clone, Default)]
+pub struct TagFilter {
+    /// Only messages in this language (ISO 639-3 code, e.g. `eng`), as
+    /// tagged by `tag`
+    #[arg(long, value_parser = parse_language)]
+    pub language: Option<String>,
+
+    /// Only messages in this category, as tagged by `tag --categories`
+    #[arg(long, value_enum)]
+    pub category: Option<Category>,
+}
+
+/// A [`TagFilter`] with the tags it's checked against
+pub struct Filter {
+    filter: TagFilter,
+    tags: HashMap<u64, Tag>,
+}
+
+impl Filter {
+    pub fn load(filter: &TagFilter) -> Result<Self, Error> {
+        let tags = if filter.language.is_some() || filter.category.is_some() {
+            if !Path::new(TAGS_FILE).exists() {
+                return Err(format!("No {}, run tag first", TAGS_FILE).into());
+            }
+            read()?
+        } else {
+            HashMap::new()
+        };
+        Ok(Filter {
+            filter: filter.clone(),
+            tags,
+        })
+    }
+
+    pub fn matches(&self, message: &MessageData) -> bool {
+        if self.filter.language.is_none() && self.filter.category.is_none() {
+            return true;
+        }
+        let Some(tag) = self.tags.get(&message.id) else {
+            return false;
+        };
+        self.filter
+            .language
+            .as_ref()
+            .is_none_or(|l| tag.language.as_ref() == Some(l))
+            && self
+                .filter
+                .category
+                .is_none_or(|c| tag.categories.contains(&c))
+    }
+}
+
+/// Messages containing `query` (ignoring case) that match the filter, with
+/// their channels, oldest first in each channel, and how many there are in
+/// all. Only the first `limit` are kept.
+pub fn find(
+    query: &str,
+    filter: &TagFilter,
+    limit: usize,
+) -> Result<(Vec<(ChannelId, MessageData)>, usize), Error> {
+    let filter = Filter::load(filter)?;
+    let query = query.to_lowercase();
+    let mut found = Vec::new();
+    let mut count = 0;
+    for (channel, path) in message_files()? {
+        // Only the first `limit` matches are kept, so a search holds no more
+        // of the backup in memory than it shows
+        let mut messages = Vec::new();
+        for message in MessageReader::<MessageData>::open(&path)? {
+            let message = message?;
+            if message.content.to_lowercase().contains(&query) && filter.matches(&message) {
+                if found.len() + messages.len() < limit {
+                    messages.push(message);
+                }
+                count += 1;
+            }
+        }
+        messages.sort_by_key(|m| m.id);
+        found.extend(messages.into_iter().map(|m| (channel, m)));
+    }
+    Ok((found, count))
+}
+
+/// Print every message containing `query` (ignoring case) that matches the
+/// filter, oldest first
+pub fn search(query: &str, filter: &TagFilter) -> Result<(), Error> {
+    let (found, _) = find(query, filter, usize::MAX)?;
+    for (channel, message) in found.iter() {
+        println!(
+            "{}/{} {} {}: {}",
+            channel,
+            message.id,
+            message.timestamp,
+            message.author.name,
+            message.content.replace('\n', " ")
+        );
+    }
+    println!("{} messages found", found.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, content: &str, attachments: usize) -> MessageData {
+        let attachments = vec![
+            "{\"id\":\"9\",\"filename\":\"cat.png\",\"size\":1,\"url\":\"https://cdn/cat.png\"}";
+            attachments
+        ];
+        let json = format!(
+            "{{\"id\":\"{}\",\"author\":{{\"id\":\"2\",\"username\":\"tester\"}},\
+             \"timestamp\":\"2021-01-01T00:00:00+00:00\",\"content\":{},\"attachments\":[{}]}}",
+            id,
+            simd_json::to_string(content).unwrap(),
+            attachments.join(",")
+        );
+        simd_json::from_slice(&mut json.into_bytes()).unwrap()
+    }
+
+    fn tag(id: u64, language: &str, categories: Vec<Category>) -> (u64, Tag) {
+        let tag = Tag {
+            channel_id: Snowflake(1),
+            message_id: Snowflake(id),
+            language: Some(language.to_owned()),
+            categories,
+        };
+        (id, tag)
+    }
+
+    #[test]
+    fn languages_are_iso_639_3() {
+        assert_eq!(parse_language("ENG"), Ok("eng".to_owned()));
+        assert!(parse_language("en").is_err());
+    }
+
+    #[test]
+    fn prose_drops_links_and_mentions() {
+        assert_eq!(
+            prose("see https://example.com <@1>  it's <:cat:2> here"),
+            "see it's here"
+        );
+    }
+
+    #[test]
+    fn categorises_content() {
+        assert_eq!(
+            categories(&message(1, "is https://example.com down?", 0)),
+            vec![Category::Link, Category::Question]
+        );
+        assert_eq!(
+            categories(&message(2, "```rust\nfn main() {}\n```", 1)),
+            vec![Category::Code, Category::Attachment]
+        );
+        assert!(categories(&message(3, "just chatting", 0)).is_empty());
+    }
+
+    #[test]
+    fn filter_needs_every_tag() {
+        let filter = Filter {
+            filter: TagFilter {
+                language: Some("eng".to_owned()),
+                category: Some(Category::Question),
+            },
+            tags: HashMap::from([
+                tag(1, "eng", vec![Category::Question]),
+                tag(2, "eng", Vec::new()),
+                tag(3, "fra", vec![Category::Question]),
+            ]),
+        };
+        let matched: Vec<u64> = (1..=4)
+            .filter(|id| filter.matches(&message(*id, "", 0)))
+            .collect();
+        assert_eq!(matched, vec![1]);
+    }
+
+    #[test]
+    fn empty_filter_matches_untagged() {
+        let filter = Filter {
+            filter: TagFilter::default(),
+            tags: HashMap::new(),
+        };
+        assert!(filter.matches(&message(1, "", 0)));
+    }
+}
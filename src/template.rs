@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    archive::write_json,
+    model::{read_json, ChannelData, RoleData, CHANNELS_FILE, CHANNEL_TYPE_CATEGORY, ROLES_FILE},
+    Error,
+};
+
+pub const TEMPLATE_FILE: &str = "template.json";
+
+/// The `serialized_source_guild` of a Discord guild template, which is also
+/// what `POST /guilds` accepts. IDs are placeholders local to the template,
+/// with 0 being `@everyone`.
+#[derive(Serialize)]
+struct SerializedGuild {
+    name: String,
+    roles: Vec<TemplateRole>,
+    channels: Vec<TemplateChannel>,
+}
+
+#[derive(Serialize)]
+struct TemplateRole {
+    id: u64,
+    name: String,
+    permissions: String,
+    color: u32,
+    hoist: bool,
+    mentionable: bool,
+}
+
+#[derive(Serialize)]
+struct TemplateChannel {
+    id: u64,
+    #[serde(rename = "type")]
+    kind: u8,
+    name: String,
+    position: i64,
+    parent_id: Option<u64>,
+    topic: Option<String>,
+    nsfw: bool,
+    rate_limit_per_user: u64,
+    bitrate: Option<u64>,
+    user_limit: Option<u64>,
+    permission_overwrites: Vec<TemplateOverwrite>,
+}
+
+#[derive(Serialize)]
+struct TemplateOverwrite {
+    id: u64,
+    #[serde(rename = "type")]
+    kind: u8,
+    allow: String,
+    deny: String,
+}
+
+/// Build a guild template from the roles and channels in the backup so the
+/// guild's structure can be restored through Discord's own template support.
+/// Managed (bot) roles and member overwrites can't be part of a template.
+pub fn generate(name: &str) -> Result<(), Error> {
+    let mut roles: Vec<RoleData> = read_json(ROLES_FILE)?;
+    let mut channels: Vec<ChannelData> = read_json(CHANNELS_FILE)?;
+
+    roles.retain(|r| !r.managed);
+    roles.sort_by_key(|r| r.position);
+    // @everyone always has position 0 and the guild's ID
+    let mut role_ids = HashMap::new();
+    let mut template_roles = Vec::new();
+    for role in roles {
+        let id = template_roles.len() as u64;
+        role_ids.insert(role.id, id);
+        template_roles.push(TemplateRole {
+            id,
+            name: role.name,
+            permissions: role.permissions.to_string(),
+            color: role.color,
+            hoist: role.hoist,
+            mentionable: role.mentionable,
+        });
+    }
+
+    // Categories first so children can refer to them
+    channels.sort_by_key(|c| (c.kind != CHANNEL_TYPE_CATEGORY, c.position.unwrap_or(0)));
+    let mut channel_ids = HashMap::new();
+    for (i, channel) in channels.iter().enumerate() {
+        channel_ids.insert(channel.id, i as u64 + 1);
+    }
+    let template_channels = channels
+        .into_iter()
+        .map(|channel| TemplateChannel {
+            id: channel_ids[&channel.id],
+            kind: channel.kind,
+            name: channel.name.unwrap_or_default(),
+            position: channel.position.unwrap_or(0),
+            parent_id: channel.parent_id.and_then(|p| channel_ids.get(&p).copied()),
+            topic: channel.topic,
+            nsfw: channel.nsfw.unwrap_or(false),
+            rate_limit_per_user: channel.rate_limit_per_user.unwrap_or(0),
+            bitrate: channel.bitrate,
+            user_limit: channel.user_limit,
+            permission_overwrites: channel
+                .permission_overwrites
+                .into_iter()
+                .filter(|o| o.kind == 0)
+                .filter_map(|o| {
+                    Some(TemplateOverwrite {
+                        id: *role_ids.get(&o.id)?,
+                        kind: 0,
+                        allow: o.allow.to_string(),
+                        deny: o.deny.to_string(),
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+
+    write_json(
+        TEMPLATE_FILE,
+        &SerializedGuild {
+            name: name.to_owned(),
+            roles: template_roles,
+            channels: template_channels,
+        },
+    )
+}
@@ -0,0 +1,79 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::ImageFormat;
+
+use crate::{
+    attachments::{classify, read_index, AttachmentType, Status},
+    progress::Progress,
+    Error,
+};
+
+/// Longest side of a generated thumbnail, in pixels
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// Thumbnails are stored next to the original, e.g. `cat.png.thumb.jpg`
+pub fn thumbnail_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".thumb.jpg");
+    PathBuf::from(name)
+}
+
+/// Generate a thumbnail for an image, or a poster frame for a video (which
+/// needs `ffmpeg` on the path). Returns whether a new thumbnail was written.
+pub fn generate(path: &Path, kind: AttachmentType) -> Result<bool, Error> {
+    let thumbnail = thumbnail_path(path);
+    if thumbnail.exists() {
+        return Ok(false);
+    }
+
+    match kind {
+        AttachmentType::Image => {
+            image::open(path)?
+                .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+                .to_rgb8()
+                .save_with_format(&thumbnail, ImageFormat::Jpeg)?;
+        }
+        AttachmentType::Video => {
+            let scale = format!(
+                "scale={0}:{0}:force_original_aspect_ratio=decrease",
+                THUMBNAIL_SIZE
+            );
+            let status = Command::new("ffmpeg")
+                .args(["-loglevel", "error", "-y", "-i"])
+                .arg(path)
+                .args(["-frames:v", "1", "-vf", &scale])
+                .arg(&thumbnail)
+                .status()?;
+            if !status.success() {
+                return Err(format!("ffmpeg exited with {}", status).into());
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Generate thumbnails for everything already in the asset store
+pub fn generate_all(progress: &Progress) -> Result<(), Error> {
+    let mut generated = 0;
+    for entry in read_index()? {
+        let path = match (entry.status, &entry.path) {
+            (Status::Downloaded, Some(path)) => Path::new(path).to_owned(),
+            _ => continue,
+        };
+        let kind = classify(entry.content_type.as_deref(), &entry.filename);
+        match generate(&path, kind) {
+            Ok(true) => generated += 1,
+            Ok(false) => {}
+            Err(e) => progress.error(format!(
+                "Error generating thumbnail for {} {:?}",
+                entry.attachment_id, e
+            )),
+        }
+    }
+    progress.info(format!("Generated {} thumbnails", generated));
+    Ok(())
+}
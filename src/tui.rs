@@ -0,0 +1,195 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Gauge, List, ListItem, Paragraph},
+    Terminal,
+};
+
+use crate::progress::{format_bytes, format_duration, Progress, ProgressState};
+
+const TICK: Duration = Duration::from_millis(250);
+const KEYS: &str = " [p] pause/resume  [s] skip channel  [q] stop (resumable)";
+
+/// Run the interface on its own thread so it keeps drawing while the scraper
+/// is blocked on requests. The thread exits once the progress is finished.
+pub fn spawn(progress: Arc<Progress>) -> JoinHandle<io::Result<()>> {
+    thread::spawn(move || run(&progress))
+}
+
+fn run(progress: &Progress) -> io::Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = event_loop(&mut terminal, progress);
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    result
+}
+
+fn event_loop<B: Backend>(terminal: &mut Terminal<B>, progress: &Progress) -> io::Result<()> {
+    loop {
+        {
+            let state = progress.state();
+            if state.finished {
+                return Ok(());
+            }
+            let paused = progress.control.is_paused();
+            terminal.draw(|f| {
+                let rows = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([
+                        Constraint::Length(3),
+                        Constraint::Length(3),
+                        Constraint::Length(3),
+                        Constraint::Min(5),
+                        Constraint::Length(8),
+                        Constraint::Length(1),
+                    ])
+                    .split(f.size());
+                let middle = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
+                    .split(rows[3]);
+
+                f.render_widget(summary(&state, paused), rows[0]);
+                f.render_widget(overall(&state), rows[1]);
+                f.render_widget(rate_limits(&state), rows[2]);
+                f.render_widget(queue(&state), middle[0]);
+                f.render_widget(lines("Log", &state.log, middle[1].height), middle[1]);
+                f.render_widget(lines("Errors", &state.errors, rows[4].height), rows[4]);
+                f.render_widget(
+                    Paragraph::new(KEYS).style(Style::default().add_modifier(Modifier::DIM)),
+                    rows[5],
+                );
+            })?;
+        }
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('p') | KeyCode::Char(' ') => progress.control.toggle_pause(),
+                    KeyCode::Char('s') => progress.control.request_skip(),
+                    KeyCode::Char('q') | KeyCode::Esc => progress.control.request_stop(),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        progress.control.request_stop()
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn summary(state: &ProgressState, paused: bool) -> Paragraph<'static> {
+    let status = if paused {
+        Span::styled("PAUSED", Style::default().fg(Color::Yellow))
+    } else {
+        Span::styled("RUNNING", Style::default().fg(Color::Green))
+    };
+    let current = match &state.current {
+        Some(c) => format!(
+            "#{} ({}) {}{} msgs {} in {}",
+            c.name,
+            c.id,
+            c.messages,
+            state
+                .estimates
+                .get(&c.id)
+                .map(|e| format!("/~{}", e.messages))
+                .unwrap_or_default(),
+            format_bytes(c.bytes),
+            format_duration(c.started.elapsed())
+        ),
+        None => "idle".to_owned(),
+    };
+    let eta = state
+        .eta()
+        .map(format_duration)
+        .unwrap_or_else(|| "unknown".to_owned());
+    Paragraph::new(Line::from(vec![
+        status,
+        Span::raw(format!(
+            "  {} msgs  {}  {:.1} msg/s  {} files {}  elapsed {}  ETA {}  |  {}",
+            state.messages,
+            format_bytes(state.bytes),
+            state.throughput(),
+            state.attachments,
+            format_bytes(state.attachment_bytes),
+            format_duration(state.started.elapsed()),
+            eta,
+            current
+        )),
+    ]))
+    .block(Block::default().borders(Borders::ALL).title("guild-backup"))
+}
+
+fn overall(state: &ProgressState) -> Gauge<'static> {
+    let total = state.channels_done + state.queue.len() + state.current.iter().count();
+    let ratio = if total > 0 {
+        state.channels_done as f64 / total as f64
+    } else {
+        0.0
+    };
+    Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title("Channels"))
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(ratio)
+        .label(format!("{}/{}", state.channels_done, total))
+}
+
+fn rate_limits(state: &ProgressState) -> Paragraph<'static> {
+    Paragraph::new(state.rate_limit_summary())
+        .block(Block::default().borders(Borders::ALL).title("Rate limits"))
+}
+
+fn queue(state: &ProgressState) -> List<'static> {
+    let items: Vec<ListItem> = state
+        .queue
+        .iter()
+        .map(|c| match state.estimates.get(&c.id) {
+            Some(e) => ListItem::new(format!("#{} ({}) ~{} msgs", c.name, c.id, e.messages)),
+            None => ListItem::new(format!("#{} ({})", c.name, c.id)),
+        })
+        .collect();
+    List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!("Queue ({})", state.queue.len())),
+    )
+}
+
+/// Newest lines that fit in a bordered box of the given height
+fn lines(title: &'static str, lines: &VecDeque<String>, height: u16) -> Paragraph<'static> {
+    let visible = height.saturating_sub(2) as usize;
+    let mut shown: Vec<Line> = lines
+        .iter()
+        .rev()
+        .take(visible)
+        .map(|l| Line::from(l.clone()))
+        .collect();
+    shown.reverse();
+    Paragraph::new(shown).block(Block::default().borders(Borders::ALL).title(title))
+}
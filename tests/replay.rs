@@ -0,0 +1,588 @@
+//! Backups run against canned responses served by `mock-api`, so pagination,
+//! resuming, thread discovery, failed chunks, retries, rescrapes and control
+//! commands are covered without the live API
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+const BINARY: &str = env!("CARGO_BIN_EXE_guild-backup");
+const GUILD: u64 = 10;
+const CHANNEL: u64 = 20;
+const THREAD: u64 = 30;
+
+#[derive(Deserialize)]
+struct ArchivedMessage {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ScrapeState {
+    channels: Vec<ResumedChannel>,
+}
+
+#[derive(Deserialize)]
+struct ResumedChannel {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Failure {
+    kind: String,
+    channel_id: String,
+    last_message: Option<String>,
+}
+
+/// `mock-api` serving the fixtures of one test, with the backup made in a
+/// directory next to them
+struct Mock {
+    root: PathBuf,
+    server: Child,
+    port: u16,
+}
+
+impl Mock {
+    fn start(name: &str, fixtures: &[String]) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("guild-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("fixtures")).unwrap();
+        fs::create_dir_all(root.join("backup")).unwrap();
+        fs::write(root.join("fixtures/fixtures.json"), fixtures.join("\n")).unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = Command::new(BINARY)
+            .arg("mock-api")
+            .arg(root.join("fixtures"))
+            .args(["--port", &port.to_string()])
+            // Every request for metadata that isn't served is reported
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Mock { root, server, port }
+    }
+
+    fn backup_dir(&self) -> PathBuf {
+        self.root.join("backup")
+    }
+
+    fn backup(&self) -> Output {
+        self.run(&[])
+    }
+
+    /// The backup with `args` after the common ones, e.g. a subcommand
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(BINARY);
+        command
+            .current_dir(self.backup_dir())
+            .args(["--api-base", &format!("http://127.0.0.1:{}", self.port)])
+            .args(["--token", "test", "--guild", &GUILD.to_string()])
+            .args(args);
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        let output = self.command(args).output().unwrap();
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    /// Run with `args`, sending `control` once the run logs a line
+    /// containing `after`
+    #[cfg(unix)]
+    fn run_controlled(&self, args: &[&str], after: &str, control: &str) -> Output {
+        let mut run = self
+            .command(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stderr = String::new();
+        let mut sent = false;
+        for line in BufReader::new(run.stderr.take().unwrap()).lines() {
+            let line = line.unwrap();
+            if !sent && line.contains(after) {
+                let output = Command::new(BINARY)
+                    .current_dir(self.backup_dir())
+                    .args(["control", control])
+                    .output()
+                    .unwrap();
+                assert!(output.status.success());
+                sent = true;
+            }
+            stderr.push_str(&line);
+            stderr.push('\n');
+        }
+        let status = run.wait().unwrap();
+        eprintln!("{}", stderr);
+        assert!(sent, "the run never logged \"{}\"", after);
+        Output {
+            status,
+            stdout: Vec::new(),
+            stderr: stderr.into_bytes(),
+        }
+    }
+
+    /// Write a finished messages file, as an earlier backup left it
+    fn archive(&self, channel: u64, contents: &str) {
+        fs::write(
+            self.backup_dir().join(format!("{}.messages.json", channel)),
+            contents,
+        )
+        .unwrap();
+    }
+
+    fn messages(&self, channel: u64) -> Vec<u64> {
+        let mut data = fs::read(self.backup_dir().join(format!("{}.messages.json", channel)))
+            .expect("messages file missing");
+        let messages: Vec<ArchivedMessage> = simd_json::from_slice(&mut data).unwrap();
+        messages.iter().map(|m| m.id.parse().unwrap()).collect()
+    }
+
+    /// The channels the state file resumes, for a run that was stopped
+    fn resumed(&self) -> Vec<u64> {
+        let mut data =
+            fs::read(self.backup_dir().join(".discord_scrape_state")).expect("state file missing");
+        let state: ScrapeState = simd_json::from_slice(&mut data).unwrap();
+        state
+            .channels
+            .iter()
+            .map(|c| c.id.parse().unwrap())
+            .collect()
+    }
+
+    /// Write the error ledger, as earlier runs left it
+    fn ledger(&self, failures: &[String]) {
+        fs::write(self.backup_dir().join("errors.json"), failures.join("\n")).unwrap();
+    }
+
+    fn failures(&self) -> Vec<Failure> {
+        let Ok(data) = fs::read_to_string(self.backup_dir().join("errors.json")) else {
+            return Vec::new();
+        };
+        data.lines()
+            .map(|line| simd_json::from_slice(&mut line.as_bytes().to_vec()).unwrap())
+            .collect()
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn fixture(path: &str, status: u16, body: &str) -> String {
+    format!(
+        "{{\"method\":\"GET\",\"path\":{},\"status\":{},\
+         \"headers\":[[\"content-type\",\"application/json\"]],\"body\":{}}}",
+        simd_json::to_string(&format!("/api/v9{}", path)).unwrap(),
+        status,
+        simd_json::to_string(body).unwrap()
+    )
+}
+
+/// The fixture, answered only after `ms` milliseconds
+fn delayed(fixture: String, ms: u64) -> String {
+    format!("{},\"delay_ms\":{}}}", &fixture[..fixture.len() - 1], ms)
+}
+
+fn message(id: u64, channel: u64) -> String {
+    format!(
+        "{{\"id\":\"{}\",\"channel_id\":\"{}\",\"author\":{{\"id\":\"40\",\"username\":\"tester\",\
+         \"discriminator\":\"0001\",\"avatar\":null}},\"content\":\"message {}\",\
+         \"timestamp\":\"2021-01-01T00:00:00+00:00\",\"edited_timestamp\":null,\"tts\":false,\
+         \"mention_everyone\":false,\"mentions\":[],\"mention_roles\":[],\"attachments\":[],\
+         \"embeds\":[],\"pinned\":false,\"type\":0}}",
+        id, channel, id
+    )
+}
+
+/// Messages newest first, as Discord returns them
+fn page(channel: u64, newest: u64, oldest: u64) -> String {
+    let messages: Vec<String> = (oldest..=newest)
+        .rev()
+        .map(|id| message(id, channel))
+        .collect();
+    format!("[{}]", messages.join(","))
+}
+
+fn text_channel(id: u64) -> String {
+    format!(
+        "{{\"id\":\"{}\",\"guild_id\":\"{}\",\"type\":0,\"name\":\"general\",\"position\":0,\
+         \"permission_overwrites\":[],\"nsfw\":false}}",
+        id, GUILD
+    )
+}
+
+fn archived_thread(id: u64, parent: u64) -> String {
+    format!(
+        "{{\"id\":\"{}\",\"guild_id\":\"{}\",\"type\":11,\"name\":\"old thread\",\
+         \"parent_id\":\"{}\",\"owner_id\":\"40\",\"member_count\":1,\"message_count\":2,\
+         \"thread_metadata\":{{\"archived\":true,\"auto_archive_duration\":60,\
+         \"archive_timestamp\":\"2021-01-02T00:00:00+00:00\",\"locked\":false}}}}",
+        id, GUILD, parent
+    )
+}
+
+fn threads(threads: &[String]) -> String {
+    format!(
+        "{{\"threads\":[{}],\"members\":[],\"has_more\":false}}",
+        threads.join(",")
+    )
+}
+
+/// A guild with one text channel, `archived` its archived public threads.
+/// Metadata the backup exports besides isn't served, which it only reports.
+fn guild(archived: &[String]) -> Vec<String> {
+    vec![
+        fixture(
+            &format!("/guilds/{}/channels", GUILD),
+            200,
+            &format!("[{}]", text_channel(CHANNEL)),
+        ),
+        fixture(
+            &format!("/guilds/{}/threads/active", GUILD),
+            200,
+            &threads(&[]),
+        ),
+        fixture(
+            &format!("/channels/{}", CHANNEL),
+            200,
+            &text_channel(CHANNEL),
+        ),
+        fixture(
+            &format!("/channels/{}/threads/archived/public?", CHANNEL),
+            200,
+            &threads(archived),
+        ),
+        fixture(
+            &format!("/channels/{}/threads/archived/private?", CHANNEL),
+            200,
+            &threads(&[]),
+        ),
+    ]
+}
+
+fn first_page(channel: u64) -> String {
+    format!("/channels/{}/messages?&limit=100", channel)
+}
+
+fn page_before(channel: u64, before: u64) -> String {
+    format!(
+        "/channels/{}/messages?&before={}&limit=100",
+        channel, before
+    )
+}
+
+fn page_after(channel: u64, after: u64) -> String {
+    format!("/channels/{}/messages?after={}&limit=100", channel, after)
+}
+
+fn failed_chunk(channel: u64, last_message: u64) -> String {
+    format!(
+        "{{\"kind\":\"chunk\",\"channel_id\":\"{}\",\"last_message\":\"{}\",\
+         \"forward\":false,\"error\":\"Internal Server Error\"}}",
+        channel, last_message
+    )
+}
+
+fn assert_finished(mock: &Mock, output: &Output) {
+    assert!(output.status.success());
+    assert!(!mock.backup_dir().join(".discord_scrape_state").exists());
+}
+
+#[test]
+fn paginates_until_a_short_page() {
+    let mut fixtures = guild(&[]);
+    fixtures.push(fixture(
+        &first_page(CHANNEL),
+        200,
+        &page(CHANNEL, 1150, 1051),
+    ));
+    fixtures.push(fixture(
+        &page_before(CHANNEL, 1051),
+        200,
+        &page(CHANNEL, 1050, 1001),
+    ));
+    let mock = Mock::start("paginates", &fixtures);
+
+    let output = mock.backup();
+    assert_finished(&mock, &output);
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (1001..=1150).rev().collect::<Vec<_>>()
+    );
+    assert!(mock.failures().is_empty());
+}
+
+#[test]
+fn resumes_an_interrupted_channel() {
+    let mut fixtures = guild(&[]);
+    // Only the rest of the channel is served, refetching the start fails
+    fixtures.push(fixture(
+        &page_before(CHANNEL, 1051),
+        200,
+        &page(CHANNEL, 1050, 1001),
+    ));
+    let mock = Mock::start("resumes", &fixtures);
+
+    // As left by a run killed while writing the second chunk, with the state
+    // saved after the first
+    let written = page(CHANNEL, 1150, 1051);
+    let written = &written[..written.len() - 1];
+    fs::write(
+        mock.backup_dir().join(format!("{}.messages.json", CHANNEL)),
+        format!("{},{{\"id\":\"105", written),
+    )
+    .unwrap();
+    fs::write(
+        mock.backup_dir().join(".discord_scrape_state"),
+        format!(
+            "{{\"current_guild\":\"{}\",\"channels\":[{{\"id\":\"{}\",\"status\":\"in_progress\",\
+             \"last_message\":\"1051\",\"fetched\":100}}]}}",
+            GUILD, CHANNEL
+        ),
+    )
+    .unwrap();
+
+    let output = mock.backup();
+    assert_finished(&mock, &output);
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (1001..=1150).rev().collect::<Vec<_>>()
+    );
+    assert!(mock.failures().is_empty());
+}
+
+#[test]
+fn backs_up_archived_threads() {
+    let mut fixtures = guild(&[archived_thread(THREAD, CHANNEL)]);
+    fixtures.push(fixture(
+        &first_page(CHANNEL),
+        200,
+        &page(CHANNEL, 1010, 1001),
+    ));
+    fixtures.push(fixture(
+        &format!("/channels/{}", THREAD),
+        200,
+        &archived_thread(THREAD, CHANNEL),
+    ));
+    fixtures.push(fixture(&first_page(THREAD), 200, &page(THREAD, 2002, 2001)));
+    let mock = Mock::start("threads", &fixtures);
+
+    let output = mock.backup();
+    assert_finished(&mock, &output);
+    assert_eq!(mock.messages(CHANNEL).len(), 10);
+    assert_eq!(mock.messages(THREAD), vec![2002, 2001]);
+}
+
+#[test]
+fn records_a_failed_chunk() {
+    let mut fixtures = guild(&[]);
+    fixtures.push(fixture(
+        &first_page(CHANNEL),
+        200,
+        &page(CHANNEL, 1150, 1051),
+    ));
+    fixtures.push(fixture(
+        &page_before(CHANNEL, 1051),
+        500,
+        "{\"message\":\"Internal Server Error\",\"code\":0}",
+    ));
+    let mock = Mock::start("failed-chunk", &fixtures);
+
+    let output = mock.backup();
+    assert_finished(&mock, &output);
+    // Closed and valid up to the failed chunk, for retry-failed to continue
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (1051..=1150).rev().collect::<Vec<_>>()
+    );
+    let failures = mock.failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].kind, "chunk");
+    assert_eq!(failures[0].channel_id, CHANNEL.to_string());
+    assert_eq!(failures[0].last_message.as_deref(), Some("1051"));
+}
+
+#[test]
+fn waits_out_rate_limits() {
+    let mut fixtures = vec![fixture(
+        &format!("/channels/{}", CHANNEL),
+        429,
+        "{\"message\":\"You are being rate limited.\",\"retry_after\":0.1,\"global\":false}",
+    )];
+    fixtures.extend(guild(&[]));
+    fixtures.push(fixture(
+        &first_page(CHANNEL),
+        200,
+        &page(CHANNEL, 1010, 1001),
+    ));
+    let mock = Mock::start("rate-limited", &fixtures);
+
+    let output = mock.backup();
+    assert_finished(&mock, &output);
+    assert!(mock
+        .backup_dir()
+        .join(format!("{}.meta.json", CHANNEL))
+        .exists());
+    assert_eq!(mock.messages(CHANNEL).len(), 10);
+}
+
+#[test]
+fn rescrapes_a_channel_with_archived_threads() {
+    let mut fixtures = guild(&[archived_thread(THREAD, CHANNEL)]);
+    fixtures.push(fixture(
+        &first_page(CHANNEL),
+        200,
+        &page(CHANNEL, 1010, 1001),
+    ));
+    let mock = Mock::start("rescrape-threads", &fixtures);
+    // The thread was backed up with its channel, so isn't fetched again
+    mock.archive(CHANNEL, &page(CHANNEL, 1010, 1005));
+    mock.archive(THREAD, &page(THREAD, 2002, 2001));
+
+    let output = mock.run(&["rescrape", "--channel", &CHANNEL.to_string()]);
+    assert_finished(&mock, &output);
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (1001..=1010).rev().collect::<Vec<_>>()
+    );
+    assert_eq!(mock.messages(THREAD), vec![2002, 2001]);
+}
+
+#[test]
+fn retries_a_channel_with_archived_threads() {
+    let mut fixtures = guild(&[archived_thread(THREAD, CHANNEL)]);
+    fixtures.push(fixture(
+        &page_before(CHANNEL, 1051),
+        200,
+        &page(CHANNEL, 1050, 1001),
+    ));
+    let mock = Mock::start("retry-threads", &fixtures);
+    // As left by a run whose second chunk failed
+    mock.archive(CHANNEL, &page(CHANNEL, 1150, 1051));
+    mock.archive(THREAD, &page(THREAD, 2002, 2001));
+    mock.ledger(&[failed_chunk(CHANNEL, 1051)]);
+
+    let output = mock.run(&["retry-failed"]);
+    assert_finished(&mock, &output);
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (1001..=1150).rev().collect::<Vec<_>>()
+    );
+    assert_eq!(mock.messages(THREAD), vec![2002, 2001]);
+    assert!(mock.failures().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn stopping_a_retry_keeps_the_rest() {
+    const OTHER: u64 = 21;
+    let mut fixtures = guild(&[]);
+    // A full page, so the retry carries on and sees the stop
+    fixtures.push(delayed(
+        fixture(&page_before(CHANNEL, 1051), 200, &page(CHANNEL, 1050, 951)),
+        1000,
+    ));
+    fixtures.push(fixture(
+        &format!("/channels/{}", OTHER),
+        200,
+        &text_channel(OTHER),
+    ));
+    fixtures.push(fixture(
+        &page_before(OTHER, 2051),
+        200,
+        &page(OTHER, 2050, 2001),
+    ));
+    let mock = Mock::start("retry-stopped", &fixtures);
+    mock.archive(CHANNEL, &page(CHANNEL, 1150, 1051));
+    mock.archive(OTHER, &page(OTHER, 2150, 2051));
+    mock.ledger(&[failed_chunk(CHANNEL, 1051), failed_chunk(OTHER, 2051)]);
+
+    let output = mock.run_controlled(&["retry-failed"], "Fetching message chunk", "stop");
+    assert!(output.status.success());
+    // The stopped channel resumes from the state file, the other is retried
+    // later
+    assert_eq!(mock.resumed(), vec![CHANNEL]);
+    let failures = mock.failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].channel_id, OTHER.to_string());
+    assert_eq!(failures[0].last_message.as_deref(), Some("2051"));
+}
+
+#[cfg(unix)]
+#[test]
+fn skipping_a_resumed_channel_keeps_it() {
+    let mut fixtures = guild(&[]);
+    fixtures.push(delayed(
+        fixture(&page_before(CHANNEL, 1051), 200, &page(CHANNEL, 1050, 951)),
+        1000,
+    ));
+    let mock = Mock::start("skip-resumed", &fixtures);
+    let written = page(CHANNEL, 1150, 1051);
+    mock.archive(CHANNEL, &written[..written.len() - 1]);
+    fs::write(
+        mock.backup_dir().join(".discord_scrape_state"),
+        format!(
+            "{{\"current_guild\":\"{}\",\"channels\":[{{\"id\":\"{}\",\"status\":\"in_progress\",\
+             \"last_message\":\"1051\",\"fetched\":100}}]}}",
+            GUILD, CHANNEL
+        ),
+    )
+    .unwrap();
+
+    let output = mock.run_controlled(&[], "Fetching message chunk", "skip");
+    assert_finished(&mock, &output);
+    // Closed after the chunk fetched before the skip, for retry-failed to
+    // continue
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (951..=1150).rev().collect::<Vec<_>>()
+    );
+    let failures = mock.failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].kind, "chunk");
+    assert_eq!(failures[0].last_message.as_deref(), Some("951"));
+}
+
+#[test]
+fn resumes_forward_from_the_archive() {
+    let mut fixtures = guild(&[]);
+    fixtures.push(fixture(
+        &page_after(CHANNEL, 1010),
+        200,
+        &page(CHANNEL, 1030, 1011),
+    ));
+    let mock = Mock::start("resume-forward", &fixtures);
+    mock.archive(CHANNEL, &page(CHANNEL, 1010, 1001));
+
+    let output = mock.run(&["--resume-forward"]);
+    assert_finished(&mock, &output);
+    assert_eq!(
+        mock.messages(CHANNEL),
+        (1001..=1030).rev().collect::<Vec<_>>()
+    );
+    assert!(mock.failures().is_empty());
+}